@@ -0,0 +1,427 @@
+//! Double buffering shared by all capture sources.
+//!
+//! A source keeps up to two DMA transfers in flight: the one being filled,
+//! and the next one, started by an END-to-START shortcut when the first
+//! ends. On each END interrupt the finished block is sent to the report
+//! queue, and a new one is queued behind the running transfer. Running out
+//! of blocks, the fuse and the overflow policy are handled here, the
+//! peripheral specific parts live in the `Source` implementations.
+//!
+//! The peripheral, the pool, the queue and the clock are all behind traits,
+//! so this builds and is tested on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use diegesis_icd::OverflowPolicy;
+use heapless::mpmc::MpMcQueue;
+
+#[cfg(test)]
+mod tests;
+
+#[cfg(feature = "defmt")]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        defmt::$level!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "defmt"))]
+macro_rules! log {
+    (panic, $($arg:tt)*) => {
+        panic!($($arg)*)
+    };
+    ($level:ident, $($arg:tt)*) => {
+        let _ = ($($arg)*);
+    };
+}
+
+/// A peripheral capturing blocks with EasyDMA.
+///
+/// The peripheral moves through three typestates: `Idle`, `One` with a
+/// transfer running, and `Two` with the next transfer queued as well.
+pub trait Source {
+    /// A block fresh from the pool.
+    type Uninit;
+    /// A block, as handed to the DMA.
+    type Block;
+    type Report;
+
+    type Idle;
+    type One;
+    type Two;
+
+    /// Prefix of log messages.
+    const NAME: &'static str;
+
+    /// Prepare a fresh block for the DMA.
+    fn block(&mut self, uninit: Self::Uninit) -> Self::Block;
+
+    /// Report a filled block.
+    fn report(&mut self, timestamp: u32, seq: u32, block: Self::Block) -> Self::Report;
+
+    /// Index of this source in the lost block counters.
+    fn lost_index(&self) -> usize;
+
+    /// Ticks after which a block is considered late, if checked.
+    fn expected_ticks(&self) -> Option<u32> {
+        None
+    }
+
+    /// Arm the first transfer. It only starts with the synchronized start.
+    fn arm(&mut self, idle: Self::Idle, block: Self::Block) -> Self::One;
+
+    /// Start a transfer right away, after the previous one stopped.
+    fn restart(&mut self, idle: Self::Idle, block: Self::Block) -> Self::One;
+
+    fn is_done(&mut self, one: &mut Self::One) -> bool;
+
+    /// Clear the STARTED event, when no next transfer will be queued.
+    fn clear_started(&mut self, one: &mut Self::One);
+
+    fn wait(&mut self, one: Self::One) -> (Self::Idle, Self::Block);
+
+    /// Queue the next transfer, and enable the END-to-START shortcut.
+    fn enqueue(&mut self, one: Self::One, block: Self::Block) -> Self::Two;
+
+    /// Take the finished block once the queued transfer took over, and
+    /// disable the END-to-START shortcut.
+    fn exchange(&mut self, two: Self::Two) -> (Self::One, Self::Block);
+
+    /// A capture is about to start.
+    fn started(&mut self, _idle: &mut Self::Idle, _now: u32) {}
+
+    /// The last transfer is done, and none follows.
+    fn halted(&mut self, _idle: &mut Self::Idle) {}
+
+    /// The capture has ended.
+    fn stopped(&mut self, _idle: &mut Self::Idle) {}
+
+    /// Whether to pause once the current block is done.
+    fn pause_requested(&self) -> bool {
+        false
+    }
+
+    /// Paused, after the last block was sent.
+    fn paused(&mut self, _idle: &mut Self::Idle) {}
+
+    /// The first transfer after the pause is armed, start it.
+    fn resume(&mut self) {}
+}
+
+/// Where the blocks come from, and what happens once there are none left.
+pub trait BlockPool {
+    type Uninit;
+
+    /// A block to start a capture with.
+    fn alloc(&self) -> Option<Self::Uninit>;
+
+    /// A block for the next transfer of a running capture. Depending on the
+    /// policy, this may drop queued blocks to make room.
+    fn alloc_next(&self) -> Option<Self::Uninit> {
+        self.alloc()
+    }
+
+    fn policy(&self) -> OverflowPolicy {
+        OverflowPolicy::Stop
+    }
+
+    /// A block of the given source was lost to the overflow policy.
+    fn count_lost(&self, _source: usize) {}
+}
+
+impl<P> BlockPool for &P
+where
+    P: BlockPool + ?Sized,
+{
+    type Uninit = P::Uninit;
+
+    fn alloc(&self) -> Option<Self::Uninit> {
+        (**self).alloc()
+    }
+
+    fn alloc_next(&self) -> Option<Self::Uninit> {
+        (**self).alloc_next()
+    }
+
+    fn policy(&self) -> OverflowPolicy {
+        (**self).policy()
+    }
+
+    fn count_lost(&self, source: usize) {
+        (**self).count_lost(source)
+    }
+}
+
+pub trait ReportQueue<T> {
+    /// Hands the report back if the queue is full.
+    fn enqueue(&self, report: T) -> Result<(), T>;
+}
+
+impl<T, const N: usize> ReportQueue<T> for MpMcQueue<T, N> {
+    fn enqueue(&self, report: T) -> Result<(), T> {
+        MpMcQueue::enqueue(self, report)
+    }
+}
+
+impl<T, Q> ReportQueue<T> for &Q
+where
+    Q: ReportQueue<T> + ?Sized,
+{
+    fn enqueue(&self, report: T) -> Result<(), T> {
+        (**self).enqueue(report)
+    }
+}
+
+pub trait Clock {
+    fn now(&self) -> u32;
+
+    /// Tick of the last synchronized start.
+    fn start_tick(&self) -> u32;
+}
+
+impl<C> Clock for &C
+where
+    C: Clock + ?Sized,
+{
+    fn now(&self) -> u32 {
+        (**self).now()
+    }
+
+    fn start_tick(&self) -> u32 {
+        (**self).start_tick()
+    }
+}
+
+pub enum State<S: Source> {
+    Idle(S::Idle),
+    OnePending(S::One),
+    TwoPending(S::Two),
+    Unstable,
+}
+
+pub struct DoubleBuffer<S, P, Q, C>
+where
+    S: Source,
+    P: BlockPool<Uninit = S::Uninit>,
+    Q: ReportQueue<S::Report>,
+    C: Clock,
+{
+    source: S,
+    state: State<S>,
+    pool: P,
+    queue: Q,
+    clock: C,
+    last_start: u32,
+    awaiting_start: bool,
+    seq: u32,
+}
+
+impl<S, P, Q, C> DoubleBuffer<S, P, Q, C>
+where
+    S: Source,
+    P: BlockPool<Uninit = S::Uninit>,
+    Q: ReportQueue<S::Report>,
+    C: Clock,
+{
+    pub fn new(source: S, idle: S::Idle, pool: P, queue: Q, clock: C) -> Self {
+        Self {
+            source,
+            state: State::Idle(idle),
+            pool,
+            queue,
+            clock,
+            last_start: 0,
+            awaiting_start: false,
+            seq: 0,
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub fn state(&self) -> &State<S> {
+        &self.state
+    }
+
+    pub fn parts(&mut self) -> (&mut S, &mut State<S>) {
+        (&mut self.source, &mut self.state)
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        seq
+    }
+
+    /// Start tick of the block that just completed.
+    fn block_start(&mut self, now: u32) -> u32 {
+        let block_start = if self.awaiting_start {
+            self.awaiting_start = false;
+            self.clock.start_tick()
+        } else {
+            self.last_start
+        };
+        self.last_start = now;
+        block_start
+    }
+
+    fn check_late(&self, block_start: u32, now: u32) {
+        if let Some(expected) = self.source.expected_ticks() {
+            let elapsed = now.wrapping_sub(block_start);
+            if elapsed >= expected {
+                log!(warn, "{}: deviation, {} elapsed!", S::NAME, elapsed);
+            }
+        }
+    }
+
+    fn send(&mut self, block_start: u32, block: S::Block) {
+        let seq = self.next_seq();
+        let rpt = self.source.report(block_start, seq, block);
+
+        if self.queue.enqueue(rpt).is_err() {
+            log!(warn, "{}: Failed to send box!", S::NAME);
+        }
+    }
+
+    /// Handle an interrupt of the peripheral. `now` should be taken as early
+    /// as possible: the next block started when the END event that got us
+    /// here fired, so this is the closest we get to its start tick.
+    pub fn poll(&mut self, fuse: &AtomicBool, now: u32) {
+        let fuse_blown = fuse.load(Ordering::SeqCst);
+        let new_state = match mem::replace(&mut self.state, State::Unstable) {
+            State::Idle(idle) if fuse_blown => {
+                // Nothing to do here
+                State::Idle(idle)
+            }
+            State::Idle(mut idle) => {
+                self.source.started(&mut idle, now);
+
+                if let Some(uninit) = self.pool.alloc() {
+                    let block = self.source.block(uninit);
+                    self.awaiting_start = true;
+                    State::OnePending(self.source.arm(idle, block))
+                } else {
+                    // No data available! Blow the fuse.
+                    log!(error, "{}: Blowing fuse idle-to-one transition", S::NAME);
+                    fuse.store(true, Ordering::SeqCst);
+                    self.source.stopped(&mut idle);
+                    State::Idle(idle)
+                }
+            }
+            State::OnePending(mut one) => {
+                if fuse_blown || self.source.pause_requested() {
+                    // Manually clear the started event, as we won't be
+                    // clearing/processing it for the second queued write
+                    self.source.clear_started(&mut one);
+
+                    // Don't enqueue a new transfer, but finish the current
+                    // one, since we already have the alloc page
+                    if self.source.is_done(&mut one) {
+                        self.finish(one, fuse, fuse_blown, now)
+                    } else {
+                        // Not ready yet
+                        State::OnePending(one)
+                    }
+                } else if self.source.is_done(&mut one) {
+                    self.restart(one, now)
+                } else {
+                    self.enqueue(one, fuse)
+                }
+            }
+            State::TwoPending(two) => {
+                let (one, block) = self.source.exchange(two);
+
+                let block_start = self.block_start(now);
+                self.check_late(block_start, now);
+                self.send(block_start, block);
+
+                State::OnePending(one)
+            }
+            State::Unstable => {
+                log!(panic, "{}: encountered invalid state", S::NAME);
+            }
+        };
+
+        self.state = new_state;
+    }
+
+    /// The last transfer is done, stop or pause.
+    fn finish(&mut self, one: S::One, fuse: &AtomicBool, fuse_blown: bool, now: u32) -> State<S> {
+        let (mut idle, block) = self.source.wait(one);
+        self.source.halted(&mut idle);
+
+        let block_start = self.block_start(now);
+        self.check_late(block_start, now);
+        self.send(block_start, block);
+
+        if fuse_blown {
+            self.source.stopped(&mut idle);
+            State::Idle(idle)
+        } else {
+            self.pause(idle, fuse)
+        }
+    }
+
+    /// Only happens if there was no box for the next block, and the policy
+    /// let us keep going. The transfer stopped, restart it, reusing this
+    /// block if there is still no box.
+    fn restart(&mut self, one: S::One, now: u32) -> State<S> {
+        let (idle, block) = self.source.wait(one);
+        let block_start = self.block_start(now);
+
+        let block = match self.pool.alloc_next() {
+            Some(uninit) => {
+                self.send(block_start, block);
+                self.source.block(uninit)
+            }
+            None => {
+                self.pool.count_lost(self.source.lost_index());
+                self.next_seq();
+                block
+            }
+        };
+
+        State::OnePending(self.source.restart(idle, block))
+    }
+
+    fn enqueue(&mut self, one: S::One, fuse: &AtomicBool) -> State<S> {
+        if let Some(uninit) = self.pool.alloc_next() {
+            let block = self.source.block(uninit);
+            State::TwoPending(self.source.enqueue(one, block))
+        } else if self.pool.policy() == OverflowPolicy::Stop {
+            // No data available! Blow the fuse.
+            log!(error, "{}: Blowing fuse one-to-two transition", S::NAME);
+            fuse.store(true, Ordering::SeqCst);
+            State::OnePending(one)
+        } else {
+            // Let the block finish, and restart once it is done
+            log!(warn, "{}: Out of boxes one-to-two transition", S::NAME);
+            State::OnePending(one)
+        }
+    }
+
+    /// Resume on our own after a pause, the other sources kept running.
+    fn pause(&mut self, mut idle: S::Idle, fuse: &AtomicBool) -> State<S> {
+        self.source.paused(&mut idle);
+
+        if let Some(uninit) = self.pool.alloc() {
+            let block = self.source.block(uninit);
+            let one = self.source.restart(idle, block);
+            self.last_start = self.clock.now();
+            self.source.resume();
+            State::OnePending(one)
+        } else {
+            // No data available! Blow the fuse.
+            log!(error, "{}: Blowing fuse after pausing", S::NAME);
+            fuse.store(true, Ordering::SeqCst);
+            self.source.stopped(&mut idle);
+            State::Idle(idle)
+        }
+    }
+}
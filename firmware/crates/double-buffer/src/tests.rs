@@ -0,0 +1,410 @@
+//! The state machine, driven by a mock DMA peripheral.
+//!
+//! Each `poll` stands for one interrupt. The STARTED interrupt of a transfer
+//! queues the next one, its END interrupt hands the block over. Blocks are
+//! numbered in the order they were allocated.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use diegesis_icd::OverflowPolicy;
+
+use super::*;
+
+/// Shared state of the mock DMA peripheral.
+#[derive(Default)]
+struct Dma {
+    /// The END event of the running transfer.
+    end: Cell<bool>,
+    /// Transfers started, in order.
+    started: RefCell<Vec<u32>>,
+    shortcut: Cell<bool>,
+}
+
+struct Transfer {
+    block: u32,
+}
+
+#[derive(Debug, PartialEq)]
+struct Report {
+    timestamp: u32,
+    seq: u32,
+    block: u32,
+}
+
+struct MockSource<'a> {
+    dma: &'a Dma,
+    pause: bool,
+    resumed: u32,
+    capturing: bool,
+}
+
+impl<'a> MockSource<'a> {
+    fn new(dma: &'a Dma) -> Self {
+        Self {
+            dma,
+            pause: false,
+            resumed: 0,
+            capturing: false,
+        }
+    }
+}
+
+impl<'a> Source for MockSource<'a> {
+    type Uninit = u32;
+    type Block = u32;
+    type Report = Report;
+    type Idle = ();
+    type One = Transfer;
+    type Two = (Transfer, u32);
+
+    const NAME: &'static str = "MOCK";
+
+    fn block(&mut self, uninit: u32) -> u32 {
+        uninit
+    }
+
+    fn report(&mut self, timestamp: u32, seq: u32, block: u32) -> Report {
+        Report {
+            timestamp,
+            seq,
+            block,
+        }
+    }
+
+    fn lost_index(&self) -> usize {
+        3
+    }
+
+    fn arm(&mut self, _idle: (), block: u32) -> Transfer {
+        self.dma.started.borrow_mut().push(block);
+        Transfer { block }
+    }
+
+    fn restart(&mut self, _idle: (), block: u32) -> Transfer {
+        self.dma.started.borrow_mut().push(block);
+        Transfer { block }
+    }
+
+    fn is_done(&mut self, _one: &mut Transfer) -> bool {
+        self.dma.end.get()
+    }
+
+    fn clear_started(&mut self, _one: &mut Transfer) {}
+
+    fn wait(&mut self, one: Transfer) -> ((), u32) {
+        assert!(self.dma.end.replace(false));
+        ((), one.block)
+    }
+
+    fn enqueue(&mut self, one: Transfer, block: u32) -> (Transfer, u32) {
+        self.dma.shortcut.set(true);
+        (one, block)
+    }
+
+    fn exchange(&mut self, two: (Transfer, u32)) -> (Transfer, u32) {
+        assert!(self.dma.end.replace(false));
+        self.dma.shortcut.set(false);
+        let (done, next) = two;
+        self.dma.started.borrow_mut().push(next);
+        (Transfer { block: next }, done.block)
+    }
+
+    fn started(&mut self, _idle: &mut (), _now: u32) {
+        self.capturing = true;
+    }
+
+    fn stopped(&mut self, _idle: &mut ()) {
+        self.capturing = false;
+    }
+
+    fn pause_requested(&self) -> bool {
+        self.pause
+    }
+
+    fn paused(&mut self, _idle: &mut ()) {
+        self.pause = false;
+    }
+
+    fn resume(&mut self) {
+        self.resumed += 1;
+    }
+}
+
+struct MockPool {
+    free: Cell<u32>,
+    next: Cell<u32>,
+    policy: OverflowPolicy,
+    lost: RefCell<Vec<usize>>,
+}
+
+impl MockPool {
+    fn new(free: u32, policy: OverflowPolicy) -> Self {
+        Self {
+            free: Cell::new(free),
+            next: Cell::new(0),
+            policy,
+            lost: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl BlockPool for MockPool {
+    type Uninit = u32;
+
+    fn alloc(&self) -> Option<u32> {
+        let free = self.free.get().checked_sub(1)?;
+        self.free.set(free);
+        let block = self.next.get();
+        self.next.set(block + 1);
+        Some(block)
+    }
+
+    fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    fn count_lost(&self, source: usize) {
+        self.lost.borrow_mut().push(source);
+    }
+}
+
+struct MockQueue {
+    reports: RefCell<VecDeque<Report>>,
+    capacity: usize,
+}
+
+impl MockQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            reports: RefCell::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    fn take(&self) -> Vec<Report> {
+        self.reports.borrow_mut().drain(..).collect()
+    }
+}
+
+impl ReportQueue<Report> for MockQueue {
+    fn enqueue(&self, report: Report) -> Result<(), Report> {
+        let mut reports = self.reports.borrow_mut();
+        if reports.len() >= self.capacity {
+            return Err(report);
+        }
+        reports.push_back(report);
+        Ok(())
+    }
+}
+
+struct MockClock;
+
+impl Clock for MockClock {
+    fn now(&self) -> u32 {
+        1000
+    }
+
+    fn start_tick(&self) -> u32 {
+        100
+    }
+}
+
+type Mock<'a> = DoubleBuffer<MockSource<'a>, &'a MockPool, &'a MockQueue, MockClock>;
+
+fn mock<'a>(dma: &'a Dma, pool: &'a MockPool, queue: &'a MockQueue) -> Mock<'a> {
+    DoubleBuffer::new(MockSource::new(dma), (), pool, queue, MockClock)
+}
+
+fn rpt(timestamp: u32, seq: u32, block: u32) -> Report {
+    Report {
+        timestamp,
+        seq,
+        block,
+    }
+}
+
+/// The END event of the running transfer fired.
+fn end(dma: &Dma, db: &mut Mock, fuse: &AtomicBool, now: u32) {
+    dma.end.set(true);
+    db.poll(fuse, now);
+}
+
+#[test]
+fn streams_blocks() {
+    let dma = Dma::default();
+    let pool = MockPool::new(8, OverflowPolicy::Stop);
+    let queue = MockQueue::new(8);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    // Armed, then the STARTED interrupt queues the next block
+    db.poll(&fuse, 10);
+    assert!(matches!(db.state(), State::OnePending(_)));
+    db.poll(&fuse, 20);
+    assert!(matches!(db.state(), State::TwoPending(_)));
+    assert!(dma.shortcut.get());
+
+    end(&dma, &mut db, &fuse, 300);
+    db.poll(&fuse, 310);
+    end(&dma, &mut db, &fuse, 500);
+
+    // The first block is stamped with the synchronized start
+    let reports = queue.take();
+    assert_eq!(reports, vec![rpt(100, 0, 0), rpt(300, 1, 1),]);
+    assert_eq!(*dma.started.borrow(), vec![0, 1, 2]);
+    assert!(!fuse.load(Ordering::SeqCst));
+}
+
+#[test]
+fn idle_fuse() {
+    let dma = Dma::default();
+    let pool = MockPool::new(8, OverflowPolicy::Stop);
+    let queue = MockQueue::new(8);
+    let fuse = AtomicBool::new(true);
+    let mut db = mock(&dma, &pool, &queue);
+
+    db.poll(&fuse, 10);
+    assert!(matches!(db.state(), State::Idle(_)));
+    assert_eq!(pool.free.get(), 8);
+
+    // No block to start with
+    let pool = MockPool::new(0, OverflowPolicy::Stop);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    db.poll(&fuse, 10);
+    assert!(matches!(db.state(), State::Idle(_)));
+    assert!(fuse.load(Ordering::SeqCst));
+    assert!(!db.source().capturing);
+}
+
+#[test]
+fn fuse_finishes_block() {
+    let dma = Dma::default();
+    let pool = MockPool::new(8, OverflowPolicy::Stop);
+    let queue = MockQueue::new(8);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    db.poll(&fuse, 10);
+    db.poll(&fuse, 20);
+    end(&dma, &mut db, &fuse, 300);
+    assert!(db.source().capturing);
+
+    // The running block is still sent, but no next one is queued
+    fuse.store(true, Ordering::SeqCst);
+    db.poll(&fuse, 310);
+    assert!(matches!(db.state(), State::OnePending(_)));
+    end(&dma, &mut db, &fuse, 500);
+    assert!(matches!(db.state(), State::Idle(_)));
+    assert!(!db.source().capturing);
+
+    let blocks: Vec<_> = queue.take().iter().map(|rpt| rpt.block).collect();
+    assert_eq!(blocks, vec![0, 1]);
+}
+
+#[test]
+fn pool_exhaustion_blows_fuse() {
+    let dma = Dma::default();
+    let pool = MockPool::new(1, OverflowPolicy::Stop);
+    let queue = MockQueue::new(8);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    db.poll(&fuse, 10);
+    db.poll(&fuse, 20);
+    assert!(matches!(db.state(), State::OnePending(_)));
+    assert!(fuse.load(Ordering::SeqCst));
+
+    end(&dma, &mut db, &fuse, 300);
+    assert!(matches!(db.state(), State::Idle(_)));
+    assert_eq!(queue.take(), vec![rpt(100, 0, 0)]);
+}
+
+#[test]
+fn pool_exhaustion_drops_blocks() {
+    let dma = Dma::default();
+    let pool = MockPool::new(1, OverflowPolicy::DropNewest);
+    let queue = MockQueue::new(8);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    // Out of boxes, the block finishes on its own
+    db.poll(&fuse, 10);
+    db.poll(&fuse, 20);
+    assert!(matches!(db.state(), State::OnePending(_)));
+    assert!(!fuse.load(Ordering::SeqCst));
+
+    // Still none, so the block is overwritten
+    end(&dma, &mut db, &fuse, 300);
+    assert!(matches!(db.state(), State::OnePending(_)));
+    assert_eq!(*pool.lost.borrow(), vec![3]);
+    assert!(queue.take().is_empty());
+
+    // Boxes again, the block is sent and sampling goes on
+    pool.free.set(4);
+    end(&dma, &mut db, &fuse, 500);
+    db.poll(&fuse, 510);
+    assert!(matches!(db.state(), State::TwoPending(_)));
+    assert!(!fuse.load(Ordering::SeqCst));
+
+    // The lost block still used up a sequence number
+    assert_eq!(queue.take(), vec![rpt(300, 1, 0)]);
+    assert_eq!(*dma.started.borrow(), vec![0, 0, 1]);
+}
+
+#[test]
+fn queue_full() {
+    let dma = Dma::default();
+    let pool = MockPool::new(8, OverflowPolicy::Stop);
+    let queue = MockQueue::new(1);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    db.poll(&fuse, 10);
+    db.poll(&fuse, 20);
+    end(&dma, &mut db, &fuse, 300);
+    db.poll(&fuse, 310);
+    end(&dma, &mut db, &fuse, 500);
+    db.poll(&fuse, 510);
+
+    // The second report was dropped, but the capture goes on
+    assert!(matches!(db.state(), State::TwoPending(_)));
+    assert!(!fuse.load(Ordering::SeqCst));
+    assert_eq!(queue.take(), vec![rpt(100, 0, 0)]);
+
+    end(&dma, &mut db, &fuse, 700);
+    assert_eq!(queue.take(), vec![rpt(500, 2, 2)]);
+}
+
+#[test]
+fn pause_and_resume() {
+    let dma = Dma::default();
+    let pool = MockPool::new(8, OverflowPolicy::Stop);
+    let queue = MockQueue::new(8);
+    let fuse = AtomicBool::new(false);
+    let mut db = mock(&dma, &pool, &queue);
+
+    db.poll(&fuse, 10);
+    db.poll(&fuse, 20);
+    end(&dma, &mut db, &fuse, 300);
+
+    db.parts().0.pause = true;
+    db.poll(&fuse, 310);
+    assert!(matches!(db.state(), State::OnePending(_)));
+    end(&dma, &mut db, &fuse, 500);
+
+    // Resumed on its own, with a fresh block
+    assert!(matches!(db.state(), State::OnePending(_)));
+    assert_eq!(db.source().resumed, 1);
+    assert_eq!(*dma.started.borrow(), vec![0, 1, 2]);
+
+    db.poll(&fuse, 1010);
+    end(&dma, &mut db, &fuse, 1300);
+    let stamps: Vec<_> = queue.take().iter().map(|rpt| rpt.timestamp).collect();
+    assert_eq!(stamps, vec![100, 300, 1000]);
+}
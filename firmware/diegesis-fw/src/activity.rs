@@ -0,0 +1,147 @@
+//! Only-send-on-activity mode for the digital channels.
+//!
+//! Blocks during which a line never changed (all 0x00 or all 0xFF) are
+//! merged into a single `Idle` report per run. The run is sent once the
+//! line becomes active again, or the capture has ended.
+
+use core::{fmt::Debug, ops::Deref};
+
+use heapless::pool::singleton::Pool;
+
+use crate::{InternalReport, InternalReportKind, PBox, DIGITAL_CHANNELS};
+
+#[derive(Clone, Copy)]
+struct IdleRun {
+    timestamp: u32,
+    seq: u32,
+    level: bool,
+    blocks: u32,
+}
+
+impl IdleRun {
+    fn report<DigitalPool, AnalogPool>(
+        &self,
+        channel: u8,
+    ) -> InternalReport<DigitalPool, AnalogPool>
+    where
+        DigitalPool: Pool,
+        AnalogPool: Pool,
+        PBox<DigitalPool>: Debug,
+        PBox<AnalogPool>: Debug,
+    {
+        InternalReport {
+            timestamp: self.timestamp,
+            seq: self.seq,
+            kind: InternalReportKind::Idle {
+                channel,
+                level: self.level,
+                blocks: self.blocks,
+            },
+        }
+    }
+}
+
+pub struct ActivityFilter<DigitalPool, AnalogPool>
+where
+    DigitalPool: Pool,
+    AnalogPool: Pool,
+    PBox<DigitalPool>: Debug,
+    PBox<AnalogPool>: Debug,
+{
+    enabled: bool,
+    runs: [Option<IdleRun>; DIGITAL_CHANNELS],
+    /// A block that ended a run, sent right after it.
+    held: Option<InternalReport<DigitalPool, AnalogPool>>,
+}
+
+impl<DigitalPool, AnalogPool> ActivityFilter<DigitalPool, AnalogPool>
+where
+    DigitalPool: Pool,
+    AnalogPool: Pool,
+    PBox<DigitalPool>: Debug + Deref<Target = [u8; 4096]>,
+    PBox<AnalogPool>: Debug,
+{
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            runs: [None; DIGITAL_CHANNELS],
+            held: None,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Take the block held back by `filter`. Check this before filtering
+    /// the next report.
+    pub fn pop(&mut self) -> Option<InternalReport<DigitalPool, AnalogPool>> {
+        self.held.take()
+    }
+
+    /// Returns the report to send in place of `rpt`, if any.
+    pub fn filter(
+        &mut self,
+        rpt: InternalReport<DigitalPool, AnalogPool>,
+    ) -> Option<InternalReport<DigitalPool, AnalogPool>> {
+        let (channel, level) = match rpt.kind {
+            InternalReportKind::DigitalReport {
+                channel,
+                ref payload,
+            } if self.enabled => (channel, idle_level(payload.deref())),
+            _ => return Some(rpt),
+        };
+        let run = match self.runs.get_mut(channel as usize) {
+            Some(run) => run,
+            None => return Some(rpt),
+        };
+
+        match (level, *run) {
+            (Some(level), Some(mut current))
+                if current.level == level
+                    && current.seq.wrapping_add(current.blocks) == rpt.seq =>
+            {
+                // The block goes back to the pool here
+                current.blocks += 1;
+                *run = Some(current);
+                None
+            }
+            (Some(level), previous) => {
+                *run = Some(IdleRun {
+                    timestamp: rpt.timestamp,
+                    seq: rpt.seq,
+                    level,
+                    blocks: 1,
+                });
+                previous.map(|prev| prev.report(channel))
+            }
+            (None, Some(previous)) => {
+                *run = None;
+                self.held = Some(rpt);
+                Some(previous.report(channel))
+            }
+            (None, None) => Some(rpt),
+        }
+    }
+
+    /// Take one of the pending runs, once the capture has ended.
+    pub fn flush(&mut self) -> Option<InternalReport<DigitalPool, AnalogPool>> {
+        self.runs
+            .iter_mut()
+            .enumerate()
+            .find_map(|(channel, run)| run.take().map(|run| run.report(channel as u8)))
+    }
+}
+
+fn idle_level(payload: &[u8; 4096]) -> Option<bool> {
+    let level = match payload[0] {
+        0x00 => false,
+        0xFF => true,
+        _ => return None,
+    };
+    if payload.iter().all(|b| *b == payload[0]) {
+        Some(level)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,119 @@
+#![no_main]
+#![no_std]
+
+use diegesis_fw::groundhog_nrf52::GlobalRollingTimer;
+use diegesis_icd::{delta, AnalogEncoding, DataReport, Managed, ReportKind};
+use groundhog::RollingTimer;
+use nrf52840_hal::{clocks::Clocks, pac::Peripherals};
+
+/// Interleaved inputs of the test patterns, like three scanned channels.
+const CHANNELS: u8 = 0b0000_0111;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    // Enable instruction caches for MAXIMUM SPEED
+    let board = Peripherals::take().unwrap();
+    board.NVMC.icachecnf.write(|w| w.cacheen().set_bit());
+    cortex_m::asm::isb();
+
+    defmt::info!("Hello, world!");
+
+    let clocks = Clocks::new(board.CLOCK);
+    let _clocks = clocks.enable_ext_hfosc();
+
+    GlobalRollingTimer::init(board.TIMER0);
+
+    let mut test_buffer = [0u8; 4096];
+    let mut delta_buf = [0u8; 4095];
+    let mut output_buf = [0u8; 4096 + 1024];
+    let mut third_buf = [0u8; 4096 + 1024];
+
+    let fill_patterns: &[(&str, fn(&mut [u8; 4096]))] = &[
+        ("flat        ", flat),
+        ("slow_ramp   ", slow_ramp),
+        ("noise       ", noise),
+    ];
+
+    let timer = GlobalRollingTimer::new();
+    defmt::info!("|  name          | encoding | enc bytes | ratio | Tticks | Tcycbyte |");
+
+    for (name, pattern) in fill_patterns {
+        for use_delta in [false, true].iter() {
+            // fill
+            (pattern)(&mut test_buffer);
+
+            let start = timer.get_ticks();
+
+            // Falls back to the raw block if delta encoding doesn't pay off
+            let channels = CHANNELS.count_ones() as usize;
+            let delta_used = if *use_delta {
+                delta::encode(&test_buffer, channels, &mut delta_buf)
+            } else {
+                None
+            };
+            let (encoding, payload) = match delta_used {
+                Some(used) => (AnalogEncoding::Delta, &mut delta_buf[..used]),
+                None => (AnalogEncoding::Raw, &mut test_buffer[..]),
+            };
+
+            let output = DataReport {
+                timestamp: 0x01020304,
+                seq: 0,
+
+                kind: ReportKind::AnalogPin {
+                    channel_bitflag: CHANNELS,
+                    encoding,
+                },
+
+                payload: Managed::Borrowed(payload),
+            };
+
+            let serialized = postcard::to_slice(&output, &mut output_buf).unwrap();
+            let encoded = kolben::rlercobs::encode_all(serialized, &mut third_buf, true).unwrap();
+            let len = encoded.len();
+
+            let elapsed = timer.ticks_since(start);
+            let cyc_bytes = ((elapsed as f32) / 4_000_000.0 / 4096.0) * 64_000_000.0;
+            let ratio = 4096.0 / (len as f32);
+            let encoding = if *use_delta { "delta   " } else { "raw     " };
+            defmt::info!(
+                "| {} | {} | {}\t | {} | {} | {} |",
+                name,
+                encoding,
+                len as u32,
+                ratio,
+                elapsed,
+                cyc_bytes
+            );
+
+            let now = timer.get_ticks();
+            while timer.millis_since(now) < 500 {}
+        }
+    }
+
+    diegesis_fw::exit();
+}
+
+fn fill(data: &mut [u8; 4096], mut sample: impl FnMut(usize) -> i16) {
+    data.chunks_exact_mut(2).enumerate().for_each(|(i, b)| {
+        b.copy_from_slice(&sample(i).to_le_bytes());
+    });
+}
+
+fn flat(data: &mut [u8; 4096]) {
+    fill(data, |i| 1000 + (i % 3) as i16);
+}
+
+/// Slowly rising inputs, like a charging capacitor.
+fn slow_ramp(data: &mut [u8; 4096]) {
+    fill(data, |i| ((i / 3) as i16) * 5 - (i % 3) as i16 * 700);
+}
+
+/// Full scale pseudo random codes, the worst case.
+fn noise(data: &mut [u8; 4096]) {
+    let mut state = 0x1234_5678u32;
+    fill(data, |_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        ((state >> 16) as i16) >> 2
+    });
+}
@@ -3,16 +3,17 @@
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use diegesis_fw::{Board, InternalReport, groundhog_nrf52::GlobalRollingTimer, pinmap::{PinMap, Leds}, profiler, saadc_src::SaadcSrc, spim_src::SpimSrc, time_ticks};
+use diegesis_fw::{Board, InternalReport, DIGITAL_CHANNELS, MAX_ENCODED_LEN, UART_PORTS, StreamWriter, activity::ActivityFilter, calibration::CalibrationMonitor, command::CommandReader, comparator::Comparator, gpiote_trigger::GpioteTrigger, groundhog_nrf52::GlobalRollingTimer, i2s_src::I2sSrc, marker::MarkerQueue, measure::Measure, overflow::Overflow, pdm_src::PdmSrc, pinmap::{PinMap, Leds}, pretrigger::PreTrigger, profiler, saadc_src::SaadcSrc, spim_src::{frequency_from_rate, SpimSrc}, sync_start::SyncStart, time_ticks, trigger::Trigger, uart_src::UartSrc};
+use diegesis_icd::{AutoRestart, Command, DigitalRate, ReportKind};
 use nrf52840_hal::{
     clocks::{Clocks, ExternalOscillator, Internal, LfOscStopped},
     gpio::{
-        p0::{Parts as P0Parts, P0_02, P0_03, P0_29},
+        p0::Parts as P0Parts,
         p1::Parts as P1Parts,
-        Disconnected, Level, Output, Pin, PushPull,
+        Level, Output, Pin, PushPull,
     },
-    pac::{Interrupt, SPIM0, SPIM1, SPIM2, SPIM3, TIMER1},
-    ppi::{self, Ppi0, Ppi1},
+    pac::{Interrupt, SPIM0, SPIM1, SPIM2, SPIM3, TIMER1, UARTE0, UARTE1},
+    ppi::{self, Ppi0, Ppi1, Ppi2, Ppi3, Ppi4},
     spim::Frequency,
     usbd::Usbd,
 };
@@ -21,7 +22,8 @@ use bbqueue::{consts as bbconsts, BBBuffer, ConstBBBuffer};
 use embedded_hal::digital::v2::OutputPin;
 use groundhog::RollingTimer;
 use heapless::{mpmc::MpMcQueue, pool::singleton::Pool};
-use rtic::app;
+use postcard::to_rlercobs_writer;
+use rtic::{app, Mutex};
 use usb_device::{bus::UsbBusAllocator, class::UsbClass as _, device::UsbDeviceState, prelude::*};
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
@@ -36,19 +38,45 @@ pub mod allocs {
 }
 
 static ENCODED_QUEUE: BBBuffer<bbconsts::U32768> = BBBuffer(ConstBBBuffer::new());
+static TRIGGER: Trigger = Trigger::new();
+static OVERFLOW: Overflow = Overflow::new();
 static POOL_QUEUE: MpMcQueue<InternalReport<allocs::DIGITAL_POOL, allocs::ANALOG_POOL>, 32> =
     MpMcQueue::new();
+static MARKERS: MarkerQueue<InternalReport<allocs::DIGITAL_POOL, allocs::ANALOG_POOL>> =
+    MarkerQueue::new();
 static PROFILER: Profiler = Profiler::new();
 static FUSE: AtomicBool = AtomicBool::new(true);
 
+/// Sample blocks kept per channel from before the trigger fired.
+const PRE_TRIGGER_BLOCKS: usize = 2;
+
+/// Blocks of the digital pool. Each digital channel and UART sniffer has
+/// two in flight, and holds `PRE_TRIGGER_BLOCKS` more while a trigger is
+/// armed. The rest covers the one being encoded, and one queued behind it.
+const DIGITAL_POOL_BLOCKS: usize = (2 + PRE_TRIGGER_BLOCKS) * (DIGITAL_CHANNELS + UART_PORTS) + 2;
+
+/// Time to let the sources wind down after the fuse blew.
+const FUSE_COOLDOWN_MS: u32 = 2500;
+
+/// Longest wait before an automatic restart, the rolling timer wraps after
+/// about 17 minutes.
+const MAX_RESTART_DELAY_MS: u32 = 10 * 60 * 1000;
+
 profiler!(Profiler {
     spim_p0_ints,
     spim_p1_ints,
     spim_p2_ints,
     spim_p3_ints,
+    i2s_ints,
     saadc_ints,
+    pdm_ints,
+    uart0_ints,
+    uart1_ints,
     usb_writes,
     report_sers,
+    rle_blocks,
+    delta_blocks,
+    split_grants,
     encoded_in_bytes,
     bbq_push_bytes,
     bbq_pull_bytes,
@@ -63,8 +91,12 @@ profiler!(Profiler {
     ticks_spimp1,
     ticks_spimp2,
     ticks_spimp3,
+    ticks_i2s,
 
-    ticks_saadc
+    ticks_saadc,
+    ticks_pdm,
+    ticks_uart0,
+    ticks_uart1
 } => ProfilerRpt);
 
 // TODO: Replace with "Active" and "Inactive" instead of High/Low
@@ -110,8 +142,8 @@ const APP: () = {
         spim_p1: SpimSrc<SPIM1, allocs::DIGITAL_POOL, allocs::ANALOG_POOL, 32>,
         spim_p2: SpimSrc<SPIM2, allocs::DIGITAL_POOL, allocs::ANALOG_POOL, 32>,
         spim_p3: SpimSrc<SPIM3, allocs::DIGITAL_POOL, allocs::ANALOG_POOL, 32>,
+        i2s: I2sSrc<allocs::DIGITAL_POOL, allocs::ANALOG_POOL, 32>,
         saadc: SaadcSrc<
-            (P0_02<Disconnected>, P0_03<Disconnected>, P0_29<Disconnected>),
             TIMER1,
             allocs::ANALOG_POOL,
             allocs::DIGITAL_POOL,
@@ -119,6 +151,14 @@ const APP: () = {
             Ppi1,
             32,
         >,
+        pdm: Option<PdmSrc<allocs::ANALOG_POOL, allocs::DIGITAL_POOL, 32>>,
+        uart0: UartSrc<UARTE0, allocs::DIGITAL_POOL, allocs::ANALOG_POOL, 32>,
+        uart1: UartSrc<UARTE1, allocs::DIGITAL_POOL, allocs::ANALOG_POOL, 32>,
+        sync_start: SyncStart<Ppi2, Ppi3, Ppi4>,
+        gpiote_trigger: GpioteTrigger,
+        measure: Measure,
+        comparator: Comparator,
+        calibration: CalibrationMonitor,
         start_stop_btn: <Board as PinMap>::ButtonPin,
         start_stop_led: Option<Pin<Output<PushPull>>>,
     }
@@ -127,7 +167,7 @@ const APP: () = {
     fn init(ctx: init::Context) -> init::LateResources {
         static mut CLOCKS: Option<Clocks<ExternalOscillator, Internal, LfOscStopped>> = None;
         static mut USB_BUS: Option<UsbBusAllocator<Usbd<'static>>> = None;
-        static mut DATA_POOL_A: [u8; 24 * 4096] = [0u8; 24 * 4096];
+        static mut DATA_POOL_A: [u8; DIGITAL_POOL_BLOCKS * 4096] = [0u8; DIGITAL_POOL_BLOCKS * 4096];
         static mut DATA_POOL_B: [u8; 16 * 4096] = [0u8; 16 * 4096];
 
         // Enable instruction caches for MAXIMUM SPEED
@@ -168,6 +208,13 @@ const APP: () = {
         let gpios_p1 = P1Parts::new(board.P1);
         let pins = Board::map_pins(gpios_p0, gpios_p1);
 
+        let digital_pins = [
+            pins.spim_p0_data.psel_bits(),
+            pins.spim_p1_data.psel_bits(),
+            pins.spim_p2_data.psel_bits(),
+            pins.spim_p3_data.psel_bits(),
+        ];
+
         let spim0 = SpimSrc::from_parts(
             board.SPIM0,
             pins.spim_p0_data,
@@ -176,6 +223,7 @@ const APP: () = {
             Frequency::M2,
             GlobalRollingTimer,
             0,
+            &OVERFLOW,
         );
 
         let spim1 = SpimSrc::from_parts(
@@ -186,6 +234,7 @@ const APP: () = {
             Frequency::M2,
             GlobalRollingTimer,
             1,
+            &OVERFLOW,
         );
 
         let spim2 = SpimSrc::from_parts(
@@ -196,6 +245,7 @@ const APP: () = {
             Frequency::M2,
             GlobalRollingTimer,
             2,
+            &OVERFLOW,
         );
 
         let spim3 = SpimSrc::from_parts(
@@ -206,9 +256,23 @@ const APP: () = {
             Frequency::M2,
             GlobalRollingTimer,
             3,
+            &OVERFLOW,
         );
 
         let ppi = ppi::Parts::new(board.PPI);
+        let i2s = I2sSrc::from_parts(
+            board.I2S,
+            pins.i2s_sdin,
+            pins.i2s_sck,
+            pins.i2s_lrck,
+            ppi.ppi6,
+            &POOL_QUEUE,
+            DigitalRate::M2,
+            GlobalRollingTimer,
+            4,
+            &OVERFLOW,
+        );
+
         let saadc = SaadcSrc::new(
             board.SAADC,
             board.TIMER1,
@@ -216,8 +280,71 @@ const APP: () = {
             ppi.ppi0,
             ppi.ppi1,
             &POOL_QUEUE,
+            &MARKERS,
+            &TRIGGER,
+            &OVERFLOW,
         );
 
+        // A `map` closure would move all of `board` and `ppi`
+        let pdm = match pins.mic {
+            Some(mic) => Some(PdmSrc::from_parts(
+                board.PDM,
+                mic.data,
+                mic.clk,
+                ppi.ppi7,
+                &POOL_QUEUE,
+                GlobalRollingTimer,
+                &OVERFLOW,
+            )),
+            None => None,
+        };
+
+        let uart0 = UartSrc::from_parts(
+            board.UARTE0,
+            ppi.ppi8,
+            digital_pins,
+            &POOL_QUEUE,
+            GlobalRollingTimer,
+            &OVERFLOW,
+        );
+
+        let uart1 = UartSrc::from_parts(
+            board.UARTE1,
+            ppi.ppi9,
+            digital_pins,
+            &POOL_QUEUE,
+            GlobalRollingTimer,
+            &OVERFLOW,
+        );
+
+        let sync_start = SyncStart::new(
+            board.EGU0,
+            ppi.ppi2,
+            ppi.ppi3,
+            ppi.ppi4,
+            saadc.task_start_sampling(),
+        );
+
+        let mut gpiote_trigger = GpioteTrigger::new(board.GPIOTE, ppi.ppi5, digital_pins, &TRIGGER);
+
+        let measure = Measure::new(
+            board.TIMER2,
+            board.TIMER3,
+            (ppi.ppi10, ppi.ppi11),
+            gpiote_trigger.take_channel().unwrap(),
+            digital_pins,
+        );
+
+        let comparator = Comparator::new(
+            board.COMP,
+            pins.comp_out,
+            (ppi.ppi12, ppi.ppi13),
+            gpiote_trigger.take_channel().unwrap(),
+            digital_pins,
+        );
+
+        let calibration = CalibrationMonitor::new(board.TEMP);
+
         let start_stop_btn = Board::into_button(pins.start_pause_btn);
         let start_stop_led = if let Leds::DiscreteLeds { led3, .. } = pins.leds {
             Some(led3.into_push_pull_output(Level::High))
@@ -246,7 +373,16 @@ const APP: () = {
             spim_p1: spim1,
             spim_p2: spim2,
             spim_p3: spim3,
+            i2s,
             saadc,
+            pdm,
+            uart0,
+            uart1,
+            sync_start,
+            gpiote_trigger,
+            measure,
+            comparator,
+            calibration,
 
             start_stop_btn,
             start_stop_led,
@@ -285,6 +421,14 @@ const APP: () = {
         });
     }
 
+    #[task(binds = I2S, resources = [i2s])]
+    fn i2s(c: i2s::Context) {
+        PROFILER.i2s_ints();
+        time_ticks!(PROFILER.ticks_i2s, {
+            c.resources.i2s.poll(&FUSE);
+        });
+    }
+
     #[task(binds = SAADC, resources = [saadc])]
     fn saadc(c: saadc::Context) {
         PROFILER.saadc_ints();
@@ -293,7 +437,57 @@ const APP: () = {
         });
     }
 
-    #[idle(resources = [usb_dev, serial, start_stop_btn, start_stop_led])]
+    #[task(binds = PDM, resources = [pdm])]
+    fn pdm(c: pdm::Context) {
+        PROFILER.pdm_ints();
+        time_ticks!(PROFILER.ticks_pdm, {
+            if let Some(pdm) = c.resources.pdm {
+                pdm.poll(&FUSE);
+            }
+        });
+    }
+
+    #[task(binds = UARTE0_UART0, resources = [uart0])]
+    fn uart0(c: uart0::Context) {
+        PROFILER.uart0_ints();
+        time_ticks!(PROFILER.ticks_uart0, {
+            c.resources.uart0.poll(&FUSE);
+        });
+    }
+
+    #[task(binds = UARTE1, resources = [uart1])]
+    fn uart1(c: uart1::Context) {
+        PROFILER.uart1_ints();
+        time_ticks!(PROFILER.ticks_uart1, {
+            c.resources.uart1.poll(&FUSE);
+        });
+    }
+
+    #[task(binds = GPIOTE, resources = [gpiote_trigger, measure])]
+    fn gpiote(c: gpiote::Context) {
+        c.resources.gpiote_trigger.poll();
+        c.resources.measure.poll();
+    }
+
+    #[idle(resources = [
+        usb_dev,
+        serial,
+        sync_start,
+        gpiote_trigger,
+        measure,
+        comparator,
+        calibration,
+        start_stop_btn,
+        start_stop_led,
+        spim_p0,
+        spim_p1,
+        spim_p2,
+        spim_p3,
+        i2s,
+        saadc,
+        uart0,
+        uart1,
+    ])]
     fn idle(mut c: idle::Context) -> ! {
         let mut state: UsbDeviceState = UsbDeviceState::Default;
         let timer = GlobalRollingTimer::new();
@@ -305,12 +499,20 @@ const APP: () = {
 
         let mut button = ButtonDebounce::StableHigh;
         let mut running = false;
+        let mut awaiting_trigger = false;
+        let mut auto_restart: Option<AutoRestart> = None;
+        let mut restarts = 0;
 
         let mut last_loop = timer.get_ticks();
         let mut min_ticks = 0xFFFFFFFF;
         let mut max_ticks = 0x00000000;
 
-        let mut temp_buf = [0u8; 4096 + 1024];
+        let mut compress_buf = [0u8; 4095];
+        let mut compress_digital = false;
+        let mut compress_analog = false;
+        let mut commands = CommandReader::new();
+        let mut pre_trigger = PreTrigger::<_, _, PRE_TRIGGER_BLOCKS>::new();
+        let mut activity = ActivityFilter::new();
 
         loop {
             let elapsed = timer.ticks_since(last_loop);
@@ -347,12 +549,20 @@ const APP: () = {
             // FUSES, START, AND STOP
             /////////////////////////////////////////////////////////
             time_ticks!(PROFILER.ticks_misc, {
+                let mut start = false;
                 let is_active = Board::button_active(c.resources.start_stop_btn);
                 if let Some(Level::Low) = button.poll(is_active) {
                     if running {
                         // Stopping by blowing the fuse
                         defmt::info!("Stopping!");
                         FUSE.store(true, Ordering::SeqCst);
+                        if awaiting_trigger {
+                            // Start the armed sources so they can wind down,
+                            // their samples are dropped as the trigger never fired
+                            c.resources.gpiote_trigger.lock(|t| t.disarm());
+                            c.resources.sync_start.trigger();
+                            awaiting_trigger = false;
+                        }
                         if let Some(led) = c.resources.start_stop_led.as_mut() {
                             led.set_high().ok();
                         }
@@ -360,32 +570,87 @@ const APP: () = {
                     } else if fuse_timeout.is_some() {
                         // TODO: start after the fuse is cleared?
                         defmt::info!("Not starting, waiting for fuse!");
+
+                        // Pressing the button also cancels any automatic restart
+                        restarts = u8::MAX;
                     } else {
                         defmt::info!("Starting!");
-                        FUSE.store(false, Ordering::SeqCst);
-                        rtic::pend(Interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
-                        rtic::pend(Interrupt::SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1);
-                        rtic::pend(Interrupt::SPIM2_SPIS2_SPI2);
-                        rtic::pend(Interrupt::SPIM3);
-                        rtic::pend(Interrupt::SAADC);
+                        restarts = 0;
+                        start = true;
+                    }
+                }
 
-                        if let Some(led) = c.resources.start_stop_led.as_mut() {
-                            led.set_low().ok();
+                if awaiting_trigger {
+                    if let Some(start_tick) = c.resources.sync_start.poll_started() {
+                        defmt::info!("Triggered!");
+                        awaiting_trigger = false;
+                        MARKERS.send(InternalReport::capture_start(start_tick));
+                        if let Some(source) = c.resources.gpiote_trigger.lock(|t| t.fired()) {
+                            MARKERS.send(InternalReport::trigger(start_tick, source));
                         }
-                        running = true;
                     }
                 }
 
                 if let Some(tick) = fuse_timeout.take() {
-                    if timer.millis_since(tick) > 2500 {
+                    let restart = auto_restart.filter(|cfg| restarts < cfg.max_attempts);
+                    let cooldown_ms = restart
+                        .map(|cfg| {
+                            cfg.delay_ms(restarts + 1)
+                                .clamp(FUSE_COOLDOWN_MS, MAX_RESTART_DELAY_MS)
+                        })
+                        .unwrap_or(FUSE_COOLDOWN_MS);
+
+                    if timer.millis_since(tick) > cooldown_ms {
                         defmt::info!("Fuse restored! Cleared");
-                        // NOTE: DON'T auto-clear the fuse! wait for an explicit run command
+                        // NOTE: DON'T auto-clear the fuse! wait for an explicit run command,
+                        // unless automatic restarts were requested
+                        if restart.is_some() {
+                            restarts += 1;
+                            defmt::info!("Restarting, attempt {}", restarts);
+                            MARKERS.send(InternalReport::restart(timer.get_ticks(), restarts));
+                            start = true;
+                        }
                     } else {
                         // Still cooling...
                         fuse_timeout = Some(tick);
                     }
                 }
 
+                if start {
+                    FUSE.store(false, Ordering::SeqCst);
+                    TRIGGER.reset();
+                    OVERFLOW.reset();
+                    MARKERS.reset();
+                    pre_trigger.clear();
+                    rtic::pend(Interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
+                    rtic::pend(Interrupt::SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1);
+                    rtic::pend(Interrupt::SPIM2_SPIS2_SPI2);
+                    rtic::pend(Interrupt::SPIM3);
+                    rtic::pend(Interrupt::I2S);
+                    rtic::pend(Interrupt::SAADC);
+                    rtic::pend(Interrupt::PDM);
+                    rtic::pend(Interrupt::UARTE0_UART0);
+                    rtic::pend(Interrupt::UARTE1);
+
+                    // The sources only arm their peripherals when pended,
+                    // make sure they all ran before starting them at once.
+                    cortex_m::asm::dsb();
+                    cortex_m::asm::isb();
+                    c.resources.sync_start.reset();
+                    if c.resources.gpiote_trigger.lock(|t| t.arm()) {
+                        defmt::info!("Waiting for trigger!");
+                        awaiting_trigger = true;
+                    } else {
+                        let start_tick = c.resources.sync_start.trigger();
+                        MARKERS.send(InternalReport::capture_start(start_tick));
+                    }
+
+                    if let Some(led) = c.resources.start_stop_led.as_mut() {
+                        led.set_low().ok();
+                    }
+                    running = true;
+                }
+
                 if running && fuse_timeout.is_none() && FUSE.load(Ordering::SeqCst) {
                     defmt::info!("Fuse blown! Cooling down...");
                     if let Some(led) = c.resources.start_stop_led.as_mut() {
@@ -410,25 +675,203 @@ const APP: () = {
 
                     min_ticks = 0xFFFFFFFF;
                     max_ticks = 0x00000000;
+
+                    // Ticks are 4MHz, so this is in bytes per millisecond
+                    if rpt.ticks_encoding != 0 {
+                        defmt::info!(
+                            "encoding: {} B/ms in, {} B/ms out",
+                            ((rpt.encoded_in_bytes as u64 * 4_000) / rpt.ticks_encoding as u64) as u32,
+                            ((rpt.bbq_push_bytes as u64 * 4_000) / rpt.ticks_encoding as u64) as u32,
+                        );
+                    }
+
+                    let lost = OVERFLOW.lost();
+                    if lost.iter().any(|n| *n != 0) {
+                        defmt::warn!("Lost blocks: {}", lost);
+                    }
+                    if MARKERS.lost() != 0 {
+                        defmt::warn!("Lost markers: {}", MARKERS.lost());
+                    }
+                }
+
+                if let Some((tick, channel, measurement)) = c.resources.measure.lock(|m| m.poll_gate()) {
+                    MARKERS.send(InternalReport::measurement(tick, channel, measurement));
+                }
+
+                // Bytes are only sent once the sniffers flushed them
+                c.resources.uart0.lock(|u| u.flush(&FUSE));
+                c.resources.uart1.lock(|u| u.flush(&FUSE));
+
+                // Requests made while stopped are handled once the next capture starts
+                if let Some(temperature) = c.resources.calibration.poll() {
+                    defmt::info!("Requesting SAADC recalibration at {} / 4 C", temperature);
+                    c.resources
+                        .saadc
+                        .lock(|s| s.request_recalibration(temperature));
                 }
             });
 
-            // TODO: read?
+            /////////////////////////////////////////////////////////
+            // HOST COMMANDS
+            /////////////////////////////////////////////////////////
+            let mut rx_buf = [0u8; 64];
+            if let Ok(n) = serial.read(&mut rx_buf) {
+                for byte in &rx_buf[..n] {
+                    let cmd = match commands.push(*byte) {
+                        Some(cmd) => cmd,
+                        None => continue,
+                    };
 
-            // TODO: with a little more complexity, we could use split grants
-            // for a more efficient use of the encoding buffer. For now, we may
-            // end up wasting 0 <= n < 5KiB at the end of the ring, which is a
-            // whole pbox worth (7.8% of 64K capacity)
-            if let Ok(mut wgr) = enc_prod.grant_exact(1024 + 4096) {
-                if let Some(mut new_rpt) = POOL_QUEUE.dequeue() {
+                    if running {
+                        defmt::warn!("Ignoring command while running!");
+                        continue;
+                    }
+
+                    let result = match cmd {
+                        Command::SetDigitalRate { channel, rate } => {
+                            let freq = frequency_from_rate(rate);
+                            match channel {
+                                0 => c.resources.spim_p0.lock(|s| s.set_frequency(freq)),
+                                1 => c.resources.spim_p1.lock(|s| s.set_frequency(freq)),
+                                2 => c.resources.spim_p2.lock(|s| s.set_frequency(freq)),
+                                3 => c.resources.spim_p3.lock(|s| s.set_frequency(freq)),
+                                4 => c.resources.i2s.lock(|s| s.set_rate(rate)),
+                                _ => Err(()),
+                            }
+                        }
+                        Command::SetAnalogConfig(config) => {
+                            c.resources.saadc.lock(|s| s.set_config(config))
+                        }
+                        Command::SetAnalogTrigger(trigger) => {
+                            c.resources.saadc.lock(|s| s.set_trigger(trigger))
+                        }
+                        Command::SetDigitalTrigger(trigger) => {
+                            c.resources.gpiote_trigger.lock(|t| t.set_config(trigger))
+                        }
+                        Command::SetOverflowPolicy(policy) => {
+                            OVERFLOW.set_policy(policy);
+                            Ok(())
+                        }
+                        Command::SetAutoRestart(restart) => {
+                            auto_restart = restart;
+                            Ok(())
+                        }
+                        Command::SetActivityOnly(enabled) => {
+                            activity.set_enabled(enabled);
+                            Ok(())
+                        }
+                        Command::SetDigitalCompression(enabled) => {
+                            compress_digital = enabled;
+                            Ok(())
+                        }
+                        Command::SetAnalogCompression(enabled) => {
+                            compress_analog = enabled;
+                            Ok(())
+                        }
+                        Command::SetUartSniffer { port, config } => match port {
+                            0 => c.resources.uart0.lock(|u| u.set_config(config)),
+                            1 => c.resources.uart1.lock(|u| u.set_config(config)),
+                            _ => Err(()),
+                        },
+                        Command::SetMeasurement(config) => {
+                            c.resources.measure.lock(|m| m.set_config(config))
+                        }
+                        Command::SetComparator(config) => {
+                            let (p0, p1, p2, p3) = (
+                                &mut c.resources.spim_p0,
+                                &mut c.resources.spim_p1,
+                                &mut c.resources.spim_p2,
+                                &mut c.resources.spim_p3,
+                            );
+                            let mut set_data_pins = |pins: [u32; 4]| {
+                                p0.lock(|s| s.set_data_pin(pins[0]))
+                                    .and_then(|_| p1.lock(|s| s.set_data_pin(pins[1])))
+                                    .and_then(|_| p2.lock(|s| s.set_data_pin(pins[2])))
+                                    .and_then(|_| p3.lock(|s| s.set_data_pin(pins[3])))
+                            };
+
+                            let comparator = &mut c.resources.comparator;
+                            let previous = comparator.config();
+                            let result = comparator
+                                .set_config(config)
+                                .and_then(|_| set_data_pins(comparator.data_pins()));
+                            if result.is_err() {
+                                // Don't leave some of the channels switched over
+                                comparator.set_config(previous).ok();
+                                set_data_pins(comparator.data_pins()).ok();
+                            }
+                            result
+                        }
+                    };
+
+                    if result.is_err() {
+                        defmt::warn!("Rejected command!");
+                    }
+                }
+            }
+
+            // Reports are encoded straight into the ring, possibly wrapping
+            // around its end, so only start one once the worst case fits
+            if diegesis_fw::free_space(&mut enc_cons) >= MAX_ENCODED_LEN {
+                // Markers go out first. Sample data captured before the
+                // trigger fired is held back, and sent ahead of newer blocks
+                // once it fires
+                let new_rpt = if let Some(held) = activity.pop() {
+                    Some(held)
+                } else if let Some(marker) = MARKERS.dequeue() {
+                    Some(marker)
+                } else if TRIGGER.is_streaming() {
+                    pre_trigger
+                        .pop()
+                        .or_else(|| POOL_QUEUE.dequeue())
+                        .and_then(|rpt| activity.filter(rpt))
+                } else {
+                    if let Some(rpt) = POOL_QUEUE.dequeue() {
+                        pre_trigger.hold(rpt);
+                    }
+                    None
+                };
+
+                // Idle runs still open are sent once the capture has ended
+                let new_rpt = match new_rpt {
+                    None if !running => activity.flush(),
+                    other => other,
+                };
+                if let Some(mut new_rpt) = new_rpt {
                     time_ticks!(PROFILER.ticks_encoding, {
                         PROFILER.report_sers();
 
-                        let report = new_rpt.as_data_report();
-                        let serialized = postcard::to_slice(&report, &mut temp_buf).unwrap();
+                        let mut report = new_rpt.as_data_report();
+                        PROFILER
+                            .encoded_in_bytes
+                            .fetch_add(report.payload.len() as u32, Ordering::SeqCst);
+
+                        match report.kind {
+                            ReportKind::DigitalPin { .. } if compress_digital => {
+                                if diegesis_fw::compress_digital(&mut report, &mut compress_buf) {
+                                    PROFILER.rle_blocks();
+                                }
+                            }
+                            ReportKind::AnalogPin { .. } if compress_analog => {
+                                if diegesis_fw::compress_analog(&mut report, &mut compress_buf) {
+                                    PROFILER.delta_blocks();
+                                }
+                            }
+                            _ => {}
+                        }
 
-                        let len = kolben::rlercobs::encode_all(serialized, &mut wgr, true).unwrap().len();
-                        wgr.commit(len);
+                        let (len, split) = match to_rlercobs_writer(&report, StreamWriter::new(&mut enc_prod)) {
+                            Ok(writer) => writer.finish(),
+                            Err(_) => {
+                                // Only possible if the free space check above
+                                // is wrong, what was committed stays torn
+                                defmt::warn!("Encoded report cut short!");
+                                (0, false)
+                            }
+                        };
+                        if split {
+                            PROFILER.split_grants();
+                        }
 
                         PROFILER
                             .bbq_push_bytes
@@ -30,7 +30,7 @@ use usb_device::{bus::UsbBusAllocator, class::UsbClass as _, device::UsbDeviceSt
 use usbd_serial::{SerialPort, USB_CLASS_CDC};
 use serde::{Serialize, Deserialize};
 
-use diegesis_icd::{DataReport, Managed, ReportKind};
+use diegesis_icd::{rle, DataReport, DigitalEncoding, Managed, ReportKind};
 
 use rlercobs::Write as _;
 
@@ -68,11 +68,13 @@ fn main() -> ! {
     let mut test_buffer = [0u8; 4096];
     let mut output_buf = [0u8; 4096 + 1024];
     let mut third_buf = [0u8; 4096 + 1024];
+    let mut rle_buf = [0u8; 4095];
 
     let fill_patterns: &[(&str, fn(&mut [u8; 4096]))] = &[
         ("all_zeroes  ", all_zeroes),
         ("all_ones    ", all_ones),
         ("rolling_data", rolling_data),
+        ("slow_toggle ", slow_toggle),
     ];
 
     let timer = GlobalRollingTimer::new();
@@ -85,8 +87,12 @@ fn main() -> ! {
         // encode
         let output = DataReport {
             timestamp: 0x01020304,
+            seq: 0,
 
-            kind: ReportKind::DigitalPin { channel: 23 },
+            kind: ReportKind::DigitalPin {
+                channel: 23,
+                encoding: DigitalEncoding::Raw,
+            },
 
             payload: Managed::Borrowed(&mut test_buffer),
         };
@@ -117,8 +123,12 @@ fn main() -> ! {
         // encode
         let output = DataReport {
             timestamp: 0x01020304,
+            seq: 0,
 
-            kind: ReportKind::DigitalPin { channel: 23 },
+            kind: ReportKind::DigitalPin {
+                channel: 23,
+                encoding: DigitalEncoding::Raw,
+            },
 
             payload: Managed::Borrowed(&mut test_buffer),
         };
@@ -155,6 +165,45 @@ fn main() -> ! {
         while timer.millis_since(now) < 500 { }
     }
 
+    defmt::info!("Run-length encoded:");
+    for (name, pattern) in fill_patterns {
+        // fill
+        (pattern)(&mut test_buffer);
+
+        let start = timer.get_ticks();
+
+        // Falls back to the raw block if run-length encoding doesn't pay off
+        let (encoding, payload) = match rle::encode(&test_buffer, &mut rle_buf) {
+            Some(used) => (DigitalEncoding::RunLength, &mut rle_buf[..used]),
+            None => (DigitalEncoding::Raw, &mut test_buffer[..]),
+        };
+        let output = DataReport {
+            timestamp: 0x01020304,
+            seq: 0,
+
+            kind: ReportKind::DigitalPin {
+                channel: 23,
+                encoding,
+            },
+
+            payload: Managed::Borrowed(payload),
+        };
+
+        let rle_elapsed = timer.ticks_since(start);
+
+        let serialized = postcard::to_slice(&output, &mut output_buf).unwrap();
+        let encoded = kolben::rlercobs::encode_all(serialized, &mut third_buf, true).unwrap();
+        let len = encoded.len();
+
+        let elapsed = timer.ticks_since(start);
+        let cyc_bytes = ((elapsed as f32) / 4_000_000.0 / 4096.0) * 64_000_000.0;
+        defmt::info!("| {} | {}\t | {} | {} |", name, len as u32, elapsed, cyc_bytes);
+        defmt::info!("rle: {}, ser+enc: {}", rle_elapsed, elapsed - rle_elapsed);
+
+        let now = timer.get_ticks();
+        while timer.millis_since(now) < 500 { }
+    }
+
     diegesis_fw::exit();
 }
 
@@ -174,6 +223,13 @@ fn rolling_data(data: &mut [u8; 4096]) {
     });
 }
 
+/// A line idling high, toggling every 64 samples.
+fn slow_toggle(data: &mut [u8; 4096]) {
+    data.iter_mut().enumerate().for_each(|(i, b)| {
+        *b = if (i / 8) % 2 == 0 { 0xFF } else { 0x00 };
+    });
+}
+
 
 #[derive(Debug)]
 pub struct FillBuf<'a> {
@@ -0,0 +1,78 @@
+//! Decides when the SAADC offset should be recalibrated during a capture.
+//!
+//! Nordic recommends recalibrating whenever the temperature changed by more
+//! than 10°C. The die temperature is measured once per second, and a
+//! recalibration is also requested after a fixed interval as a fallback.
+
+use crate::groundhog_nrf52::GlobalRollingTimer;
+use groundhog::RollingTimer;
+use nrf52840_hal::pac::TEMP;
+
+/// Temperature change requiring a recalibration, in 0.25°C steps.
+const RECAL_TEMP_DELTA: i32 = 10 * 4;
+
+/// Time between two temperature measurements.
+const MEASURE_INTERVAL_MS: u32 = 1000;
+
+/// Longest time between two calibrations. Longer than the global timer takes
+/// to wrap, so the time between measurements is added up instead.
+const RECAL_INTERVAL_MS: u32 = 30 * 60 * 1000;
+
+pub struct CalibrationMonitor {
+    temp: TEMP,
+    timer: GlobalRollingTimer,
+    last_measure: u32,
+    measuring: bool,
+    calibrated_at: Option<i32>,
+    ms_since_cal: u32,
+}
+
+impl CalibrationMonitor {
+    pub fn new(temp: TEMP) -> Self {
+        let timer = GlobalRollingTimer::new();
+        Self {
+            temp,
+            last_measure: timer.get_ticks(),
+            timer,
+            measuring: false,
+            calibrated_at: None,
+            ms_since_cal: 0,
+        }
+    }
+
+    /// Poll the temperature sensor. Returns the die temperature, in 0.25°C
+    /// steps, when a recalibration is due.
+    pub fn poll(&mut self) -> Option<i32> {
+        if !self.measuring {
+            let elapsed = self.timer.millis_since(self.last_measure);
+            if elapsed >= MEASURE_INTERVAL_MS {
+                self.ms_since_cal = self.ms_since_cal.saturating_add(elapsed);
+                self.last_measure = self.timer.get_ticks();
+                self.temp.events_datardy.reset();
+                self.temp.tasks_start.write(|w| unsafe { w.bits(1) });
+                self.measuring = true;
+            }
+            return None;
+        }
+
+        if self.temp.events_datardy.read().bits() == 0 {
+            return None;
+        }
+        self.temp.events_datardy.reset();
+        self.temp.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.measuring = false;
+
+        let temperature = self.temp.temp.read().bits() as i32;
+
+        // The SAADC was calibrated at boot, take that as the reference
+        let calibrated_at = *self.calibrated_at.get_or_insert(temperature);
+        let drifted = (temperature - calibrated_at).abs() >= RECAL_TEMP_DELTA;
+        if drifted || (self.ms_since_cal >= RECAL_INTERVAL_MS) {
+            self.calibrated_at = Some(temperature);
+            self.ms_since_cal = 0;
+            Some(temperature)
+        } else {
+            None
+        }
+    }
+}
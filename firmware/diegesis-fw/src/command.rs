@@ -0,0 +1,49 @@
+//! Reception of commands sent by the host.
+
+use diegesis_icd::Command;
+use heapless::Vec;
+
+pub struct CommandReader {
+    buf: Vec<u8, { Command::MAX_ENCODED_LEN }>,
+    overflowed: bool,
+}
+
+impl CommandReader {
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Feed a received byte, returning a command once a full frame is received.
+    pub fn push(&mut self, byte: u8) -> Option<Command> {
+        if byte != 0 {
+            // Keep room for the terminator
+            if self.buf.len() + 1 < self.buf.capacity() {
+                self.buf.push(byte).ok();
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let cmd = if self.overflowed {
+            defmt::warn!("Dropping oversized command");
+            None
+        } else {
+            // Re-add the terminator, as the decoder expects a whole frame.
+            // There is always room left for it.
+            self.buf.push(0).ok();
+            let cmd = postcard::from_bytes_cobs(&mut self.buf).ok();
+            if cmd.is_none() {
+                defmt::warn!("Dropping malformed command");
+            }
+            cmd
+        };
+
+        self.buf.clear();
+        self.overflowed = false;
+        cmd
+    }
+}
@@ -0,0 +1,172 @@
+//! Digital channels at other logic levels, through the comparator.
+//!
+//! The COMP compares an analog input against thresholds set by the host,
+//! and its UP and DOWN events set and clear a GPIOTE task pin through PPI.
+//! That pin isn't connected on the board. The SPIM of a digital channel
+//! samples it in place of its own pin, so the comparator output is captured
+//! like any other digital channel.
+//!
+//! The thresholds only exist in single ended mode, where the upper and lower
+//! one give the hysteresis.
+
+use crate::gpiote_trigger::GpioteChannel;
+use diegesis_icd::{ComparatorConfig, ComparatorReference};
+use nrf52840_hal::{
+    gpio::{Floating, Input, Pin},
+    pac::{COMP, PPI},
+    ppi::{Ppi12, Ppi13},
+};
+
+/// The PPI channels owned through `Ppi12` and `Ppi13`.
+const UP_PPI_CH: usize = 12;
+const DOWN_PPI_CH: usize = 13;
+
+pub struct Comparator {
+    comp: COMP,
+    _out: Pin<Input<Floating>>,
+    out_psel: u32,
+    _ppis: (Ppi12, Ppi13),
+    /// Drives the output pin.
+    gpiote: GpioteChannel,
+    /// PSEL bits of the data pin of each digital channel.
+    pins: [u32; 4],
+    config: Option<ComparatorConfig>,
+}
+
+impl Comparator {
+    /// `out` must not be connected to anything, it is driven with the
+    /// comparator output.
+    pub fn new<OUT>(
+        comp: COMP,
+        out: Pin<OUT>,
+        ppis: (Ppi12, Ppi13),
+        gpiote: GpioteChannel,
+        pins: [u32; 4],
+    ) -> Self {
+        // The input buffer stays connected once GPIOTE drives the pin, so
+        // the SPIM can read it back
+        let out = out.into_floating_input();
+        let out_psel = out.psel_bits();
+
+        // SAFETY: We own `Ppi12` and `Ppi13`
+        unsafe {
+            let ppi = &*PPI::ptr();
+            ppi.ch[UP_PPI_CH]
+                .eep
+                .write(|w| w.bits(&comp.events_up as *const _ as u32));
+            ppi.ch[UP_PPI_CH]
+                .tep
+                .write(|w| w.bits(gpiote.task_set() as *const _ as u32));
+            ppi.ch[DOWN_PPI_CH]
+                .eep
+                .write(|w| w.bits(&comp.events_down as *const _ as u32));
+            ppi.ch[DOWN_PPI_CH]
+                .tep
+                .write(|w| w.bits(gpiote.task_clr() as *const _ as u32));
+        }
+
+        Self {
+            comp,
+            _out: out,
+            out_psel,
+            _ppis: ppis,
+            gpiote,
+            pins,
+            config: None,
+        }
+    }
+
+    /// Start comparing, or stop with `None`. Apply `data_pins` to the
+    /// digital channels afterwards.
+    pub fn set_config(&mut self, config: Option<ComparatorConfig>) -> Result<(), ()> {
+        if let Some(cfg) = config {
+            if !cfg.is_valid(self.pins.len() as u8) {
+                return Err(());
+            }
+            if self.gpiote.is_claimed_elsewhere(self.out_psel) {
+                return Err(());
+            }
+        }
+        self.stop();
+
+        let cfg = match config {
+            Some(cfg) => cfg,
+            None => return Ok(()),
+        };
+
+        let comp = &self.comp;
+        comp.psel.write(|w| unsafe { w.bits(u32::from(cfg.ain)) });
+        comp.refsel.write(|w| match cfg.reference {
+            ComparatorReference::Internal1V2 => w.refsel().int1v2(),
+            ComparatorReference::Internal1V8 => w.refsel().int1v8(),
+            ComparatorReference::Internal2V4 => w.refsel().int2v4(),
+            ComparatorReference::Vdd => w.refsel().vdd(),
+        });
+        // VUP = (THUP + 1) / 64 * VREF, the same goes for VDOWN
+        let th = u32::from(cfg.threshold_down - 1) | (u32::from(cfg.threshold_up - 1) << 8);
+        comp.th.write(|w| unsafe { w.bits(th) });
+        comp.mode.write(|w| w.sp().high().main().se());
+        comp.enable.write(|w| w.enable().enabled());
+
+        comp.events_ready.reset();
+        comp.tasks_start.write(|w| unsafe { w.bits(1) });
+        while comp.events_ready.read().bits() == 0 {}
+        comp.events_ready.reset();
+
+        let above = self.sample();
+        self.gpiote.drive(self.out_psel, above)?;
+
+        comp.events_up.reset();
+        comp.events_down.reset();
+        // SAFETY: We own both channels
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.chenset
+            .write(|w| unsafe { w.bits((1 << UP_PPI_CH) | (1 << DOWN_PPI_CH)) });
+
+        // A crossing before the channels were enabled went nowhere
+        if self.sample() != above {
+            if above {
+                self.gpiote.task_clr().write(|w| unsafe { w.bits(1) });
+            } else {
+                self.gpiote.task_set().write(|w| unsafe { w.bits(1) });
+            }
+        }
+
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn config(&self) -> Option<ComparatorConfig> {
+        self.config
+    }
+
+    /// The PSEL bits each digital channel should sample, its own pin or the
+    /// comparator output.
+    pub fn data_pins(&self) -> [u32; 4] {
+        let mut pins = self.pins;
+        if let Some(cfg) = self.config {
+            pins[cfg.channel as usize] = self.out_psel;
+        }
+        pins
+    }
+
+    /// Whether the input is above the threshold.
+    fn sample(&self) -> bool {
+        self.comp.tasks_sample.write(|w| unsafe { w.bits(1) });
+        self.comp.result.read().bits() & 1 != 0
+    }
+
+    fn stop(&mut self) {
+        // SAFETY: We own both channels
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.chenclr
+            .write(|w| unsafe { w.bits((1 << UP_PPI_CH) | (1 << DOWN_PPI_CH)) });
+
+        self.gpiote.disable();
+
+        if self.config.take().is_some() {
+            self.comp.tasks_stop.write(|w| unsafe { w.bits(1) });
+            self.comp.enable.write(|w| w.enable().disabled());
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Digital trigger, starting a capture on the digital channels' pins.
+//!
+//! Edge triggers route the GPIOTE event through PPI straight into the
+//! `SyncStart` task, so the sources start within a few cycles of the edge.
+//! Pattern triggers can't be matched in hardware, so every edge on one of
+//! the masked pins interrupts, and the levels are checked in software.
+//!
+//! The pins stay connected to their SPIM, GPIOTE only listens in.
+//!
+//! The trigger owns the GPIOTE, and hands out its remaining channels to the
+//! other users. A pin can only be used by one channel at a time, so each
+//! channel claims its pin while configured, and a second claim is refused.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{sync_start, trigger::Trigger};
+use diegesis_icd::{DigitalTrigger, Edge, TriggerSource};
+use nrf52840_hal::{
+    pac::{
+        gpiote::{EVENTS_IN, TASKS_CLR, TASKS_SET},
+        GPIOTE, P0, P1, PPI,
+    },
+    ppi::Ppi5,
+};
+
+/// The PPI channel owned through `Ppi5`, and the group used to disable it
+/// in hardware once it fired, so later edges don't restart the sources.
+const PPI_CH: usize = 5;
+const PPI_GROUP: usize = 0;
+
+/// GPIOTE channels used by the trigger, one per digital channel. The
+/// others are handed out through `take_channel`.
+const TRIGGER_CHANNELS: usize = 4;
+const INTEN_MASK: u32 = (1 << TRIGGER_CHANNELS) - 1;
+
+const GPIOTE_CHANNELS: usize = 8;
+
+/// PSEL bits of the pin claimed by each GPIOTE channel, or `UNCLAIMED`.
+static CLAIMED: [AtomicU32; GPIOTE_CHANNELS] = {
+    const FREE: AtomicU32 = AtomicU32::new(UNCLAIMED);
+    [FREE; GPIOTE_CHANNELS]
+};
+const UNCLAIMED: u32 = u32::MAX;
+
+pub struct GpioteTrigger {
+    gpiote: GPIOTE,
+    _ppi: Ppi5,
+    /// PSEL bits of the data pin of each digital channel.
+    pins: [u32; 4],
+    trigger: &'static Trigger,
+    config: Option<DigitalTrigger>,
+    armed: bool,
+    next_channel: usize,
+}
+
+impl GpioteTrigger {
+    pub fn new(gpiote: GPIOTE, ppi: Ppi5, pins: [u32; 4], trigger: &'static Trigger) -> Self {
+        Self {
+            gpiote,
+            _ppi: ppi,
+            pins,
+            trigger,
+            config: None,
+            armed: false,
+            next_channel: TRIGGER_CHANNELS,
+        }
+    }
+
+    /// Hand out one of the GPIOTE channels the trigger doesn't use.
+    pub fn take_channel(&mut self) -> Option<GpioteChannel> {
+        if self.next_channel >= GPIOTE_CHANNELS {
+            return None;
+        }
+        let ch = self.next_channel;
+        self.next_channel += 1;
+        Some(GpioteChannel { ch })
+    }
+
+    /// Only applied while stopped. Refused if another GPIOTE channel uses
+    /// one of the pins, e.g. for a measurement.
+    pub fn set_config(&mut self, config: Option<DigitalTrigger>) -> Result<(), ()> {
+        if self.armed {
+            return Err(());
+        }
+        let mask = match config {
+            Some(cfg) if !cfg.is_valid(self.pins.len() as u8) => return Err(()),
+            Some(DigitalTrigger::Edge { channel, .. }) => 1 << channel,
+            Some(DigitalTrigger::Pattern { mask, .. }) => mask,
+            None => 0,
+        };
+
+        let used = |ch: usize| mask & (1 << ch) != 0;
+        if (0..self.pins.len()).any(|ch| used(ch) && claimed_elsewhere(ch, self.pins[ch])) {
+            return Err(());
+        }
+        for (ch, pin) in self.pins.iter().enumerate() {
+            let claim = if used(ch) { *pin } else { UNCLAIMED };
+            CLAIMED[ch].store(claim, Ordering::SeqCst);
+        }
+
+        self.config = config;
+        Ok(())
+    }
+
+    /// Arm the trigger for the next capture. Returns false without a trigger,
+    /// in which case the capture should be started right away.
+    ///
+    /// Sample data is held back through `Trigger` until `fired` is called,
+    /// so the sources may be started to wind them down if stopped early.
+    pub fn arm(&mut self) -> bool {
+        let cfg = match self.config {
+            Some(cfg) => cfg,
+            None => return false,
+        };
+
+        match cfg {
+            DigitalTrigger::Edge { channel, edge } => {
+                let ch = channel as usize;
+                self.listen(ch, edge);
+
+                // SAFETY: We own `Ppi5`, and are the only user of the PPI group
+                let ppi = unsafe { &*PPI::ptr() };
+                ppi.chg[PPI_GROUP].write(|w| unsafe { w.bits(1 << PPI_CH) });
+                ppi.ch[PPI_CH]
+                    .eep
+                    .write(|w| unsafe { w.bits(&self.gpiote.events_in[ch] as *const _ as u32) });
+                ppi.ch[PPI_CH]
+                    .tep
+                    .write(|w| unsafe { w.bits(sync_start::trigger_task() as *const _ as u32) });
+                ppi.fork[PPI_CH]
+                    .tep
+                    .write(|w| unsafe { w.bits(&ppi.tasks_chg[PPI_GROUP].dis as *const _ as u32) });
+                ppi.chenset.write(|w| unsafe { w.bits(1 << PPI_CH) });
+            }
+            DigitalTrigger::Pattern { mask, .. } => {
+                for ch in 0..self.pins.len() {
+                    if mask & (1 << ch) != 0 {
+                        self.listen(ch, Edge::Any);
+                        self.gpiote.intenset.write(|w| unsafe { w.bits(1 << ch) });
+                    }
+                }
+            }
+        }
+
+        self.trigger.arm();
+        self.armed = true;
+        true
+    }
+
+    /// Stop listening to the pins.
+    pub fn disarm(&mut self) {
+        // SAFETY: See `arm`
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.chenclr.write(|w| unsafe { w.bits(1 << PPI_CH) });
+        ppi.fork[PPI_CH].tep.write(|w| unsafe { w.bits(0) });
+
+        self.gpiote
+            .intenclr
+            .write(|w| unsafe { w.bits(INTEN_MASK) });
+        for ch in 0..self.pins.len() {
+            self.gpiote.config[ch].write(|w| w.mode().disabled());
+            self.gpiote.events_in[ch].reset();
+        }
+        self.armed = false;
+    }
+
+    /// Called once the armed capture was started. Returns the source to
+    /// report, if this trigger started it.
+    pub fn fired(&mut self) -> Option<TriggerSource> {
+        if !self.armed {
+            return None;
+        }
+        self.disarm();
+
+        if self.trigger.fire() {
+            self.config.map(TriggerSource::Digital)
+        } else {
+            None
+        }
+    }
+
+    /// To be called from the GPIOTE interrupt, only enabled by pattern triggers.
+    pub fn poll(&mut self) {
+        for ch in 0..self.pins.len() {
+            self.gpiote.events_in[ch].reset();
+        }
+
+        let (mask, levels) = match self.config {
+            Some(DigitalTrigger::Pattern { mask, levels }) if self.armed => (mask, levels),
+            _ => return,
+        };
+
+        let current = self
+            .pins
+            .iter()
+            .enumerate()
+            .fold(0u8, |acc, (ch, pin)| acc | ((pin_level(*pin) as u8) << ch));
+
+        if (current ^ levels) & mask == 0 {
+            self.gpiote
+                .intenclr
+                .write(|w| unsafe { w.bits(INTEN_MASK) });
+            sync_start::trigger_task().write(|w| unsafe { w.bits(1) });
+        }
+    }
+
+    fn listen(&mut self, ch: usize, edge: Edge) {
+        let pin = self.pins[ch];
+        self.gpiote.events_in[ch].reset();
+        self.gpiote.config[ch].write(|w| {
+            let w = unsafe { w.mode().event().psel().bits((pin & 0x1F) as u8) };
+            let w = w.port().bit(pin & 0x20 != 0);
+            match edge {
+                Edge::Rising => w.polarity().lo_to_hi(),
+                Edge::Falling => w.polarity().hi_to_lo(),
+                Edge::Any => w.polarity().toggle(),
+            }
+        });
+    }
+}
+
+/// Current level of the pin with PSEL bits `psel`.
+pub fn pin_level(psel: u32) -> bool {
+    // SAFETY: Read only access to the input registers
+    let input = if psel & 0x20 == 0 {
+        unsafe { (*P0::ptr()).in_.read().bits() }
+    } else {
+        unsafe { (*P1::ptr()).in_.read().bits() }
+    };
+    input & (1 << (psel & 0x1F)) != 0
+}
+
+/// Whether a GPIOTE channel other than `ch` claimed the pin `psel`.
+fn claimed_elsewhere(ch: usize, psel: u32) -> bool {
+    CLAIMED
+        .iter()
+        .enumerate()
+        .any(|(other, claim)| other != ch && claim.load(Ordering::SeqCst) == psel)
+}
+
+/// A GPIOTE channel handed out by `GpioteTrigger::take_channel`. Only the
+/// registers of this channel are touched.
+pub struct GpioteChannel {
+    ch: usize,
+}
+
+impl GpioteChannel {
+    /// Generate an event on every edge of the pin `psel`. Refused if another
+    /// channel uses the pin.
+    pub fn listen(&mut self, psel: u32) -> Result<(), ()> {
+        self.claim(psel)?;
+        gpiote().config[self.ch].write(|w| {
+            let w = unsafe { w.mode().event().psel().bits((psel & 0x1F) as u8) };
+            w.port().bit(psel & 0x20 != 0).polarity().toggle()
+        });
+        self.clear_event();
+        Ok(())
+    }
+
+    /// Drive the pin `psel` through the SET and CLR tasks, starting at
+    /// `level`. Refused if another channel uses the pin.
+    pub fn drive(&mut self, psel: u32, level: bool) -> Result<(), ()> {
+        self.claim(psel)?;
+        gpiote().config[self.ch].write(|w| {
+            let w = unsafe { w.mode().task().psel().bits((psel & 0x1F) as u8) };
+            w.port()
+                .bit(psel & 0x20 != 0)
+                .polarity()
+                .none()
+                .outinit()
+                .bit(level)
+        });
+        Ok(())
+    }
+
+    /// Whether another channel uses the pin `psel`.
+    pub fn is_claimed_elsewhere(&self, psel: u32) -> bool {
+        claimed_elsewhere(self.ch, psel)
+    }
+
+    /// Stop using the pin.
+    pub fn disable(&mut self) {
+        self.disable_interrupt();
+        gpiote().config[self.ch].write(|w| w.mode().disabled());
+        self.clear_event();
+        CLAIMED[self.ch].store(UNCLAIMED, Ordering::SeqCst);
+    }
+
+    pub fn event_in(&self) -> &'static EVENTS_IN {
+        &gpiote().events_in[self.ch]
+    }
+
+    pub fn task_set(&self) -> &'static TASKS_SET {
+        &gpiote().tasks_set[self.ch]
+    }
+
+    pub fn task_clr(&self) -> &'static TASKS_CLR {
+        &gpiote().tasks_clr[self.ch]
+    }
+
+    /// Returns whether the event fired, and clears it.
+    pub fn take_event(&self) -> bool {
+        let fired = self.event_in().read().bits() != 0;
+        if fired {
+            self.clear_event();
+        }
+        fired
+    }
+
+    pub fn clear_event(&self) {
+        self.event_in().reset();
+    }
+
+    pub fn enable_interrupt(&self) {
+        gpiote().intenset.write(|w| unsafe { w.bits(1 << self.ch) });
+    }
+
+    pub fn disable_interrupt(&self) {
+        gpiote().intenclr.write(|w| unsafe { w.bits(1 << self.ch) });
+    }
+
+    fn claim(&mut self, psel: u32) -> Result<(), ()> {
+        if claimed_elsewhere(self.ch, psel) {
+            return Err(());
+        }
+        CLAIMED[self.ch].store(psel, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// `GpioteTrigger` owns the GPIOTE. Each `GpioteChannel` only touches the
+/// registers of its own channel, and the shared INTENSET and INTENCLR are
+/// write one to change.
+fn gpiote() -> &'static nrf52840_hal::pac::gpiote::RegisterBlock {
+    unsafe { &*GPIOTE::ptr() }
+}
@@ -39,7 +39,10 @@
 //!
 
 use groundhog::RollingTimer;
-use nrf52840_hal::{pac::timer0::RegisterBlock as RegBlock0, timer::Instance};
+use nrf52840_hal::{
+    pac::timer0::{RegisterBlock as RegBlock0, TASKS_CAPTURE},
+    timer::Instance,
+};
 use rtic::{Fraction, Monotonic};
 use embedded_hal::blocking::delay::{DelayUs, DelayMs};
 
@@ -74,6 +77,23 @@ impl GlobalRollingTimer {
 
         debug_assert!(old_ptr == core::ptr::null_mut());
     }
+
+    /// The capture task of compare register `cc`, e.g. to latch the current
+    /// tick from PPI. CC0 and CC1 are used by the timer itself.
+    pub fn task_capture(cc: usize) -> Option<&'static TASKS_CAPTURE> {
+        assert!(cc >= 2, "CC0 and CC1 are reserved");
+        let t0 = unsafe { TIMER_PTR.load(Ordering::SeqCst).as_ref() }?;
+        t0.tasks_capture.get(cc)
+    }
+
+    /// The tick last latched into compare register `cc`.
+    pub fn captured_ticks(cc: usize) -> u32 {
+        if let Some(t0) = unsafe { TIMER_PTR.load(Ordering::SeqCst).as_ref() } {
+            t0.cc[cc].read().bits()
+        } else {
+            0
+        }
+    }
 }
 
 impl Monotonic for GlobalRollingTimer {
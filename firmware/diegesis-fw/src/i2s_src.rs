@@ -0,0 +1,324 @@
+//! A fifth digital channel, sampled as the SDIN line of the I2S.
+//!
+//! The I2S runs as master with 16 bit stereo frames and a ratio of 32, so
+//! SCK runs at MCK and the frames follow each other without gaps: SDIN is
+//! sampled as one continuous bit stream, like the MISO line of a SPIM. SCK
+//! and LRCK are driven on otherwise unused pins, and may be ignored, or
+//! wired up to capture an actual I2S bus.
+//!
+//! Unlike the SPIM, the I2S has no END event. RXPTRUPD fires once the
+//! pointer of a block was latched, which marks both the end of the last
+//! block and the start of the next one. Without a next block, the same
+//! pointer is latched again, so the first words of the last block of a
+//! capture may be overwritten before the peripheral stops.
+
+use core::{fmt::Debug, marker::PhantomData, ops::DerefMut, sync::atomic::AtomicBool};
+
+use crate::{
+    groundhog_nrf52::GlobalRollingTimer,
+    overflow::Overflow,
+    source::{Blocks, DoubleBuffer, Source, State},
+    sync_start, InternalReport, InternalReportKind, PBox,
+};
+use diegesis_icd::DigitalRate;
+use nrf52840_hal::{
+    gpio::{Floating, Input, Level, Output, Pin, PushPull},
+    pac::{i2s::config::mckfreq::MCKFREQ_A, I2S},
+    ppi::Ppi6,
+};
+
+use groundhog::RollingTimer;
+use heapless::{
+    mpmc::MpMcQueue,
+    pool::{
+        singleton::{Box, Pool},
+        Uninit,
+    },
+};
+
+/// The PPI channel owned through `Ppi6`, starting the I2S with the others.
+const PPI_CH: usize = 6;
+
+/// MAXCNT counts 32 bit words, one stereo frame each.
+const BLOCK_WORDS: u32 = 4096 / 4;
+
+/// MCK, and so SCK, for the supported sample rates.
+fn mckfreq(rate: DigitalRate) -> Option<MCKFREQ_A> {
+    match rate {
+        DigitalRate::M1 => Some(MCKFREQ_A::_32MDIV32),
+        DigitalRate::M2 => Some(MCKFREQ_A::_32MDIV16),
+        DigitalRate::M4 => Some(MCKFREQ_A::_32MDIV8),
+        _ => None,
+    }
+}
+
+/// Ticks after which a block is considered late, at the given rate.
+///
+/// There are no gaps between the frames, the 1% only covers the interrupt
+/// latency.
+fn expected_ticks(rate: DigitalRate) -> u32 {
+    let nominal = ((4_000_000u64 * 8 * 4096) / u64::from(rate.hz())) as u32;
+    nominal + (nominal / 100)
+}
+
+/// A block being filled by the I2S.
+pub struct Transfer<POOL>
+where
+    POOL: Pool,
+{
+    i2s: I2S,
+    block: PBox<POOL>,
+    /// Whether the pointer of this block was latched yet.
+    started: bool,
+}
+
+/// One digital channel, sampled as the SDIN line of the I2S.
+pub struct I2sChannel<POOL, OtherPool> {
+    channel: u8,
+    expected_ticks: u32,
+    _pools: PhantomData<(POOL, OtherPool)>,
+}
+
+impl<POOL, OtherPool> I2sChannel<POOL, OtherPool>
+where
+    POOL: Pool,
+    PBox<POOL>: DerefMut<Target = [u8; 4096]>,
+{
+    fn set_ptr(i2s: &I2S, block: &PBox<POOL>) {
+        let ptr = block.as_ptr() as u32;
+        debug_assert_eq!(ptr % 4, 0, "I2S blocks must be word aligned");
+        i2s.rxd.ptr.write(|w| unsafe { w.ptr().bits(ptr) });
+    }
+}
+
+impl<POOL, OtherPool> Source for I2sChannel<POOL, OtherPool>
+where
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<OtherPool>: Debug,
+{
+    type Uninit = Box<POOL, Uninit>;
+    type Block = PBox<POOL>;
+    type Report = InternalReport<POOL, OtherPool>;
+    type Idle = I2S;
+    type One = Transfer<POOL>;
+    type Two = (Transfer<POOL>, PBox<POOL>);
+
+    const NAME: &'static str = "I2S";
+
+    fn block(&mut self, pbox: Box<POOL, Uninit>) -> PBox<POOL> {
+        pbox.freeze()
+    }
+
+    fn report(&mut self, timestamp: u32, seq: u32, mut block: PBox<POOL>) -> Self::Report {
+        // Samples are received MSB first, but stored as little endian
+        // halfwords. Swap them, so the oldest sample is the MSB of the
+        // first byte, like on the SPIM channels.
+        for halfword in block.chunks_exact_mut(2) {
+            halfword.swap(0, 1);
+        }
+
+        InternalReport {
+            timestamp,
+            seq,
+            kind: InternalReportKind::DigitalReport {
+                channel: self.channel,
+                payload: block,
+            },
+        }
+    }
+
+    fn lost_index(&self) -> usize {
+        self.channel as usize
+    }
+
+    fn expected_ticks(&self) -> Option<u32> {
+        Some(self.expected_ticks)
+    }
+
+    fn arm(&mut self, i2s: I2S, block: PBox<POOL>) -> Self::One {
+        // Only arm the transfer, the synchronized start begins it
+        Self::set_ptr(&i2s, &block);
+        i2s.enable.write(|w| w.enable().enabled());
+        Transfer {
+            i2s,
+            block,
+            started: false,
+        }
+    }
+
+    fn restart(&mut self, i2s: I2S, block: PBox<POOL>) -> Self::One {
+        let one = self.arm(i2s, block);
+        one.i2s.tasks_start.write(|w| unsafe { w.bits(1) });
+        one
+    }
+
+    fn is_done(&mut self, one: &mut Self::One) -> bool {
+        if one.i2s.events_rxptrupd.read().bits() == 0 {
+            return false;
+        }
+        one.i2s.events_rxptrupd.reset();
+
+        // The first update latched this block, a second one latched it again
+        let done = one.started;
+        one.started = true;
+        done
+    }
+
+    fn clear_started(&mut self, one: &mut Self::One) {
+        if !one.started && one.i2s.events_rxptrupd.read().bits() != 0 {
+            one.i2s.events_rxptrupd.reset();
+            one.started = true;
+        }
+    }
+
+    fn wait(&mut self, one: Self::One) -> (I2S, PBox<POOL>) {
+        let Transfer { i2s, block, .. } = one;
+
+        i2s.tasks_stop.write(|w| unsafe { w.bits(1) });
+        while i2s.events_stopped.read().bits() == 0 {}
+        i2s.events_stopped.reset();
+        i2s.events_rxptrupd.reset();
+
+        // Tasks are ignored while disabled, so a later synchronized start
+        // can't restart it with a stale pointer
+        i2s.enable.write(|w| w.enable().disabled());
+
+        (i2s, block)
+    }
+
+    fn enqueue(&mut self, one: Self::One, block: PBox<POOL>) -> Self::Two {
+        // Latched once the current block is full
+        Self::set_ptr(&one.i2s, &block);
+        (one, block)
+    }
+
+    fn exchange(&mut self, two: Self::Two) -> (Self::One, PBox<POOL>) {
+        let (current, next) = two;
+        assert!(current.i2s.events_rxptrupd.read().bits() != 0);
+        current.i2s.events_rxptrupd.reset();
+
+        let one = Transfer {
+            i2s: current.i2s,
+            block: next,
+            started: true,
+        };
+        (one, current.block)
+    }
+}
+
+pub struct I2sSrc<POOL, OtherPool, const N: usize>
+where
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<OtherPool>: Debug,
+{
+    core: DoubleBuffer<I2sChannel<POOL, OtherPool>, POOL, POOL, OtherPool, N>,
+    timer: GlobalRollingTimer,
+    _ppi: Ppi6,
+    _pins: (
+        Pin<Input<Floating>>,
+        Pin<Output<PushPull>>,
+        Pin<Output<PushPull>>,
+    ),
+}
+
+impl<POOL, OtherPool, const N: usize> I2sSrc<POOL, OtherPool, N>
+where
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<OtherPool>: Debug,
+{
+    pub fn from_parts<SDIN, SCK, LRCK>(
+        i2s: I2S,
+        sdin_pin: Pin<SDIN>,
+        sck_pin: Pin<SCK>,
+        lrck_pin: Pin<LRCK>,
+        ppi: Ppi6,
+        pool_q: &'static MpMcQueue<InternalReport<POOL, OtherPool>, N>,
+        rate: DigitalRate,
+        timer: GlobalRollingTimer,
+        channel: u8,
+        overflow: &'static Overflow,
+    ) -> Self {
+        let mckfreq = mckfreq(rate).expect("Unsupported I2S rate");
+
+        let sdin = sdin_pin.into_floating_input();
+        let sck = sck_pin.into_push_pull_output(Level::Low);
+        let lrck = lrck_pin.into_push_pull_output(Level::Low);
+
+        i2s.psel.sdin.write(|w| unsafe { w.bits(sdin.psel_bits()) });
+        i2s.psel.sck.write(|w| unsafe { w.bits(sck.psel_bits()) });
+        i2s.psel.lrck.write(|w| unsafe { w.bits(lrck.psel_bits()) });
+
+        i2s.config.mode.write(|w| w.mode().master());
+        i2s.config.rxen.write(|w| w.rxen().enabled());
+        i2s.config.txen.write(|w| w.txen().disabled());
+        i2s.config.mcken.write(|w| w.mcken().enabled());
+        i2s.config.mckfreq.write(|w| w.mckfreq().variant(mckfreq));
+        i2s.config.ratio.write(|w| w.ratio()._32x());
+        i2s.config.swidth.write(|w| w.swidth()._16bit());
+        i2s.config.align.write(|w| w.align().left());
+        i2s.config.format.write(|w| w.format().aligned());
+        i2s.config.channels.write(|w| w.channels().stereo());
+        i2s.rxtxd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(BLOCK_WORDS) });
+
+        i2s.intenset.write(|w| w.rxptrupd().set_bit());
+
+        // SAFETY: We own `Ppi6`
+        unsafe { sync_start::connect_start(PPI_CH, &i2s.tasks_start as *const _ as u32) };
+
+        let source = I2sChannel {
+            channel,
+            expected_ticks: expected_ticks(rate),
+            _pools: PhantomData,
+        };
+
+        Self {
+            core: DoubleBuffer::new(
+                source,
+                i2s,
+                Blocks::new(pool_q, overflow),
+                pool_q,
+                timer.clone(),
+            ),
+            timer,
+            _ppi: ppi,
+            _pins: (sdin, sck, lrck),
+        }
+    }
+
+    /// Change the sample rate. Only possible while no capture is running,
+    /// and only for the rates MCK can be divided down to.
+    pub fn set_rate(&mut self, rate: DigitalRate) -> Result<(), ()> {
+        let mckfreq = mckfreq(rate).ok_or(())?;
+
+        match self.core.parts() {
+            (source, State::Idle(i2s)) => {
+                i2s.config.mckfreq.write(|w| w.mckfreq().variant(mckfreq));
+                source.expected_ticks = expected_ticks(rate);
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    pub fn poll(&mut self, fuse: &AtomicBool) {
+        // Take the timestamp as early as possible, see `DoubleBuffer::poll`
+        let now = self.timer.get_ticks();
+
+        let exchanged = matches!(self.core.state(), State::TwoPending(_));
+        self.core.poll(fuse, now);
+
+        // The update that finished a block also started the next one, and
+        // there won't be another event to queue the block after it on
+        if exchanged {
+            self.core.poll(fuse, now);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #![no_std]
 
 use core::{
+    any::TypeId,
     fmt::Debug,
     ops::DerefMut,
 };
@@ -10,9 +11,13 @@ use nrf52840_hal::{
     target_constants::SRAM_UPPER,
 };
 
-use bbqueue::{consts as bbconsts, GrantW};
+use bbqueue::{consts as bbconsts, Consumer, GrantW, Producer};
+use generic_array::typenum::Unsigned;
 use defmt_rtt as _; // global logger
-use diegesis_icd::{DataReport, Managed, ReportKind};
+use diegesis_icd::{
+    delta, rle, AnalogEncoding, AnalogSettings, DataReport, DigitalEncoding, Managed, Measurement, ReportKind,
+    TriggerSource, UartErrors,
+};
 use embedded_dma::ReadBuffer;
 use heapless::pool::singleton::Pool;
 use kolben::rlercobs;
@@ -24,12 +29,27 @@ pub use pinmap::Nrf52Dk as Board;
 #[cfg(feature = "board-playground")]
 pub use pinmap::AdafruitPlaygroundBluefruit as Board;
 
+pub mod activity;
+pub mod calibration;
+pub mod command;
+pub mod comparator;
+pub mod gpiote_trigger;
 pub mod groundhog_nrf52;
+pub mod i2s_src;
+pub mod marker;
+pub mod measure;
+pub mod overflow;
+pub mod pdm_src;
 pub mod patterns;
-mod saadc;
+pub mod pretrigger;
+pub mod saadc;
 pub mod saadc_src;
+pub mod source;
 pub mod spim_src;
 pub mod pinmap;
+pub mod sync_start;
+pub mod trigger;
+pub mod uart_src;
 
 #[macro_use]
 pub mod profile_ct;
@@ -58,6 +78,12 @@ pub fn exit() -> ! {
 
 pub type PBox<T> = heapless::pool::singleton::Box<T>;
 
+/// Digital channels: the four SPIMs, followed by the I2S.
+pub const DIGITAL_CHANNELS: usize = 5;
+
+/// UART sniffers, on the two UARTEs.
+pub const UART_PORTS: usize = 2;
+
 pub struct NopSlice;
 
 unsafe impl ReadBuffer for NopSlice {
@@ -69,27 +95,132 @@ unsafe impl ReadBuffer for NopSlice {
     }
 }
 
-#[derive(Debug)]
-pub struct FillBuf {
-    pub buf: GrantW<'static, bbconsts::U32768>,
-    pub used: usize,
+/// Largest encoded report, a full block after postcard and rlercobs.
+pub const MAX_ENCODED_LEN: usize = 4096 + 1024;
+
+/// Bytes that can still be written to the encoded queue, including the
+/// space left after wrapping around the end of the ring.
+pub fn free_space(cons: &mut Consumer<'static, bbconsts::U32768>) -> usize {
+    let readable = match cons.split_read() {
+        Ok(rgr) => {
+            let (first, second) = rgr.bufs();
+            let len = first.len() + second.len();
+            rgr.release(0);
+            len
+        }
+        Err(_) => 0,
+    };
+
+    // One byte always stays empty, to tell a full ring from an empty one
+    bbconsts::U32768::to_usize()
+        .saturating_sub(readable)
+        .saturating_sub(1)
 }
 
-impl FillBuf {
-    pub fn content_len(&self) -> usize {
-        self.used
+/// Encodes straight into the encoded queue. When a grant fills up, it is
+/// committed and encoding continues in the next one, wrapping around the
+/// end of the ring, so no space is wasted there.
+///
+/// Check `free_space` before encoding a report, a report that doesn't fit
+/// is cut short.
+pub struct StreamWriter<'a> {
+    prod: &'a mut Producer<'static, bbconsts::U32768>,
+    grant: Option<GrantW<'static, bbconsts::U32768>>,
+    used: usize,
+    committed: usize,
+    splits: usize,
+}
+
+impl<'a> StreamWriter<'a> {
+    pub fn new(prod: &'a mut Producer<'static, bbconsts::U32768>) -> Self {
+        Self {
+            prod,
+            grant: None,
+            used: 0,
+            committed: 0,
+            splits: 0,
+        }
+    }
+
+    /// Commit the bytes written so far. Returns the total length, and
+    /// whether the report was split across the end of the ring.
+    pub fn finish(mut self) -> (usize, bool) {
+        self.commit();
+        (self.committed, self.splits > 1)
+    }
+
+    fn commit(&mut self) {
+        if let Some(grant) = self.grant.take() {
+            grant.commit(self.used);
+            self.committed += self.used;
+            self.used = 0;
+        }
+    }
+
+    #[cold]
+    fn next_grant(&mut self) -> Result<(), ()> {
+        self.commit();
+        let grant = self.prod.grant_max_remaining(MAX_ENCODED_LEN).map_err(drop)?;
+        self.grant = Some(grant);
+        self.splits += 1;
+        Ok(())
     }
 }
 
-impl rlercobs::Write for FillBuf {
+impl<'a> rlercobs::Write for StreamWriter<'a> {
     type Error = ();
 
     #[inline(always)]
     fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
-        let buf_byte = self.buf.get_mut(self.used).ok_or(())?;
-        *buf_byte = byte;
-        self.used += 1;
-        Ok(())
+        loop {
+            if let Some(buf_byte) = self.grant.as_mut().and_then(|g| g.get_mut(self.used)) {
+                *buf_byte = byte;
+                self.used += 1;
+                return Ok(());
+            }
+            self.next_grant()?;
+        }
+    }
+}
+
+/// Run-length encode the payload of a raw digital report into `buf`, if
+/// that makes it smaller. Returns whether it did.
+pub fn compress_digital<'a>(report: &mut DataReport<'a>, buf: &'a mut [u8; 4095]) -> bool {
+    let encoding = match report.kind {
+        ReportKind::DigitalPin {
+            ref mut encoding, ..
+        } if *encoding == DigitalEncoding::Raw => encoding,
+        _ => return false,
+    };
+
+    match rle::encode(&report.payload, buf) {
+        Some(used) => {
+            *encoding = DigitalEncoding::RunLength;
+            report.payload = Managed::Borrowed(&mut buf[..used]);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Delta encode the payload of a raw analog report into `buf`, if that
+/// makes it smaller. Returns whether it did.
+pub fn compress_analog<'a>(report: &mut DataReport<'a>, buf: &'a mut [u8; 4095]) -> bool {
+    let (channels, encoding) = match report.kind {
+        ReportKind::AnalogPin {
+            channel_bitflag,
+            ref mut encoding,
+        } if *encoding == AnalogEncoding::Raw => (channel_bitflag.count_ones() as usize, encoding),
+        _ => return false,
+    };
+
+    match delta::encode(&report.payload, channels, buf) {
+        Some(used) => {
+            *encoding = AnalogEncoding::Delta;
+            report.payload = Managed::Borrowed(&mut buf[..used]);
+            true
+        }
+        None => false,
     }
 }
 
@@ -102,6 +233,7 @@ where
     PBox<PoolB>: Debug,
 {
     timestamp: u32,
+    seq: u32,
     kind: InternalReportKind<PoolA, PoolB>,
 }
 
@@ -121,6 +253,81 @@ where
         channel_bitflag: u8,
         payload: PBox<PoolB>,
     },
+    AudioReport {
+        sample_rate: u32,
+        payload: PBox<PoolB>,
+    },
+    /// Only the first `len` bytes were received.
+    UartReport {
+        port: u8,
+        errors: UartErrors,
+        len: u16,
+        payload: PBox<PoolA>,
+    },
+    CaptureStart,
+    AnalogSettings {
+        channel_bitflag: u8,
+        settings: AnalogSettings,
+    },
+    AnalogCalibrated {
+        channel_bitflag: u8,
+        temperature: i32,
+    },
+    Trigger {
+        source: TriggerSource,
+    },
+    CaptureRestart {
+        attempt: u8,
+    },
+    Idle {
+        channel: u8,
+        level: bool,
+        blocks: u32,
+    },
+    Measurement {
+        channel: u8,
+        measurement: Measurement,
+    },
+}
+
+impl<DigitalPool, AnalogPool> InternalReport<DigitalPool, AnalogPool>
+where
+    DigitalPool: Pool,
+    AnalogPool: Pool,
+    PBox<DigitalPool>: Debug,
+    PBox<AnalogPool>: Debug,
+{
+    /// The source index of sample data, see `overflow::SOURCES`.
+    pub fn source(&self) -> Option<usize> {
+        match self.kind {
+            InternalReportKind::DigitalReport { channel, .. } => Some(channel as usize),
+            InternalReportKind::AnalogReport { .. } => Some(overflow::ANALOG_SOURCE),
+            InternalReportKind::AudioReport { .. } => Some(overflow::AUDIO_SOURCE),
+            InternalReportKind::UartReport { port, .. } => {
+                Some(overflow::UART_SOURCE + port as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the payload is a box of `P`.
+    pub fn is_from<P>(&self) -> bool
+    where
+        P: 'static,
+        DigitalPool: 'static,
+        AnalogPool: 'static,
+    {
+        let pool = match self.kind {
+            InternalReportKind::DigitalReport { .. } | InternalReportKind::UartReport { .. } => {
+                TypeId::of::<DigitalPool>()
+            }
+            InternalReportKind::AnalogReport { .. } | InternalReportKind::AudioReport { .. } => {
+                TypeId::of::<AnalogPool>()
+            }
+            _ => return false,
+        };
+        pool == TypeId::of::<P>()
+    }
 }
 
 impl<DigitalPool, AnalogPool> InternalReport<DigitalPool, AnalogPool>
@@ -130,6 +337,45 @@ where
     PBox<DigitalPool>: Debug + DerefMut<Target = [u8; 4096]>,
     PBox<AnalogPool>: Debug + DerefMut<Target = [i16; 2048]>,
 {
+    /// Marks the common start tick of all capture sources.
+    pub fn capture_start(tick: u32) -> Self {
+        Self {
+            timestamp: tick,
+            seq: 0,
+            kind: InternalReportKind::CaptureStart,
+        }
+    }
+
+    /// Marks the tick a trigger fired at.
+    pub fn trigger(tick: u32, source: TriggerSource) -> Self {
+        Self {
+            timestamp: tick,
+            seq: 0,
+            kind: InternalReportKind::Trigger { source },
+        }
+    }
+
+    /// Marks an automatic restart of the capture.
+    pub fn restart(tick: u32, attempt: u8) -> Self {
+        Self {
+            timestamp: tick,
+            seq: 0,
+            kind: InternalReportKind::CaptureRestart { attempt },
+        }
+    }
+
+    /// A measurement of a digital channel, over the gate ending at `tick`.
+    pub fn measurement(tick: u32, channel: u8, measurement: Measurement) -> Self {
+        Self {
+            timestamp: tick,
+            seq: 0,
+            kind: InternalReportKind::Measurement {
+                channel,
+                measurement,
+            },
+        }
+    }
+
     pub fn as_data_report(&mut self) -> DataReport {
         match self.kind {
             InternalReportKind::DigitalReport {
@@ -137,8 +383,12 @@ where
                 ref mut payload,
             } => DataReport {
                 timestamp: self.timestamp,
-                kind: ReportKind::DigitalPin { channel },
-                payload: Managed::Borrowed(payload.deref_mut()),
+                seq: self.seq,
+                kind: ReportKind::DigitalPin {
+                    channel,
+                    encoding: DigitalEncoding::Raw,
+                },
+                payload: Managed::Borrowed(&mut payload.deref_mut()[..]),
             },
             InternalReportKind::AnalogReport {
                 channel_bitflag,
@@ -155,10 +405,111 @@ where
 
                 DataReport {
                     timestamp: self.timestamp,
-                    kind: ReportKind::AnalogPin { channel_bitflag },
-                    payload: Managed::Borrowed(casted_slice),
+                    seq: self.seq,
+                    kind: ReportKind::AnalogPin {
+                        channel_bitflag,
+                        encoding: AnalogEncoding::Raw,
+                    },
+                    payload: Managed::Borrowed(&mut casted_slice[..]),
                 }
             }
+            InternalReportKind::AudioReport {
+                sample_rate,
+                ref mut payload,
+            } => {
+                // SAFETY: See `AnalogReport`, the samples are little endian
+                // `i16`s just the same
+                let casted_slice: &mut [u8; 4096] = unsafe {
+                    let i16_slice: &mut [i16; 2048] = payload.deref_mut();
+                    core::mem::transmute(i16_slice)
+                };
+
+                DataReport {
+                    timestamp: self.timestamp,
+                    seq: self.seq,
+                    kind: ReportKind::Audio { sample_rate },
+                    payload: Managed::Borrowed(&mut casted_slice[..]),
+                }
+            }
+            InternalReportKind::UartReport {
+                port,
+                errors,
+                len,
+                ref mut payload,
+            } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::UartBytes { port, errors },
+                payload: Managed::Borrowed(&mut payload.deref_mut()[..len as usize]),
+            },
+            InternalReportKind::CaptureStart => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::CaptureStart,
+                payload: Managed::Borrowed(&mut []),
+            },
+            InternalReportKind::AnalogSettings {
+                channel_bitflag,
+                settings,
+            } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::AnalogSettings {
+                    channel_bitflag,
+                    settings,
+                },
+                payload: Managed::Borrowed(&mut []),
+            },
+            InternalReportKind::AnalogCalibrated {
+                channel_bitflag,
+                temperature,
+            } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::AnalogCalibrated {
+                    channel_bitflag,
+                    temperature,
+                },
+                payload: Managed::Borrowed(&mut []),
+            },
+            InternalReportKind::Trigger { source } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::Trigger { source },
+                payload: Managed::Borrowed(&mut []),
+            },
+            InternalReportKind::CaptureRestart { attempt } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::CaptureRestart { attempt },
+                payload: Managed::Borrowed(&mut []),
+            },
+            InternalReportKind::Idle {
+                channel,
+                level,
+                blocks,
+            } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::Idle {
+                    channel,
+                    level,
+                    blocks,
+                },
+                payload: Managed::Borrowed(&mut []),
+            },
+            InternalReportKind::Measurement {
+                channel,
+                measurement,
+            } => DataReport {
+                timestamp: self.timestamp,
+                seq: self.seq,
+                kind: ReportKind::Measurement {
+                    channel,
+                    measurement,
+                },
+                payload: Managed::Borrowed(&mut []),
+            },
         }
     }
 }
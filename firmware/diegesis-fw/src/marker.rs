@@ -0,0 +1,48 @@
+//! Queue of the reports marking events in the stream, like the start of a
+//! capture or a trigger firing.
+//!
+//! Markers are kept apart from the sample blocks, so a queue full of blocks
+//! can't crowd them out, and dropping blocks never touches them. They are
+//! sent ahead of any queued blocks, the host places them by their tick.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use heapless::mpmc::MpMcQueue;
+
+/// Markers come in a few at a time, and are sent before any blocks.
+pub const MARKER_QUEUE_LEN: usize = 8;
+
+pub struct MarkerQueue<R> {
+    queue: MpMcQueue<R, MARKER_QUEUE_LEN>,
+    lost: AtomicU32,
+}
+
+impl<R> MarkerQueue<R> {
+    pub const fn new() -> Self {
+        Self {
+            queue: MpMcQueue::new(),
+            lost: AtomicU32::new(0),
+        }
+    }
+
+    /// Queue `marker`, or count it as lost if the queue is full.
+    pub fn send(&self, marker: R) {
+        if self.queue.enqueue(marker).is_err() {
+            self.lost.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn dequeue(&self) -> Option<R> {
+        self.queue.dequeue()
+    }
+
+    /// Forget the losses of the last capture.
+    pub fn reset(&self) {
+        self.lost.store(0, Ordering::SeqCst);
+    }
+
+    /// Markers lost since the capture started.
+    pub fn lost(&self) -> u32 {
+        self.lost.load(Ordering::SeqCst)
+    }
+}
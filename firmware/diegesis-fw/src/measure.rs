@@ -0,0 +1,297 @@
+//! Frequency and pulse width measurement on the pin of a digital channel.
+//!
+//! A GPIOTE channel turns every edge on the pin into an event, which PPI
+//! routes to TIMER2 in counter mode, and to a capture of TIMER3, which runs
+//! freely at 16 MHz. A compare of TIMER3 ends each gate, latching the edge
+//! count and the global tick through PPI, so the gates are exact however
+//! late the idle loop picks them up.
+//!
+//! Pulse widths need the level of the line, which PPI doesn't know about.
+//! At the start of each gate, the edge interrupt is enabled until a high and
+//! a low pulse were timed, or `MAX_TIMED_EDGES` went by. It stays off for
+//! the whole gate if the last one counted too many edges. The edges
+//! themselves are still timed by TIMER3, the interrupt only reads the
+//! level, and checks that no other edge came in between.
+//!
+//! The pin stays connected to its SPIM, GPIOTE only listens in. GPIOTE
+//! can't listen to a pin twice, so a measurement and a digital trigger on
+//! the same channel refuse each other.
+
+use crate::{
+    gpiote_trigger::{pin_level, GpioteChannel},
+    groundhog_nrf52::GlobalRollingTimer,
+};
+use diegesis_icd::{Measurement, MeasurementConfig};
+use nrf52840_hal::{
+    pac::{PPI, TIMER2, TIMER3},
+    ppi::{Ppi10, Ppi11},
+};
+
+/// The PPI channels owned through `Ppi10` and `Ppi11`.
+const EDGE_PPI_CH: usize = 10;
+const GATE_PPI_CH: usize = 11;
+
+/// Compare register of the global timer holding the tick a gate ended at.
+pub const GATE_TICK_CC: usize = 3;
+
+/// TIMER2 compare registers, the edge count latched at the end of a gate,
+/// and by the interrupt.
+const GATE_EDGES_CC: usize = 0;
+const EDGES_CC: usize = 1;
+
+/// TIMER3 compare registers, the time of the last edge, the end of the
+/// gate, and the current time.
+const EDGE_TIME_CC: usize = 0;
+const GATE_END_CC: usize = 2;
+const NOW_CC: usize = 3;
+
+/// An edge seen by the interrupt.
+#[derive(Clone, Copy)]
+struct Edge {
+    count: u32,
+    ticks: u32,
+    /// The level the edge went to.
+    level: bool,
+}
+
+pub struct Measure {
+    counter: TIMER2,
+    timer: TIMER3,
+    _ppis: (Ppi10, Ppi11),
+    /// Turns the edges on the pin into events.
+    gpiote: GpioteChannel,
+    /// PSEL bits of the data pin of each digital channel.
+    pins: [u32; 4],
+    config: Option<MeasurementConfig>,
+    gate_ticks: u32,
+    gate_start: u32,
+    gate_edges: u32,
+    last_edge: Option<Edge>,
+    /// Interrupts taken during the gate.
+    timed_edges: u32,
+    high_ticks: Option<u32>,
+    low_ticks: Option<u32>,
+}
+
+impl Measure {
+    pub fn new(
+        counter: TIMER2,
+        timer: TIMER3,
+        ppis: (Ppi10, Ppi11),
+        gpiote: GpioteChannel,
+        pins: [u32; 4],
+    ) -> Self {
+        counter.mode.write(|w| w.mode().counter());
+        counter.bitmode.write(|w| w.bitmode()._32bit());
+        timer.mode.write(|w| w.mode().timer());
+        timer.bitmode.write(|w| w.bitmode()._32bit());
+        timer.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
+
+        let gate_tick = GlobalRollingTimer::task_capture(GATE_TICK_CC)
+            .expect("Global timer must be initialized first");
+
+        // SAFETY: We own `Ppi10` and `Ppi11`
+        unsafe {
+            connect(
+                EDGE_PPI_CH,
+                gpiote.event_in() as *const _ as u32,
+                &counter.tasks_count as *const _ as u32,
+                &timer.tasks_capture[EDGE_TIME_CC] as *const _ as u32,
+            );
+            connect(
+                GATE_PPI_CH,
+                &timer.events_compare[GATE_END_CC] as *const _ as u32,
+                &counter.tasks_capture[GATE_EDGES_CC] as *const _ as u32,
+                gate_tick as *const _ as u32,
+            );
+        }
+
+        Self {
+            counter,
+            timer,
+            _ppis: ppis,
+            gpiote,
+            pins,
+            config: None,
+            gate_ticks: 0,
+            gate_start: 0,
+            gate_edges: 0,
+            last_edge: None,
+            timed_edges: 0,
+            high_ticks: None,
+            low_ticks: None,
+        }
+    }
+
+    /// Start measuring, or stop with `None`. Only applied while stopped.
+    /// Refused while a digital trigger uses the pin.
+    pub fn set_config(&mut self, config: Option<MeasurementConfig>) -> Result<(), ()> {
+        if let Some(cfg) = config {
+            if !cfg.is_valid(self.pins.len() as u8) {
+                return Err(());
+            }
+            let pin = self.pins[cfg.channel as usize];
+            if self.gpiote.is_claimed_elsewhere(pin) {
+                return Err(());
+            }
+        }
+        self.stop();
+
+        let cfg = match config {
+            Some(cfg) => cfg,
+            None => return Ok(()),
+        };
+        self.gpiote.listen(self.pins[cfg.channel as usize])?;
+
+        self.counter.tasks_clear.write(|w| unsafe { w.bits(1) });
+        self.counter.tasks_start.write(|w| unsafe { w.bits(1) });
+        self.timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+        self.timer.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        self.gate_ticks = u32::from(cfg.gate_ms) * (Measurement::TICKS_PER_SECOND / 1000);
+        self.gate_start = 0;
+        self.gate_edges = 0;
+        self.timer.events_compare[GATE_END_CC].reset();
+        self.timer.cc[GATE_END_CC].write(|w| unsafe { w.bits(self.gate_ticks) });
+
+        // SAFETY: We own both channels
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.chenset
+            .write(|w| unsafe { w.bits((1 << EDGE_PPI_CH) | (1 << GATE_PPI_CH)) });
+
+        self.config = config;
+        self.time_pulses(true);
+        Ok(())
+    }
+
+    /// The measurement of the last gate, once it ended, along with the tick
+    /// it ended at and the channel measured.
+    pub fn poll_gate(&mut self) -> Option<(u32, u8, Measurement)> {
+        let cfg = self.config?;
+        if self.timer.events_compare[GATE_END_CC].read().bits() == 0 {
+            return None;
+        }
+        self.timer.events_compare[GATE_END_CC].reset();
+
+        let end = self.timer.cc[GATE_END_CC].read().bits();
+        let edges = self.counter.cc[GATE_EDGES_CC].read().bits();
+        let tick = GlobalRollingTimer::captured_ticks(GATE_TICK_CC);
+
+        let measurement = Measurement {
+            gate_ticks: end.wrapping_sub(self.gate_start),
+            edges: edges.wrapping_sub(self.gate_edges),
+            high_ticks: self.high_ticks,
+            low_ticks: self.low_ticks,
+        };
+        self.gate_start = end;
+        self.gate_edges = edges;
+
+        // Picked up more than a gate late, the next end would already have
+        // passed, and only come around again once the timer wrapped. Stretch
+        // the next gate instead.
+        let mut next = end.wrapping_add(self.gate_ticks);
+        self.timer.tasks_capture[NOW_CC].write(|w| unsafe { w.bits(1) });
+        let now = self.timer.cc[NOW_CC].read().bits();
+        if next.wrapping_sub(now) > self.gate_ticks {
+            next = now.wrapping_add(self.gate_ticks);
+        }
+        self.timer.cc[GATE_END_CC].write(|w| unsafe { w.bits(next) });
+
+        self.time_pulses(cfg.times_pulses(measurement.edges));
+        Some((tick, cfg.channel, measurement))
+    }
+
+    /// To be called from the GPIOTE interrupt, only enabled while timing
+    /// pulses.
+    pub fn poll(&mut self) {
+        if !self.gpiote.take_event() {
+            return;
+        }
+
+        let cfg = match self.config {
+            Some(cfg) => cfg,
+            None => return,
+        };
+
+        self.timed_edges += 1;
+        if self.timed_edges >= MeasurementConfig::MAX_TIMED_EDGES {
+            self.gpiote.disable_interrupt();
+        }
+
+        // The level only belongs to the last edge if none came in while
+        // reading it
+        let count = self.edges();
+        let ticks = self.timer.cc[EDGE_TIME_CC].read().bits();
+        let level = pin_level(self.pins[cfg.channel as usize]);
+        if self.edges() != count {
+            self.last_edge = None;
+            return;
+        }
+
+        if let Some(last) = self.last_edge {
+            if count == last.count.wrapping_add(1) {
+                let width = Some(ticks.wrapping_sub(last.ticks));
+                if last.level {
+                    self.high_ticks = width;
+                } else {
+                    self.low_ticks = width;
+                }
+            }
+        }
+        self.last_edge = Some(Edge {
+            count,
+            ticks,
+            level,
+        });
+
+        if self.high_ticks.is_some() && self.low_ticks.is_some() {
+            self.gpiote.disable_interrupt();
+        }
+    }
+
+    fn edges(&self) -> u32 {
+        self.counter.tasks_capture[EDGES_CC].write(|w| unsafe { w.bits(1) });
+        self.counter.cc[EDGES_CC].read().bits()
+    }
+
+    /// Time the next high and low pulse if `enable`, see `poll`. Otherwise
+    /// the gate is reported without them.
+    fn time_pulses(&mut self, enable: bool) {
+        self.last_edge = None;
+        self.timed_edges = 0;
+        self.high_ticks = None;
+        self.low_ticks = None;
+        self.gpiote.clear_event();
+        if enable {
+            self.gpiote.enable_interrupt();
+        } else {
+            self.gpiote.disable_interrupt();
+        }
+    }
+
+    fn stop(&mut self) {
+        // SAFETY: We own both channels
+        let ppi = unsafe { &*PPI::ptr() };
+        ppi.chenclr
+            .write(|w| unsafe { w.bits((1 << EDGE_PPI_CH) | (1 << GATE_PPI_CH)) });
+
+        self.gpiote.disable();
+
+        self.counter.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.config = None;
+    }
+}
+
+/// Route `event` to `task` and `fork` through PPI channel `ch`. The channel
+/// is only enabled while measuring.
+///
+/// # Safety
+///
+/// The caller must own PPI channel `ch`.
+unsafe fn connect(ch: usize, event: u32, task: u32, fork: u32) {
+    let ppi = &*PPI::ptr();
+    ppi.ch[ch].eep.write(|w| w.bits(event));
+    ppi.ch[ch].tep.write(|w| w.bits(task));
+    ppi.fork[ch].tep.write(|w| w.bits(fork));
+}
@@ -0,0 +1,126 @@
+//! Shared state of the overflow policy.
+//!
+//! Sources consult this when their pool runs dry. With a dropping policy
+//! they keep sampling, and count the blocks lost per source.
+
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use diegesis_icd::OverflowPolicy;
+use heapless::{
+    mpmc::MpMcQueue,
+    pool::{
+        singleton::{Box, Pool},
+        Uninit,
+    },
+};
+
+use crate::{InternalReport, PBox, DIGITAL_CHANNELS, UART_PORTS};
+
+/// The digital channels, followed by the analog source, the microphone and
+/// the UART sniffers.
+pub const SOURCES: usize = DIGITAL_CHANNELS + 2 + UART_PORTS;
+pub const ANALOG_SOURCE: usize = DIGITAL_CHANNELS;
+pub const AUDIO_SOURCE: usize = DIGITAL_CHANNELS + 1;
+/// Index of the first UART sniffer, followed by the others.
+pub const UART_SOURCE: usize = DIGITAL_CHANNELS + 2;
+
+pub struct Overflow {
+    policy: AtomicU8,
+    lost: [AtomicU32; SOURCES],
+}
+
+impl Overflow {
+    pub const fn new() -> Self {
+        const NONE_LOST: AtomicU32 = AtomicU32::new(0);
+        Self {
+            policy: AtomicU8::new(0),
+            lost: [NONE_LOST; SOURCES],
+        }
+    }
+
+    pub fn set_policy(&self, policy: OverflowPolicy) {
+        let raw = match policy {
+            OverflowPolicy::Stop => 0,
+            OverflowPolicy::DropNewest => 1,
+            OverflowPolicy::DropOldest => 2,
+        };
+        self.policy.store(raw, Ordering::SeqCst);
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        match self.policy.load(Ordering::SeqCst) {
+            1 => OverflowPolicy::DropNewest,
+            2 => OverflowPolicy::DropOldest,
+            _ => OverflowPolicy::Stop,
+        }
+    }
+
+    /// Forget the losses of the last capture.
+    pub fn reset(&self) {
+        for lost in self.lost.iter() {
+            lost.store(0, Ordering::SeqCst);
+        }
+    }
+
+    pub fn count_lost(&self, source: usize) {
+        if let Some(lost) = self.lost.get(source) {
+            lost.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Blocks lost per source since the capture started.
+    pub fn lost(&self) -> [u32; SOURCES] {
+        let mut lost = [0; SOURCES];
+        for (count, atomic) in lost.iter_mut().zip(self.lost.iter()) {
+            *count = atomic.load(Ordering::SeqCst);
+        }
+        lost
+    }
+
+    /// Allocate a box from `P`. With `DropOldest`, the oldest queued sample
+    /// blocks of `P` are dropped until one is free, regardless of their
+    /// source. Dropping blocks of the other pool would free nothing here.
+    pub fn alloc<P, DigitalPool, AnalogPool, const N: usize>(
+        &self,
+        pool_q: &MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
+    ) -> Option<Box<P, Uninit>>
+    where
+        P: Pool + 'static,
+        DigitalPool: Pool + 'static,
+        AnalogPool: Pool + 'static,
+        PBox<DigitalPool>: Debug,
+        PBox<AnalogPool>: Debug,
+    {
+        if self.policy() != OverflowPolicy::DropOldest {
+            return P::alloc();
+        }
+
+        // Everything else is put back, so give up after a full lap of the
+        // queue
+        for _ in 0..N {
+            if let Some(pbox) = P::alloc() {
+                return Some(pbox);
+            }
+
+            // Only sample blocks are queued here, markers have a queue of
+            // their own
+            let rpt = pool_q.dequeue()?;
+            let source = rpt.source();
+
+            // Out of order now, but the host sorts blocks by their sequence
+            // number
+            if !rpt.is_from::<P>() && pool_q.enqueue(rpt).is_ok() {
+                continue;
+            }
+            // Dropped, or another source took the slot in the meantime
+            if let Some(source) = source {
+                self.count_lost(source);
+            }
+        }
+
+        P::alloc()
+    }
+}
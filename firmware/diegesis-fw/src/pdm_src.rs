@@ -0,0 +1,241 @@
+//! Audio from a PDM microphone, like the one on the Playground.
+//!
+//! The PDM decimates the microphone's bit stream into 16 bit PCM samples,
+//! which are sent as `ReportKind::Audio` blocks out of the analog pool. Like
+//! the SPIM, it has an END event once a block is full, and a STARTED event
+//! once the pointer of the next one was latched, so the double buffering
+//! works the same. The first few milliseconds of a capture are the filter
+//! settling, and may be ignored.
+
+use core::{fmt::Debug, marker::PhantomData, ops::DerefMut, sync::atomic::AtomicBool};
+
+use crate::{
+    groundhog_nrf52::GlobalRollingTimer,
+    overflow::{Overflow, AUDIO_SOURCE},
+    source::{Blocks, DoubleBuffer, Source},
+    sync_start, InternalReport, InternalReportKind, PBox,
+};
+use nrf52840_hal::{
+    gpio::{Floating, Input, Level, Output, Pin, PushPull},
+    pac::PDM,
+    ppi::Ppi7,
+};
+
+use groundhog::RollingTimer;
+use heapless::{
+    mpmc::MpMcQueue,
+    pool::{
+        singleton::{Box, Pool},
+        Uninit,
+    },
+};
+
+/// The PPI channel owned through `Ppi7`, starting the PDM with the others.
+const PPI_CH: usize = 7;
+
+/// A PDM clock of 1.28 MHz, decimated by 80.
+pub const SAMPLE_RATE: u32 = 16_000;
+
+/// MAXCNT counts samples, which are 16 bit in mono.
+const BLOCK_SAMPLES: u32 = 2048;
+
+/// Ticks after which a block is considered late, 2% after its nominal end.
+const EXPECTED_TICKS: u32 = {
+    let nominal = (4_000_000 / SAMPLE_RATE) * BLOCK_SAMPLES;
+    nominal + (nominal / 50)
+};
+
+/// A block being filled by the PDM.
+pub struct Transfer<AnalogPool>
+where
+    AnalogPool: Pool,
+{
+    pdm: PDM,
+    block: PBox<AnalogPool>,
+}
+
+pub struct PdmMic<AnalogPool, DigitalPool> {
+    _pools: PhantomData<(AnalogPool, DigitalPool)>,
+}
+
+impl<AnalogPool, DigitalPool> PdmMic<AnalogPool, DigitalPool>
+where
+    AnalogPool: Pool,
+    PBox<AnalogPool>: DerefMut<Target = [i16; 2048]>,
+{
+    fn set_ptr(pdm: &PDM, block: &PBox<AnalogPool>) {
+        pdm.sample
+            .ptr
+            .write(|w| unsafe { w.bits(block.as_ptr() as u32) });
+    }
+}
+
+impl<AnalogPool, DigitalPool> Source for PdmMic<AnalogPool, DigitalPool>
+where
+    AnalogPool: Pool + 'static,
+    DigitalPool: Pool + 'static,
+    PBox<AnalogPool>: Debug + DerefMut<Target = [i16; 2048]>,
+    PBox<DigitalPool>: Debug,
+{
+    type Uninit = Box<AnalogPool, Uninit>;
+    type Block = PBox<AnalogPool>;
+    type Report = InternalReport<DigitalPool, AnalogPool>;
+    type Idle = PDM;
+    type One = Transfer<AnalogPool>;
+    type Two = (Transfer<AnalogPool>, PBox<AnalogPool>);
+
+    const NAME: &'static str = "PDM";
+
+    fn block(&mut self, pbox: Box<AnalogPool, Uninit>) -> PBox<AnalogPool> {
+        pbox.freeze()
+    }
+
+    fn report(&mut self, timestamp: u32, seq: u32, block: PBox<AnalogPool>) -> Self::Report {
+        InternalReport {
+            timestamp,
+            seq,
+            kind: InternalReportKind::AudioReport {
+                sample_rate: SAMPLE_RATE,
+                payload: block,
+            },
+        }
+    }
+
+    fn lost_index(&self) -> usize {
+        AUDIO_SOURCE
+    }
+
+    fn expected_ticks(&self) -> Option<u32> {
+        Some(EXPECTED_TICKS)
+    }
+
+    fn arm(&mut self, pdm: PDM, block: PBox<AnalogPool>) -> Self::One {
+        // Only arm the transfer, the synchronized start begins it
+        Self::set_ptr(&pdm, &block);
+        pdm.enable.write(|w| w.enable().enabled());
+        Transfer { pdm, block }
+    }
+
+    fn restart(&mut self, pdm: PDM, block: PBox<AnalogPool>) -> Self::One {
+        let one = self.arm(pdm, block);
+        one.pdm.tasks_start.write(|w| unsafe { w.bits(1) });
+        one
+    }
+
+    fn is_done(&mut self, one: &mut Self::One) -> bool {
+        one.pdm.events_end.read().bits() != 0
+    }
+
+    fn clear_started(&mut self, one: &mut Self::One) {
+        one.pdm.events_started.reset();
+    }
+
+    fn wait(&mut self, one: Self::One) -> (PDM, PBox<AnalogPool>) {
+        let Transfer { pdm, block } = one;
+        while pdm.events_end.read().bits() == 0 {}
+
+        // The PDM carries on into the same block, stop it right away
+        pdm.tasks_stop.write(|w| unsafe { w.bits(1) });
+        while pdm.events_stopped.read().bits() == 0 {}
+        pdm.events_stopped.reset();
+        pdm.events_started.reset();
+        pdm.events_end.reset();
+
+        // Tasks are ignored while disabled, so a later synchronized start
+        // can't restart it with a stale pointer
+        pdm.enable.write(|w| w.enable().disabled());
+
+        (pdm, block)
+    }
+
+    fn enqueue(&mut self, one: Self::One, block: PBox<AnalogPool>) -> Self::Two {
+        // Latched once the current block is full
+        one.pdm.events_started.reset();
+        Self::set_ptr(&one.pdm, &block);
+        (one, block)
+    }
+
+    fn exchange(&mut self, two: Self::Two) -> (Self::One, PBox<AnalogPool>) {
+        let (current, next) = two;
+        assert!(current.pdm.events_end.read().bits() != 0);
+        current.pdm.events_end.reset();
+
+        let one = Transfer {
+            pdm: current.pdm,
+            block: next,
+        };
+        (one, current.block)
+    }
+}
+
+pub struct PdmSrc<AnalogPool, DigitalPool, const N: usize>
+where
+    AnalogPool: Pool + 'static,
+    DigitalPool: Pool + 'static,
+    PBox<AnalogPool>: Debug + DerefMut<Target = [i16; 2048]>,
+    PBox<DigitalPool>: Debug,
+{
+    core: DoubleBuffer<PdmMic<AnalogPool, DigitalPool>, AnalogPool, DigitalPool, AnalogPool, N>,
+    timer: GlobalRollingTimer,
+    _ppi: Ppi7,
+    _pins: (Pin<Input<Floating>>, Pin<Output<PushPull>>),
+}
+
+impl<AnalogPool, DigitalPool, const N: usize> PdmSrc<AnalogPool, DigitalPool, N>
+where
+    AnalogPool: Pool + 'static,
+    DigitalPool: Pool + 'static,
+    PBox<AnalogPool>: Debug + DerefMut<Target = [i16; 2048]>,
+    PBox<DigitalPool>: Debug,
+{
+    pub fn from_parts<DATA, CLK>(
+        pdm: PDM,
+        data_pin: Pin<DATA>,
+        clk_pin: Pin<CLK>,
+        ppi: Ppi7,
+        pool_q: &'static MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
+        timer: GlobalRollingTimer,
+        overflow: &'static Overflow,
+    ) -> Self {
+        let data = data_pin.into_floating_input();
+        let clk = clk_pin.into_push_pull_output(Level::Low);
+
+        pdm.psel.din.write(|w| unsafe { w.bits(data.psel_bits()) });
+        pdm.psel.clk.write(|w| unsafe { w.bits(clk.psel_bits()) });
+
+        pdm.pdmclkctrl.write(|w| w.freq()._1280k());
+        pdm.ratio.write(|w| w.ratio().ratio80());
+        pdm.mode
+            .write(|w| w.operation().mono().edge().leftfalling());
+        pdm.sample
+            .maxcnt
+            .write(|w| unsafe { w.bits(BLOCK_SAMPLES) });
+
+        pdm.intenset
+            .write(|w| w.started().set_bit().end().set_bit());
+
+        // SAFETY: We own `Ppi7`
+        unsafe { sync_start::connect_start(PPI_CH, &pdm.tasks_start as *const _ as u32) };
+
+        Self {
+            core: DoubleBuffer::new(
+                PdmMic {
+                    _pools: PhantomData,
+                },
+                pdm,
+                Blocks::new(pool_q, overflow),
+                pool_q,
+                timer.clone(),
+            ),
+            timer,
+            _ppi: ppi,
+            _pins: (data, clk),
+        }
+    }
+
+    pub fn poll(&mut self, fuse: &AtomicBool) {
+        // Take the timestamp as early as possible, see `DoubleBuffer::poll`
+        let now = self.timer.get_ticks();
+        self.core.poll(fuse, now);
+    }
+}
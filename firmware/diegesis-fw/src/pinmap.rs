@@ -1,3 +1,4 @@
+use diegesis_icd::AnalogSource;
 use nrf52840_hal::{
     gpio::{
         p0::{Parts as P0Parts, P0_02, P0_03, P0_29},
@@ -20,6 +21,31 @@ pub enum Leds {
     }
 }
 
+/// Pins reserved for the analog inputs. The host picks what each channel
+/// samples, single ended or differential, from these and the internal
+/// sources, see `AnalogConfig::inputs`.
+pub struct AnalogPins {
+    pub ain0: P0_02<Disconnected>,
+    pub ain1: P0_03<Disconnected>,
+    pub ain5: P0_29<Disconnected>,
+}
+
+impl AnalogPins {
+    /// Whether `source` is one of these pins, or an internal source.
+    pub fn provides(&self, source: AnalogSource) -> bool {
+        match source {
+            AnalogSource::Ain(ain) => [0, 1, 5].contains(&ain),
+            AnalogSource::Vdd | AnalogSource::VddhDiv5 => true,
+        }
+    }
+}
+
+/// Pins of an on-board PDM microphone.
+pub struct MicPins {
+    pub data: Pin<Disconnected>,
+    pub clk: Pin<Disconnected>,
+}
+
 pub struct MappedPins {
     // SPIM data and clock (unused) pins
     pub spim_p0_data: Pin<Disconnected>,
@@ -31,6 +57,14 @@ pub struct MappedPins {
     pub spim_p3_data: Pin<Disconnected>,
     pub spim_p3_clk: Pin<Disconnected>,
 
+    // I2S data, and clock (unused) pins
+    pub i2s_sdin: Pin<Disconnected>,
+    pub i2s_sck: Pin<Disconnected>,
+    pub i2s_lrck: Pin<Disconnected>,
+
+    // Pin not connected on the board, driven with the comparator output
+    pub comp_out: Pin<Disconnected>,
+
     // User buttons
     pub start_pause_btn: Pin<Disconnected>,
     pub lap_reset_btn: Pin<Disconnected>,
@@ -38,8 +72,11 @@ pub struct MappedPins {
     // Display leds
     pub leds: Leds,
 
-    // ADCs (todo: variable amount? variable pins? generics?)
-    pub adcs: (P0_02<Disconnected>, P0_03<Disconnected>, P0_29<Disconnected>),
+    // ADCs
+    pub adcs: AnalogPins,
+
+    // PDM microphone, if the board has one
+    pub mic: Option<MicPins>,
 }
 
 pub trait PinMap {
@@ -75,6 +112,14 @@ impl PinMap for Nrf52Dk {
             spim_p3_data: p1.p1_07.degrade(),
             spim_p3_clk: p1.p1_06.degrade(),
 
+            // I2S data, and clock (unused) pins
+            i2s_sdin: p1.p1_08.degrade(),
+            i2s_sck: p1.p1_10.degrade(),
+            i2s_lrck: p1.p1_11.degrade(),
+
+            // Comparator output, leave the header pin unconnected
+            comp_out: p1.p1_12.degrade(),
+
             // User buttons
             start_pause_btn: p0.p0_25.degrade(),
             lap_reset_btn: p0.p0_24.degrade(),
@@ -87,7 +132,13 @@ impl PinMap for Nrf52Dk {
                 led3: p0.p0_16.degrade(),
             },
 
-            adcs: (p0.p0_02, p0.p0_03, p0.p0_29),
+            adcs: AnalogPins {
+                ain0: p0.p0_02,
+                ain1: p0.p0_03,
+                ain5: p0.p0_29,
+            },
+
+            mic: None,
         }
     }
 }
@@ -118,6 +169,14 @@ impl PinMap for AdafruitPlaygroundBluefruit {
             spim_p3_data: p0.p0_14.degrade(), // D1/TX
             spim_p3_clk: p0.p0_12.degrade(),  // Not Connected
 
+            // I2S data, and clock (unused) pins
+            i2s_sdin: p0.p0_26.degrade(), // A0/Speaker, while the speaker is off
+            i2s_sck: p0.p0_20.degrade(),  // Not Connected
+            i2s_lrck: p0.p0_24.degrade(), // Not Connected
+
+            // Comparator output
+            comp_out: p0.p0_27.degrade(), // Not Connected
+
             // User buttons
             start_pause_btn: p1.p1_15.degrade(), // Right Button
             lap_reset_btn: p1.p1_02.degrade(),   // Left Button
@@ -130,7 +189,16 @@ impl PinMap for AdafruitPlaygroundBluefruit {
                 enable_pin: p0.p0_06.degrade(),
             },
 
-            adcs: (p0.p0_02, p0.p0_03, p0.p0_29),
+            adcs: AnalogPins {
+                ain0: p0.p0_02,
+                ain1: p0.p0_03,
+                ain5: p0.p0_29,
+            },
+
+            mic: Some(MicPins {
+                data: p0.p0_16.degrade(), // PDM_DAT
+                clk: p0.p0_17.degrade(),  // PDM_CLK
+            }),
         }
     }
 }
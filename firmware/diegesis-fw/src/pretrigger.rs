@@ -0,0 +1,84 @@
+//! Holds the last sample blocks of each channel while a trigger is armed.
+//!
+//! Instead of dropping sample data until the trigger fires, the newest `N`
+//! blocks per channel are kept, and sent ahead of the post-trigger data once
+//! it fires. Older blocks go back to their pool as they are pushed out. The
+//! `Trigger` report marks where the trigger fired among them.
+//!
+//! Only captures that run while armed have any history, digital triggers
+//! start the sources when they fire.
+
+use core::{fmt::Debug, ops::DerefMut};
+
+use heapless::{pool::singleton::Pool, Deque};
+
+use crate::{InternalReport, InternalReportKind, PBox, DIGITAL_CHANNELS, UART_PORTS};
+
+pub struct PreTrigger<DigitalPool, AnalogPool, const N: usize>
+where
+    DigitalPool: Pool,
+    AnalogPool: Pool,
+    PBox<DigitalPool>: Debug,
+    PBox<AnalogPool>: Debug,
+{
+    digital: [Deque<InternalReport<DigitalPool, AnalogPool>, N>; DIGITAL_CHANNELS],
+    analog: Deque<InternalReport<DigitalPool, AnalogPool>, N>,
+    audio: Deque<InternalReport<DigitalPool, AnalogPool>, N>,
+    uart: [Deque<InternalReport<DigitalPool, AnalogPool>, N>; UART_PORTS],
+}
+
+impl<DigitalPool, AnalogPool, const N: usize> PreTrigger<DigitalPool, AnalogPool, N>
+where
+    DigitalPool: Pool,
+    AnalogPool: Pool,
+    PBox<DigitalPool>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<AnalogPool>: Debug + DerefMut<Target = [i16; 2048]>,
+{
+    pub fn new() -> Self {
+        Self {
+            digital: Default::default(),
+            analog: Deque::new(),
+            audio: Deque::new(),
+            uart: Default::default(),
+        }
+    }
+
+    /// Keep a sample block, releasing the oldest one of its channel if full.
+    pub fn hold(&mut self, rpt: InternalReport<DigitalPool, AnalogPool>) {
+        let ring = match rpt.kind {
+            InternalReportKind::DigitalReport { channel, .. } => {
+                match self.digital.get_mut(channel as usize) {
+                    Some(ring) => ring,
+                    None => return,
+                }
+            }
+            InternalReportKind::AnalogReport { .. } => &mut self.analog,
+            InternalReportKind::AudioReport { .. } => &mut self.audio,
+            InternalReportKind::UartReport { port, .. } => match self.uart.get_mut(port as usize) {
+                Some(ring) => ring,
+                None => return,
+            },
+            _ => return,
+        };
+
+        if ring.is_full() {
+            ring.pop_front();
+        }
+        ring.push_back(rpt).ok();
+    }
+
+    /// Take the next held block, oldest first per channel.
+    pub fn pop(&mut self) -> Option<InternalReport<DigitalPool, AnalogPool>> {
+        self.digital
+            .iter_mut()
+            .chain(core::iter::once(&mut self.analog))
+            .chain(core::iter::once(&mut self.audio))
+            .chain(self.uart.iter_mut())
+            .find_map(|ring| ring.pop_front())
+    }
+
+    /// Release all held blocks.
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
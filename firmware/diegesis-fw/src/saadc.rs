@@ -20,8 +20,8 @@ use saadc::{
 
 /// Interface for the SAADC peripheral.
 ///
-/// External analog channels supported by the SAADC implement the `Channel` trait.
-/// Currently, use of only one channel is allowed.
+/// Inputs supported by the SAADC implement the `ChannelInput` trait. Up to
+/// eight of them can be scanned at once, each with its own `ChannelConfig`.
 pub struct Saadc(SAADC);
 
 impl Saadc {
@@ -38,31 +38,47 @@ impl Saadc {
         } = config;
 
         saadc.enable.write(|w| w.enable().enabled());
-        saadc.resolution.write(|w| w.val().variant(resolution));
-        saadc
-            .oversample
-            .write(|w| w.oversample().variant(oversample));
         saadc.samplerate.write(|w| w.mode().task());
 
         let mut this = Saadc(saadc);
-        this.set_channel_config(
-            0,
-            ChannelConfig {
-                reference,
-                gain,
-                time,
-                resistor,
-            },
-        );
-
-        // Calibrate
-        this.0.events_calibratedone.reset();
-        this.0.tasks_calibrateoffset.write(|w| unsafe { w.bits(1) });
-        while this.0.events_calibratedone.read().bits() == 0 {}
+        this.set_resolution(resolution);
+        this.set_oversample(oversample);
+        for channel in 0..8 {
+            this.set_channel_config(
+                channel,
+                ChannelConfig {
+                    reference,
+                    gain,
+                    time,
+                    resistor,
+                },
+            );
+        }
+
+        this.calibrate();
 
         this
     }
 
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.0.resolution.write(|w| w.val().variant(resolution));
+    }
+
+    pub fn set_oversample(&mut self, oversample: Oversample) {
+        self.0
+            .oversample
+            .write(|w| w.oversample().variant(oversample));
+    }
+
+    /// Run an offset calibration, blocking until it is done.
+    pub fn calibrate(&mut self) {
+        self.0.events_calibratedone.reset();
+        self.0.tasks_calibrateoffset.write(|w| unsafe { w.bits(1) });
+        while self.0.events_calibratedone.read().bits() == 0 {}
+    }
+
+    /// Configure the `channel`th channel of a scan. Whether the channel is
+    /// single-ended or differential is set by its input, when starting a conversion.
     pub fn set_channel_config(&mut self, channel: u8, config: ChannelConfig) {
         let ChannelConfig {
             gain,
@@ -72,11 +88,10 @@ impl Saadc {
         } = config;
 
         let channel = &self.0.ch[channel as usize];
-        channel.config.write(|w| {
+        channel.config.modify(|_r, w| {
             w.refsel().variant(reference);
             w.gain().variant(gain);
             w.tacq().variant(time);
-            w.mode().se();
             w.resp().variant(resistor);
             w.resn().bypass();
             w.burst().enabled();
@@ -88,6 +103,9 @@ impl Saadc {
     ///
     /// The conversion is expected to be driven via PPI, so this will *not* collect any samples on
     /// its own.
+    ///
+    /// Samples of all channels are interleaved in the buffer, so its length
+    /// must be a multiple of the number of channels.
     pub fn start_async_conversion<B, C>(self, channels: C, mut buffer: B) -> AsyncConversion<B, C>
     where
         B: StaticWriteBuffer<Word = i16>,
@@ -95,11 +113,22 @@ impl Saadc {
     {
         // TODO check that buffer is in RAM
 
-        let mut chan_buf = [0; 8];
-        channels.channels(&mut chan_buf);
-        for (i, val) in chan_buf.iter().enumerate() {
+        // Unused channels are left at 0, which disconnects them
+        let mut pos_buf = [0; 8];
+        let mut neg_buf = [0; 8];
+        channels.channels(&mut pos_buf);
+        channels.negative_channels(&mut neg_buf);
+        for (i, (pos, neg)) in pos_buf.iter().zip(neg_buf.iter()).enumerate() {
             let channel = &self.0.ch[i];
-            channel.pselp.write(|w| unsafe { w.bits(u32::from(*val)) });
+            channel.pselp.write(|w| unsafe { w.bits(u32::from(*pos)) });
+            channel.pseln.write(|w| unsafe { w.bits(u32::from(*neg)) });
+            channel.config.modify(|_r, w| {
+                if *neg == 0 {
+                    w.mode().se()
+                } else {
+                    w.mode().diff()
+                }
+            });
         }
 
         // Use one-shot mode. Continuous mode does not support using multiple channels.
@@ -107,6 +136,7 @@ impl Saadc {
 
         let (addr, words) = unsafe { buffer.static_write_buffer() };
         assert!(words < (1 << 15));
+        assert_eq!(words % channels.len(), 0, "Buffer must hold whole scans");
         self.0
             .result
             .ptr
@@ -128,6 +158,34 @@ impl Saadc {
         }
     }
 
+    /// Set the limits of the `channel`th channel. The LIMITH/LIMITL events
+    /// fire for every result above `high` or below `low`.
+    pub fn set_limits(&self, channel: u8, low: i16, high: i16) {
+        self.0.ch[channel as usize]
+            .limit
+            .write(|w| unsafe { w.low().bits(low as u16).high().bits(high as u16) });
+    }
+
+    /// Enable or disable the LIMITH and LIMITL interrupts of the `channel`th channel.
+    pub fn set_limit_interrupts(&self, channel: u8, high: bool, low: bool) {
+        let high_bit = 1 << (6 + 2 * u32::from(channel));
+        let low_bit = high_bit << 1;
+        let set = if high { high_bit } else { 0 } | if low { low_bit } else { 0 };
+        let clear = (high_bit | low_bit) & !set;
+        self.0.intenclr.write(|w| unsafe { w.bits(clear) });
+        self.0.intenset.write(|w| unsafe { w.bits(set) });
+    }
+
+    /// Read and clear the (LIMITH, LIMITL) events of the `channel`th channel.
+    pub fn take_limit_events(&self, channel: u8) -> (bool, bool) {
+        let events = &self.0.events_ch[channel as usize];
+        let high = events.limith.read().bits() != 0;
+        let low = events.limitl.read().bits() != 0;
+        events.limith.reset();
+        events.limitl.reset();
+        (high, low)
+    }
+
     #[inline]
     pub fn enable_end_interrupt(&self) {
         self.0.intenset.write(|w| w.end().set_bit());
@@ -184,69 +242,119 @@ impl Saadc {
     }
 }
 
+/// The input of a single SAADC channel.
+pub trait ChannelInput {
+    /// Value of PSELP, selecting the positive input.
+    fn positive() -> u8;
+
+    /// Value of PSELN, selecting the negative input. `None` for single-ended inputs.
+    fn negative() -> Option<u8> {
+        None
+    }
+}
+
 /// A set of SAADC channels.
 pub trait Channels {
     /// Number of channels.
     #[doc(hidden)]
-    const LEN: usize;
+    fn len(&self) -> usize;
 
-    /// Writes the channel IDs into the first `Self::LEN` elements in `channels`.
+    /// Writes the positive inputs into the first `self.len()` elements in `channels`.
     #[doc(hidden)]
     fn channels(&self, channels: &mut [u8; 8]);
-    // NB: this doesn't return `[u8; Self::LEN]` because of limitations in current Rust
 
-    /// Returns the active channels (1..=8) as a bitflag
+    /// Writes the negative inputs into the first `self.len()` elements in
+    /// `channels`, 0 for single-ended inputs.
     #[doc(hidden)]
-    fn bitflag(&self) -> u8;
+    fn negative_channels(&self, channels: &mut [u8; 8]);
+
+    /// Returns the scanned channels as a bitflag, one bit per channel.
+    #[doc(hidden)]
+    fn bitflag(&self) -> u8 {
+        ((1u16 << self.len()) - 1) as u8
+    }
+}
+
+/// Channels chosen at runtime, as PSELP and PSELN values. The scan ends at
+/// the first disconnected positive input.
+#[derive(Clone, Copy)]
+pub struct ScanInputs {
+    positive: [u8; 8],
+    negative: [u8; 8],
+}
+
+impl ScanInputs {
+    pub fn new(positive: [u8; 8], negative: [u8; 8]) -> Self {
+        assert_ne!(positive[0], 0, "Scans need at least one channel");
+        Self { positive, negative }
+    }
+}
+
+impl Channels for ScanInputs {
+    fn len(&self) -> usize {
+        self.positive.iter().take_while(|psel| **psel != 0).count()
+    }
+
+    fn channels(&self, channels: &mut [u8; 8]) {
+        let len = self.len();
+        channels[..len].copy_from_slice(&self.positive[..len]);
+    }
+
+    fn negative_channels(&self, channels: &mut [u8; 8]) {
+        let len = self.len();
+        channels[..len].copy_from_slice(&self.negative[..len]);
+    }
+}
+
+/// A differential input, sampling the voltage of `P` relative to `N`.
+pub struct Differential<P, N> {
+    pub positive: P,
+    pub negative: N,
+}
+
+impl<P: ChannelInput, N: ChannelInput> ChannelInput for Differential<P, N> {
+    fn positive() -> u8 {
+        P::positive()
+    }
+
+    fn negative() -> Option<u8> {
+        Some(N::positive())
+    }
 }
 
 macro_rules! channel_tuples {
     ( $($len:literal: ( $($t:ident,)+ ),)+ ) => {
         $(
             impl<
-                $( $t: Channel<Saadc, ID = u8> ),*
+                $( $t: ChannelInput ),*
             > Channels for ( $($t,)+ ) {
-                const LEN: usize = $len;
+                fn len(&self) -> usize {
+                    $len
+                }
 
                 fn channels(&self, channels: &mut [u8; 8]) {
-                    channels[..$len].copy_from_slice(&[$($t::channel(),)+]);
+                    channels[..$len].copy_from_slice(&[$($t::positive(),)+]);
                 }
 
-                fn bitflag(&self) -> u8 {
-                    let mut flag = 0;
-                    for ch_bf in &[$(channel_to_bitflag($t::channel()),)+] {
-                        flag |= ch_bf;
-                    }
-                    flag
+                fn negative_channels(&self, channels: &mut [u8; 8]) {
+                    channels[..$len].copy_from_slice(&[$($t::negative().unwrap_or(0),)+]);
                 }
             }
         )+
     };
 }
 
-fn channel_to_bitflag(ch_id: u8) -> u8 {
-    match ch_id {
-        1 => 0b0000_0001,
-        2 => 0b0000_0010,
-        3 => 0b0000_0100,
-        4 => 0b0000_1000,
-        5 => 0b0001_0000,
-        6 => 0b0010_0000,
-        7 => 0b0100_0000,
-        8 => 0b1000_0000,
-        _ => 0b000000000,
+impl<T: ChannelInput> Channels for T {
+    fn len(&self) -> usize {
+        1
     }
-}
-
-impl<T: Channel<Saadc, ID = u8>> Channels for T {
-    const LEN: usize = 1;
 
     fn channels(&self, channels: &mut [u8; 8]) {
-        channels[0] = T::channel();
+        channels[0] = T::positive();
     }
 
-    fn bitflag(&self) -> u8 {
-        channel_to_bitflag(T::channel())
+    fn negative_channels(&self, channels: &mut [u8; 8]) {
+        channels[0] = T::negative().unwrap_or(0);
     }
 }
 
@@ -261,6 +369,33 @@ channel_tuples! {
     8: (T1, T2, T3, T4, T5, T6, T7, T8,),
 }
 
+/// Limits a sample buffer to a whole number of scans over `scan_len` channels.
+/// The samples past the last whole scan are left untouched.
+pub struct WholeScans<B> {
+    buffer: B,
+    scan_len: usize,
+}
+
+impl<B> WholeScans<B> {
+    pub fn new(buffer: B, scan_len: usize) -> Self {
+        assert!(scan_len > 0);
+        Self { buffer, scan_len }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.buffer
+    }
+}
+
+unsafe impl<B: StaticWriteBuffer<Word = i16>> StaticWriteBuffer for WholeScans<B> {
+    type Word = i16;
+
+    unsafe fn static_write_buffer(&mut self) -> (*mut Self::Word, usize) {
+        let (ptr, len) = self.buffer.static_write_buffer();
+        (ptr, len - (len % self.scan_len))
+    }
+}
+
 /// An ongoing asynchronous SAADC conversion.
 pub struct AsyncConversion<B, C> {
     saadc: Saadc,
@@ -290,6 +425,7 @@ impl<B: StaticWriteBuffer<Word = i16>, C> AsyncConversion<B, C> {
     ) -> Result<AsyncPendingConversion<B, B2, C>, (Self, B2)>
     where
         B2: StaticWriteBuffer<Word = i16>,
+        C: Channels,
     {
         // Previous transfer hasn't started yet.
         if self
@@ -309,6 +445,7 @@ impl<B: StaticWriteBuffer<Word = i16>, C> AsyncConversion<B, C> {
 
         let (addr, words) = unsafe { buffer.static_write_buffer() };
         assert!(words < (1 << 15));
+        assert_eq!(words % self.channels.len(), 0, "Buffer must hold whole scans");
         self.saadc
             .0
             .result
@@ -525,6 +662,12 @@ macro_rules! channel_mappings {
                     $n
                 }
             }
+
+            impl<STATE> ChannelInput for gpio::p0::$pin<STATE> {
+                fn positive() -> u8 {
+                    $n
+                }
+            }
         )*
     };
 }
@@ -562,6 +705,13 @@ impl Channel<Saadc> for InternalVdd {
     }
 }
 
+#[cfg(not(feature = "9160"))]
+impl ChannelInput for InternalVdd {
+    fn positive() -> u8 {
+        9
+    }
+}
+
 #[cfg(not(feature = "9160"))]
 /// Channel that doesn't sample a pin, but the internal VDD voltage.
 pub struct InternalVdd;
@@ -575,6 +725,13 @@ impl Channel<Saadc> for InternalVddHdiv5 {
     }
 }
 
+#[cfg(any(feature = "52833", feature = "52840"))]
+impl ChannelInput for InternalVddHdiv5 {
+    fn positive() -> u8 {
+        0x0D
+    }
+}
+
 #[cfg(any(feature = "52833", feature = "52840"))]
 /// The voltage on the VDDH pin, divided by 5.
 pub struct InternalVddHdiv5;
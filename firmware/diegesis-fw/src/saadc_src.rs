@@ -1,21 +1,26 @@
-use core::{
-    mem,
-    sync::atomic::{AtomicBool, Ordering},
-};
+use core::sync::atomic::AtomicBool;
 
 use crate::{
     groundhog_nrf52::GlobalRollingTimer,
-    saadc::{AsyncConversion, AsyncPendingConversion, Channels, Saadc, SaadcConfig},
-    InternalReport,
+    marker::MarkerQueue,
+    pinmap::AnalogPins,
+    saadc::{
+        AsyncConversion, AsyncPendingConversion, ChannelConfig, Channels, Gain, Oversample,
+        Reference, Resistor, Resolution, Saadc, SaadcConfig, ScanInputs, Time, WholeScans,
+    },
+    overflow::{Overflow, ANALOG_SOURCE},
+    source::{Blocks, DoubleBuffer, Source, State},
+    trigger::Trigger,
+    InternalReport, InternalReportKind,
+};
+use diegesis_icd::{
+    AcquisitionTime, AnalogChannelConfig, AnalogConfig, AnalogGain, AnalogReference,
+    AnalogResolution, AnalogSettings, AnalogTrigger, Edge, TriggerSource,
 };
-use cortex_m::prelude::_embedded_hal_timer_CountDown;
-use embedded_hal::timer::Cancel;
 use nrf52840_hal::{
-    pac::SAADC,
+    pac::{timer0::TASKS_START, SAADC},
     ppi::ConfigurablePpi,
-    saadc::{Oversample, Time},
-    timer::{self, Periodic},
-    Timer,
+    timer,
 };
 
 use embedded_dma::StaticWriteBuffer;
@@ -24,55 +29,368 @@ use heapless::{
     mpmc::MpMcQueue,
     pool::{
         singleton::{Box, Pool},
-        Init,
+        Init, Uninit,
     },
 };
 
 type PBox<POOL> = Box<POOL, Init>;
 use core::fmt::Debug;
 
-enum State<B, C> {
-    Idle(Saadc, C),
-    OnePending(AsyncConversion<B, C>),
-    TwoPending(AsyncPendingConversion<B, B, C>),
-    Invalid,
+fn resolution(res: AnalogResolution) -> Resolution {
+    match res {
+        AnalogResolution::Bits8 => Resolution::_8BIT,
+        AnalogResolution::Bits10 => Resolution::_10BIT,
+        AnalogResolution::Bits12 => Resolution::_12BIT,
+        AnalogResolution::Bits14 => Resolution::_14BIT,
+    }
+}
+
+fn oversample(log2: u8) -> Oversample {
+    match log2 {
+        0 => Oversample::BYPASS,
+        1 => Oversample::OVER2X,
+        2 => Oversample::OVER4X,
+        3 => Oversample::OVER8X,
+        4 => Oversample::OVER16X,
+        5 => Oversample::OVER32X,
+        6 => Oversample::OVER64X,
+        7 => Oversample::OVER128X,
+        _ => Oversample::OVER256X,
+    }
 }
 
-impl<B, C> State<B, C> {
-    fn take(&mut self) -> Self {
-        mem::replace(self, State::Invalid)
+fn channel_config(config: &AnalogChannelConfig) -> ChannelConfig {
+    ChannelConfig {
+        gain: match config.gain {
+            AnalogGain::Gain1_6 => Gain::GAIN1_6,
+            AnalogGain::Gain1_5 => Gain::GAIN1_5,
+            AnalogGain::Gain1_4 => Gain::GAIN1_4,
+            AnalogGain::Gain1_3 => Gain::GAIN1_3,
+            AnalogGain::Gain1_2 => Gain::GAIN1_2,
+            AnalogGain::Gain1 => Gain::GAIN1,
+            AnalogGain::Gain2 => Gain::GAIN2,
+            AnalogGain::Gain4 => Gain::GAIN4,
+        },
+        reference: match config.reference {
+            AnalogReference::Internal => Reference::INTERNAL,
+            AnalogReference::Vdd1_4 => Reference::VDD1_4,
+        },
+        time: match config.time {
+            AcquisitionTime::Us3 => Time::_3US,
+            AcquisitionTime::Us5 => Time::_5US,
+            AcquisitionTime::Us10 => Time::_10US,
+            AcquisitionTime::Us15 => Time::_15US,
+            AcquisitionTime::Us20 => Time::_20US,
+            AcquisitionTime::Us40 => Time::_40US,
+        },
+        resistor: Resistor::BYPASS,
     }
+}
 
-    fn saadc(&self) -> &Saadc {
-        match self {
-            State::Idle(saadc, ..) => saadc,
-            State::OnePending(pend) => pend,
-            State::TwoPending(pend) => pend,
-            State::Invalid => unreachable!(),
+/// The PSELP and PSELN values of the channels in `config`.
+fn scan_inputs(config: &AnalogConfig) -> ScanInputs {
+    let mut positive = [0; 8];
+    let mut negative = [0; 8];
+    for (i, input) in config.inputs.iter().enumerate() {
+        if let Some(input) = input {
+            positive[i] = input.positive.psel();
+            negative[i] = input.negative.map_or(0, |n| n.psel());
         }
     }
+    ScanInputs::new(positive, negative)
 }
 
-pub struct SaadcSrc<C, T, AnalogPool, DigitalPool, PPI, PPI2, const N: usize>
+/// Apply `config` to the channels it scans.
+fn apply_config<T: timer::Instance>(saadc: &mut Saadc, timer: &T, config: &AnalogConfig) {
+    saadc.set_resolution(resolution(config.resolution));
+    saadc.set_oversample(oversample(config.oversample));
+    for (i, ch) in config.channels.iter().take(config.scan_len()).enumerate() {
+        saadc.set_channel_config(i as u8, channel_config(ch));
+    }
+
+    // Offsets depend on the configuration
+    saadc.calibrate();
+
+    let regs = timer.as_timer0();
+    regs.cc[0].write(|w| unsafe { w.bits(config.sample_period_us) });
+}
+
+/// Progress of the analog trigger. The limits of the watched channel are
+/// both set to the trigger level, so an edge is a LIMITL event followed by a
+/// LIMITH event (rising), or the other way around (falling).
+#[derive(Clone, Copy, PartialEq)]
+enum TriggerStage {
+    Off,
+    /// Waiting for the input to be on the starting side of the level.
+    Priming,
+    Crossing { rising: bool },
+}
+
+type Scans<AnalogPool> = WholeScans<Box<AnalogPool>>;
+
+/// All analog channels, scanned by the SAADC on each tick of the sample
+/// timer.
+pub struct SaadcScan<T, AnalogPool, DigitalPool, PPI, PPI2>
 where
     AnalogPool: Pool + 'static,
     DigitalPool: Pool + 'static,
     PBox<AnalogPool>: Debug,
     PBox<DigitalPool>: Debug,
 {
-    state: State<Box<AnalogPool>, C>,
-    pool_q: &'static MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
-    last_start: u32,
+    markers: &'static MarkerQueue<InternalReport<DigitalPool, AnalogPool>>,
     bitflag: u8,
+    config: AnalogConfig,
+    recal_temp: Option<i32>,
+    trigger: &'static Trigger,
+    trigger_config: Option<AnalogTrigger>,
+    trigger_stage: TriggerStage,
     ppi: PPI,
     #[allow(dead_code)]
     ppi2: PPI2,
-    sample_timer: Timer<T, Periodic>,
-    sample_period: u32,
+    sample_timer: T,
+}
+
+impl<T, AnalogPool, DigitalPool, PPI, PPI2> SaadcScan<T, AnalogPool, DigitalPool, PPI, PPI2>
+where
+    Box<AnalogPool>: StaticWriteBuffer<Word = i16>,
+    AnalogPool: Pool<Data = [i16; 2048]> + 'static,
+    DigitalPool: Pool + 'static,
+    PBox<AnalogPool>: Debug,
+    PBox<DigitalPool>: Debug,
+    PPI: ConfigurablePpi,
+    PPI2: ConfigurablePpi,
+    T: timer::Instance,
+{
+    fn saadc(state: &State<Self>) -> &Saadc {
+        match state {
+            State::Idle((saadc, _)) => saadc,
+            State::OnePending(pend) => pend,
+            State::TwoPending(pend) => pend,
+            State::Unstable => unreachable!(),
+        }
+    }
+
+    fn task_start_sampling(&self) -> &TASKS_START {
+        &self.sample_timer.as_timer0().tasks_start
+    }
+
+    fn arm_trigger(&mut self, saadc: &Saadc) {
+        if let Some(cfg) = self.trigger_config {
+            self.trigger.arm();
+            saadc.set_limits(cfg.index, cfg.level, cfg.level);
+            saadc.take_limit_events(cfg.index);
+            let (high, low) = (cfg.edge != Edge::Rising, cfg.edge != Edge::Falling);
+            saadc.set_limit_interrupts(cfg.index, high, low);
+            self.trigger_stage = TriggerStage::Priming;
+        }
+    }
+
+    fn disarm_trigger(&mut self, saadc: &Saadc) {
+        if let Some(cfg) = self.trigger_config {
+            saadc.set_limit_interrupts(cfg.index, false, false);
+        }
+        self.trigger_stage = TriggerStage::Off;
+    }
+
+    /// Handle the limit events of the watched channel. Returns whether there were any.
+    fn poll_trigger(&mut self, saadc: &Saadc, now: u32) -> bool {
+        let cfg = match self.trigger_config {
+            Some(cfg) if self.trigger_stage != TriggerStage::Off => cfg,
+            _ => return false,
+        };
+
+        let (high, low) = saadc.take_limit_events(cfg.index);
+        let below = low && (cfg.edge != Edge::Falling);
+        let above = high && (cfg.edge != Edge::Rising);
+
+        match self.trigger_stage {
+            TriggerStage::Priming if below => {
+                saadc.set_limit_interrupts(cfg.index, true, false);
+                self.trigger_stage = TriggerStage::Crossing { rising: true };
+            }
+            TriggerStage::Priming if above => {
+                saadc.set_limit_interrupts(cfg.index, false, true);
+                self.trigger_stage = TriggerStage::Crossing { rising: false };
+            }
+            TriggerStage::Crossing { rising } if (rising && high) || (!rising && low) => {
+                saadc.set_limit_interrupts(cfg.index, false, false);
+                self.trigger_stage = TriggerStage::Off;
+
+                if self.trigger.fire() {
+                    self.markers.send(InternalReport {
+                        timestamp: now,
+                        seq: 0,
+                        kind: InternalReportKind::Trigger {
+                            source: TriggerSource::Analog {
+                                channel_bitflag: self.bitflag,
+                                index: cfg.index,
+                            },
+                        },
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        high || low
+    }
+}
+
+impl<T, AnalogPool, DigitalPool, PPI, PPI2> Source
+    for SaadcScan<T, AnalogPool, DigitalPool, PPI, PPI2>
+where
+    Box<AnalogPool>: StaticWriteBuffer<Word = i16>,
+    AnalogPool: Pool<Data = [i16; 2048]> + 'static,
+    DigitalPool: Pool + 'static,
+    PBox<AnalogPool>: Debug,
+    PBox<DigitalPool>: Debug,
+    PPI: ConfigurablePpi,
+    PPI2: ConfigurablePpi,
+    T: timer::Instance,
+{
+    type Uninit = Box<AnalogPool, Uninit>;
+    type Block = Scans<AnalogPool>;
+    type Report = InternalReport<DigitalPool, AnalogPool>;
+    type Idle = (Saadc, ScanInputs);
+    type One = AsyncConversion<Scans<AnalogPool>, ScanInputs>;
+    type Two = AsyncPendingConversion<Scans<AnalogPool>, Scans<AnalogPool>, ScanInputs>;
+
+    const NAME: &'static str = "ADCs";
+
+    fn block(&mut self, pbox: Box<AnalogPool, Uninit>) -> Scans<AnalogPool> {
+        // TODO(AJM): this shouldn't be necessary
+        WholeScans::new(pbox.init([0; 2048]), self.config.scan_len())
+    }
+
+    fn report(&mut self, timestamp: u32, seq: u32, block: Scans<AnalogPool>) -> Self::Report {
+        InternalReport {
+            timestamp,
+            seq,
+            kind: InternalReportKind::AnalogReport {
+                channel_bitflag: self.bitflag,
+                payload: block.into_inner(),
+            },
+        }
+    }
+
+    fn lost_index(&self) -> usize {
+        ANALOG_SOURCE
+    }
+
+    fn arm(&mut self, idle: (Saadc, ScanInputs), block: Scans<AnalogPool>) -> Self::One {
+        // Only arms the conversion, samples are not taken until the sample
+        // timer is started by the synchronized start.
+        let (saadc, channels) = idle;
+        saadc.start_async_conversion(channels, block)
+    }
+
+    fn restart(&mut self, idle: (Saadc, ScanInputs), block: Scans<AnalogPool>) -> Self::One {
+        // The sample timer kept running
+        let (saadc, channels) = idle;
+        saadc.start_async_conversion(channels, block)
+    }
+
+    fn is_done(&mut self, one: &mut Self::One) -> bool {
+        one.is_done()
+    }
+
+    fn clear_started(&mut self, one: &mut Self::One) {
+        one.event_started().reset();
+    }
+
+    fn wait(&mut self, one: Self::One) -> ((Saadc, ScanInputs), Scans<AnalogPool>) {
+        let (saadc, rxb, channels) = one.wait();
+        ((saadc, channels), rxb)
+    }
+
+    fn enqueue(&mut self, one: Self::One, block: Scans<AnalogPool>) -> Self::Two {
+        // Enable end-to-start shortcut (using PPI)
+        self.ppi.enable();
+
+        one.enqueue_next_transfer(block).map_err(drop).unwrap()
+    }
+
+    fn exchange(&mut self, two: Self::Two) -> (Self::One, Scans<AnalogPool>) {
+        assert!(two.is_done());
+        let (conv, buffer) = two.wait();
+
+        // Disable end-to-start shortcut (using PPI)
+        self.ppi.disable();
+
+        (conv, buffer)
+    }
+
+    fn started(&mut self, idle: &mut (Saadc, ScanInputs), now: u32) {
+        // Let the host know how to interpret the following blocks
+        self.markers.send(InternalReport {
+            timestamp: now,
+            seq: 0,
+            kind: InternalReportKind::AnalogSettings {
+                channel_bitflag: self.bitflag,
+                settings: AnalogSettings {
+                    config: self.config,
+                },
+            },
+        });
+
+        self.arm_trigger(&idle.0);
+    }
+
+    fn halted(&mut self, _idle: &mut (Saadc, ScanInputs)) {
+        let regs = self.sample_timer.as_timer0();
+        regs.tasks_stop.write(|w| unsafe { w.bits(1) });
+        regs.tasks_clear.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn stopped(&mut self, idle: &mut (Saadc, ScanInputs)) {
+        self.disarm_trigger(&idle.0);
+    }
+
+    fn pause_requested(&self) -> bool {
+        self.recal_temp.is_some()
+    }
+
+    fn paused(&mut self, idle: &mut (Saadc, ScanInputs)) {
+        idle.0.calibrate();
+
+        self.markers.send(InternalReport {
+            timestamp: GlobalRollingTimer.get_ticks(),
+            seq: 0,
+            kind: InternalReportKind::AnalogCalibrated {
+                channel_bitflag: self.bitflag,
+                temperature: self.recal_temp.take().unwrap_or(0),
+            },
+        });
+    }
+
+    fn resume(&mut self) {
+        self.task_start_sampling().write(|w| unsafe { w.bits(1) });
+    }
 }
 
-impl<C, T, AnalogPool, DigitalPool, PPI, PPI2, const N: usize>
-    SaadcSrc<C, T, AnalogPool, DigitalPool, PPI, PPI2, N>
+pub struct SaadcSrc<T, AnalogPool, DigitalPool, PPI, PPI2, const N: usize>
+where
+    Box<AnalogPool>: StaticWriteBuffer<Word = i16>,
+    AnalogPool: Pool<Data = [i16; 2048]> + 'static,
+    DigitalPool: Pool + 'static,
+    PBox<AnalogPool>: Debug,
+    PBox<DigitalPool>: Debug,
+    PPI: ConfigurablePpi,
+    PPI2: ConfigurablePpi,
+    T: timer::Instance,
+{
+    core: DoubleBuffer<
+        SaadcScan<T, AnalogPool, DigitalPool, PPI, PPI2>,
+        AnalogPool,
+        DigitalPool,
+        AnalogPool,
+        N,
+    >,
+    pins: AnalogPins,
+}
+
+impl<T, AnalogPool, DigitalPool, PPI, PPI2, const N: usize>
+    SaadcSrc<T, AnalogPool, DigitalPool, PPI, PPI2, N>
 where
     Box<AnalogPool>: StaticWriteBuffer<Word = i16>,
     AnalogPool: Pool<Data = [i16; 2048]> + 'static,
@@ -81,174 +399,151 @@ where
     PBox<DigitalPool>: Debug,
     PPI: ConfigurablePpi,
     PPI2: ConfigurablePpi,
-    C: Channels,
     T: timer::Instance,
 {
     pub fn new(
         peripheral: SAADC,
         timer: T,
-        channels: C,
+        pins: AnalogPins,
         mut ppi: PPI,
         mut ppi2: PPI2,
         queue: &'static MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
+        markers: &'static MarkerQueue<InternalReport<DigitalPool, AnalogPool>>,
+        trigger: &'static Trigger,
+        overflow: &'static Overflow,
     ) -> Self {
         peripheral
             .intenset
             .modify(|_r, w| w.stopped().set_bit().end().set_bit().started().set_bit());
 
-        let bitflag = channels.bitflag();
-
-        let saadc = Saadc::new(
-            peripheral,
-            SaadcConfig {
-                // Configure for fastest conversion time.
-                oversample: Oversample::BYPASS,
-                time: Time::_3US,
-                ..Default::default()
-            },
-        );
+        // Replaced by `apply_config` below
+        let mut saadc = Saadc::new(peripheral, SaadcConfig::default());
 
         ppi.set_event_endpoint(saadc.event_end());
         ppi.set_task_endpoint(saadc.task_start());
 
-        let sample_timer = Timer::periodic(timer);
+        // The sample timer is only configured here. It is started through
+        // `task_start_sampling`, together with all other sources.
+        let regs = timer.as_timer0();
+        regs.tasks_stop.write(|w| unsafe { w.bits(1) });
+        regs.tasks_clear.write(|w| unsafe { w.bits(1) });
+        regs.mode.write(|w| w.mode().timer());
+        regs.bitmode.write(|w| w.bitmode()._32bit());
+        regs.prescaler.write(|w| unsafe { w.prescaler().bits(4) }); // 1 MHz
+        regs.shorts.write(|w| w.compare0_clear().enabled());
 
-        ppi2.set_event_endpoint(sample_timer.event_compare_cc0());
+        ppi2.set_event_endpoint(&regs.events_compare[0]);
         ppi2.set_task_endpoint(saadc.task_sample());
         ppi2.enable();
 
-        Self {
-            state: State::Idle(saadc, channels),
-            pool_q: queue,
-            last_start: 0,
+        let config = AnalogConfig::default();
+        assert!(config.is_valid());
+        apply_config(&mut saadc, &timer, &config);
+        let channels = scan_inputs(&config);
+
+        let scan = SaadcScan {
+            markers,
+            bitflag: channels.bitflag(),
+            config,
+            recal_temp: None,
+            trigger,
+            trigger_config: None,
+            trigger_stage: TriggerStage::Off,
             ppi,
             ppi2,
-            bitflag,
-            sample_timer,
-            // 200 kHz -> 5 µs sample interval
-            sample_period: 5,
+            sample_timer: timer,
+        };
+
+        Self {
+            core: DoubleBuffer::new(
+                scan,
+                (saadc, channels),
+                Blocks::new(queue, overflow),
+                queue,
+                GlobalRollingTimer::new(),
+            ),
+            pins,
         }
     }
 
-    pub fn poll(&mut self, fuse: &AtomicBool) {
-        // TODO: removeme
-        self.state.saadc().event_stopped().reset();
-
-        let fuse_blown = fuse.load(Ordering::SeqCst);
-        let old_state = self.state.take();
-        let new_state = match old_state {
-            State::Idle(p, c) if fuse_blown => {
-                // Nothing to do here
-                State::Idle(p, c)
-            }
-            State::Idle(p, c) => {
-                if let Some(pbox) = AnalogPool::alloc() {
-                    // TODO(AJM): this shouldn't be necessary
-                    let pbox = pbox.init([0; 2048]);
-                    let pend = p.start_async_conversion(c, pbox);
-                    self.last_start = GlobalRollingTimer.get_ticks();
-                    self.sample_timer.start(self.sample_period);
-                    State::OnePending(pend)
-                } else {
-                    // No data available! Blow the fuse.
-                    defmt::error!("ADCs: Blowing fuse idle-to-one transition");
-                    fuse.store(true, Ordering::SeqCst);
-                    State::Idle(p, c)
-                }
-            }
-            State::OnePending(ts) if fuse_blown => {
-                // Manually clear the started event, as we won't be
-                // clearing/processing it for the second queued write
-                ts.event_started().reset();
-
-                // We shouldn't enqueue a new transfer, the fuse is blown.
-                //
-                // Attempt to finish any current transfer though, since we already
-                // have the alloc page
-                if ts.is_done() {
-                    let (p, rxb, c) = ts.wait();
-
-                    /*let elapsed = GlobalRollingTimer.ticks_since(self.last_start);
-                    let delta = (EXPECTED_TICKS as i32) - (elapsed as i32);
-                    if (delta < -ACCEPTABLE_DELTA) || (delta > ACCEPTABLE_DELTA) {
-                        defmt::warn!("saadc deviation: {}", delta);
-                    }*/
-
-                    let rpt = InternalReport {
-                        timestamp: 0x05060708, // TODO
-                        kind: crate::InternalReportKind::AnalogReport {
-                            channel_bitflag: self.bitflag,
-                            payload: rxb,
-                        },
-                    };
-
-                    if let Ok(()) = self.pool_q.enqueue(rpt) {
-                        // defmt::info!("Sent box!");
-                    } else {
-                        defmt::warn!("Failed to send box!");
-                    }
-
-                    self.sample_timer.cancel().unwrap();
-                    State::Idle(p, c)
-                } else {
-                    // Not ready yet
-                    State::OnePending(ts)
+    /// Change the settings and inputs of the analog channels. Only possible
+    /// while no capture is running, if the board provides the inputs, and a
+    /// scan over all channels fits into the sample period.
+    pub fn set_config(&mut self, config: AnalogConfig) -> Result<(), ()> {
+        let pins = &self.pins;
+        let provided = config.inputs.iter().flatten().all(|input| {
+            pins.provides(input.positive) && input.negative.map_or(true, |n| pins.provides(n))
+        });
+        if !config.is_valid() || !provided {
+            return Err(());
+        }
+
+        match self.core.parts() {
+            (scan, State::Idle((saadc, channels))) => {
+                // A trigger on a channel that is no longer scanned
+                if scan
+                    .trigger_config
+                    .map_or(false, |cfg| usize::from(cfg.index) >= config.scan_len())
+                {
+                    return Err(());
                 }
+                apply_config(saadc, &scan.sample_timer, &config);
+                *channels = scan_inputs(&config);
+                scan.bitflag = channels.bitflag();
+                scan.config = config;
+                Ok(())
             }
-            State::OnePending(ts) => {
-                if let Some(pbox) = AnalogPool::alloc() {
-                    // TODO(AJM): this shouldn't be necessary
-                    let pbox = pbox.init([0; 2048]);
-
-                    // Enable end-to-start shortcut (using PPI)
-                    self.ppi.enable();
-
-                    let pend = ts.enqueue_next_transfer(pbox).map_err(drop).unwrap();
-
-                    State::TwoPending(pend)
-                } else {
-                    // No data available! Blow the fuse.
-                    defmt::error!("ADCs: Blowing fuse one-to-two transition");
-                    fuse.store(true, Ordering::SeqCst);
-                    State::OnePending(ts)
+            _ => Err(()),
+        }
+    }
+
+    /// Task starting the sample timer, and with it the conversions.
+    pub fn task_start_sampling(&self) -> &TASKS_START {
+        self.core.source().task_start_sampling()
+    }
+
+    /// Set or clear the trigger. Only possible while no capture is running.
+    pub fn set_trigger(&mut self, config: Option<AnalogTrigger>) -> Result<(), ()> {
+        match self.core.parts() {
+            (scan, State::Idle(..)) => {
+                if config.map_or(false, |cfg| {
+                    usize::from(cfg.index) >= scan.config.scan_len()
+                }) {
+                    return Err(());
                 }
+                scan.trigger_config = config;
+                Ok(())
             }
-            State::TwoPending(pend) => {
-                assert!(pend.is_done());
-                let (conv, buffer) = pend.wait();
-
-                /*let elapsed = GlobalRollingTimer.ticks_since(self.last_start);
-                let delta = (EXPECTED_TICKS as i32) - (elapsed as i32);
-                if (delta < -ACCEPTABLE_DELTA) || (delta > ACCEPTABLE_DELTA) {
-                    defmt::warn!("saadc deviation: {}", delta);
-                }*/
-
-                self.last_start = GlobalRollingTimer.get_ticks();
-
-                // Disable end-to-start shortcut (using PPI)
-                self.ppi.disable();
-
-                let rpt = InternalReport {
-                    timestamp: 0x05060708, // TODO
-                    kind: crate::InternalReportKind::AnalogReport {
-                        channel_bitflag: self.bitflag,
-                        payload: buffer,
-                    },
-                };
-
-                if let Ok(()) = self.pool_q.enqueue(rpt) {
-                    // defmt::info!("Sent box!");
-                } else {
-                    defmt::warn!("Failed to send box!");
-                }
+            _ => Err(()),
+        }
+    }
 
-                State::OnePending(conv)
-            }
-            State::Invalid => {
-                defmt::panic!("SAADC: encountered invalid state");
+    /// Recalibrate the offset after the current block, pausing the capture
+    /// briefly. `temperature` is reported along with the calibration.
+    pub fn request_recalibration(&mut self, temperature: i32) {
+        self.core.parts().0.recal_temp = Some(temperature);
+    }
+
+    pub fn poll(&mut self, fuse: &AtomicBool) {
+        // Take the timestamp as early as possible, see `DoubleBuffer::poll`
+        let now = GlobalRollingTimer.get_ticks();
+
+        let (scan, state) = self.core.parts();
+        let saadc = SaadcScan::saadc(state);
+
+        // TODO: removeme
+        saadc.event_stopped().reset();
+
+        // Limit events share the interrupt, don't touch the conversions if
+        // they were the only reason we got here.
+        if scan.poll_trigger(saadc, now) {
+            let started = saadc.event_started().read().bits() != 0;
+            let ended = saadc.event_end().read().bits() != 0;
+            if !started && !ended {
+                return;
             }
-        };
+        }
 
-        self.state = new_state;
+        self.core.poll(fuse, now);
     }
 }
@@ -0,0 +1,99 @@
+//! Glue between the capture sources and the `double-buffer` crate, which
+//! holds the double buffering state machine they share.
+
+use core::{fmt::Debug, marker::PhantomData};
+
+use diegesis_icd::OverflowPolicy;
+use double_buffer::{BlockPool, Clock};
+use groundhog::RollingTimer;
+use heapless::{
+    mpmc::MpMcQueue,
+    pool::{
+        singleton::{Box, Pool},
+        Uninit,
+    },
+};
+
+use crate::{
+    groundhog_nrf52::GlobalRollingTimer, overflow::Overflow, sync_start::START_TICK_CC,
+    InternalReport, PBox,
+};
+
+pub use double_buffer::{Source, State};
+
+/// The double buffering core of a source of `P` blocks.
+pub type DoubleBuffer<S, P, DigitalPool, AnalogPool, const N: usize> = double_buffer::DoubleBuffer<
+    S,
+    Blocks<P, DigitalPool, AnalogPool, N>,
+    &'static MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
+    GlobalRollingTimer,
+>;
+
+/// Blocks from the pool `P`, following the overflow policy once it runs dry.
+pub struct Blocks<P, DigitalPool, AnalogPool, const N: usize>
+where
+    DigitalPool: Pool + 'static,
+    AnalogPool: Pool + 'static,
+    PBox<DigitalPool>: Debug,
+    PBox<AnalogPool>: Debug,
+{
+    pool_q: &'static MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
+    overflow: &'static Overflow,
+    _pool: PhantomData<P>,
+}
+
+impl<P, DigitalPool, AnalogPool, const N: usize> Blocks<P, DigitalPool, AnalogPool, N>
+where
+    DigitalPool: Pool + 'static,
+    AnalogPool: Pool + 'static,
+    PBox<DigitalPool>: Debug,
+    PBox<AnalogPool>: Debug,
+{
+    pub fn new(
+        pool_q: &'static MpMcQueue<InternalReport<DigitalPool, AnalogPool>, N>,
+        overflow: &'static Overflow,
+    ) -> Self {
+        Self {
+            pool_q,
+            overflow,
+            _pool: PhantomData,
+        }
+    }
+}
+
+impl<P, DigitalPool, AnalogPool, const N: usize> BlockPool for Blocks<P, DigitalPool, AnalogPool, N>
+where
+    P: Pool + 'static,
+    DigitalPool: Pool + 'static,
+    AnalogPool: Pool + 'static,
+    PBox<DigitalPool>: Debug,
+    PBox<AnalogPool>: Debug,
+{
+    type Uninit = Box<P, Uninit>;
+
+    fn alloc(&self) -> Option<Box<P, Uninit>> {
+        P::alloc()
+    }
+
+    fn alloc_next(&self) -> Option<Box<P, Uninit>> {
+        self.overflow.alloc::<P, _, _, N>(self.pool_q)
+    }
+
+    fn policy(&self) -> OverflowPolicy {
+        self.overflow.policy()
+    }
+
+    fn count_lost(&self, source: usize) {
+        self.overflow.count_lost(source)
+    }
+}
+
+impl Clock for GlobalRollingTimer {
+    fn now(&self) -> u32 {
+        self.get_ticks()
+    }
+
+    fn start_tick(&self) -> u32 {
+        GlobalRollingTimer::captured_ticks(START_TICK_CC)
+    }
+}
@@ -1,51 +1,40 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::{
+    fmt::Debug,
+    marker::PhantomData,
+    sync::atomic::AtomicBool,
+};
 
-use crate::{groundhog_nrf52::GlobalRollingTimer, InternalReport, NopSlice};
+use crate::{
+    groundhog_nrf52::GlobalRollingTimer,
+    overflow::Overflow,
+    source::{Blocks, DoubleBuffer, Source, State},
+    InternalReport, InternalReportKind, NopSlice,
+};
 use nrf52840_hal::{
     gpio::{Level, Pin},
     pac::{spim0, SPIM0, SPIM1, SPIM2, SPIM3},
     spim::{Frequency, Instance, PendingSplit, Pins, TransferSplit},
     Spim,
 };
+use diegesis_icd::DigitalRate;
 
 use embedded_dma::WriteBuffer;
 use embedded_hal::spi::MODE_0;
 use groundhog::RollingTimer;
-use heapless::{mpmc::MpMcQueue, pool::Init};
-
-type PBox<POOL> = heapless::pool::singleton::Box<POOL, Init>;
-
-pub enum SpimPeriph<S, POOL>
-where
-    S: Instance + Shame + Send,
-    POOL: heapless::pool::singleton::Pool + 'static,
-    PBox<POOL>: WriteBuffer<Word = u8>,
-{
-    Idle(Spim<S>),
-    OnePending(TransferSplit<S, NopSlice, PBox<POOL>>),
-    TwoPending {
-        transfer: TransferSplit<S, NopSlice, PBox<POOL>>,
-        pending: PendingSplit<S, NopSlice, PBox<POOL>>,
+use heapless::{
+    mpmc::MpMcQueue,
+    pool::{
+        singleton::{Box, Pool},
+        Init, Uninit,
     },
-    Unstable,
-}
+};
 
-//
-
-impl<S, POOL> SpimPeriph<S, POOL>
-where
-    S: Instance + Shame + Send,
-    POOL: heapless::pool::singleton::Pool + 'static,
-    PBox<POOL>: WriteBuffer<Word = u8>,
-{
-    fn take(&mut self) -> Self {
-        let mut new = SpimPeriph::Unstable;
-        core::mem::swap(self, &mut new);
-        new
-    }
-}
+type PBox<POOL> = Box<POOL, Init>;
 
 pub trait Shame {
+    /// Highest SCK frequency supported by this instance, in Hz.
+    const MAX_HZ: u32 = 8_000_000;
+
     fn shame_ptr() -> *const spim0::RegisterBlock;
 }
 
@@ -68,55 +57,210 @@ impl Shame for SPIM2 {
 }
 
 impl Shame for SPIM3 {
+    const MAX_HZ: u32 = 32_000_000;
+
     fn shame_ptr() -> *const spim0::RegisterBlock {
         SPIM3::ptr()
     }
 }
 
-// (4_000_000 ticks/s) / (2_000_000 bps / 8 bit-per-byte / 4096 byte-per-box)
-// const EXPECTED_TICKS: u32 = 65536;
-const EXPECTED_TICKS: u32 = 65536 + (655 * 2);
+pub fn frequency_hz(freq: Frequency) -> u32 {
+    match freq {
+        Frequency::K125 => 125_000,
+        Frequency::K250 => 250_000,
+        Frequency::K500 => 500_000,
+        Frequency::M1 => 1_000_000,
+        Frequency::M2 => 2_000_000,
+        Frequency::M4 => 4_000_000,
+        Frequency::M8 => 8_000_000,
+        Frequency::M16 => 16_000_000,
+        Frequency::M32 => 32_000_000,
+    }
+}
+
+pub fn frequency_from_rate(rate: DigitalRate) -> Frequency {
+    match rate {
+        DigitalRate::K125 => Frequency::K125,
+        DigitalRate::K250 => Frequency::K250,
+        DigitalRate::K500 => Frequency::K500,
+        DigitalRate::M1 => Frequency::M1,
+        DigitalRate::M2 => Frequency::M2,
+        DigitalRate::M4 => Frequency::M4,
+        DigitalRate::M8 => Frequency::M8,
+        DigitalRate::M16 => Frequency::M16,
+        DigitalRate::M32 => Frequency::M32,
+    }
+}
+
+/// Ticks after which a block is considered late, at the given frequency.
+///
+/// (4_000_000 ticks/s) / (freq bps / 8 bit-per-byte / 4096 byte-per-box),
+/// plus 2% for the gaps the SPIM inserts between bytes.
+fn expected_ticks(freq: Frequency) -> u32 {
+    let nominal = ((4_000_000u64 * 8 * 4096) / u64::from(frequency_hz(freq))) as u32;
+    nominal + (nominal / 50)
+}
+
+/// One digital channel, sampled as the MISO line of a SPIM.
+pub struct SpimChannel<T, POOL, OtherPool> {
+    channel: u8,
+    expected_ticks: u32,
+    _periph: PhantomData<(T, POOL, OtherPool)>,
+}
+
+impl<T, POOL, OtherPool> Source for SpimChannel<T, POOL, OtherPool>
+where
+    T: Instance + Shame + Send,
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: WriteBuffer<Word = u8>,
+    PBox<POOL>: Debug,
+    PBox<OtherPool>: Debug,
+{
+    type Uninit = Box<POOL, Uninit>;
+    type Block = PBox<POOL>;
+    type Report = InternalReport<POOL, OtherPool>;
+    type Idle = Spim<T>;
+    type One = TransferSplit<T, NopSlice, PBox<POOL>>;
+    type Two = (
+        TransferSplit<T, NopSlice, PBox<POOL>>,
+        PendingSplit<T, NopSlice, PBox<POOL>>,
+    );
+
+    const NAME: &'static str = "SPIM";
+
+    fn block(&mut self, pbox: Box<POOL, Uninit>) -> PBox<POOL> {
+        pbox.freeze()
+    }
+
+    fn report(&mut self, timestamp: u32, seq: u32, block: PBox<POOL>) -> Self::Report {
+        InternalReport {
+            timestamp,
+            seq,
+            kind: InternalReportKind::DigitalReport {
+                channel: self.channel,
+                payload: block,
+            },
+        }
+    }
 
-use core::fmt::Debug;
+    fn lost_index(&self) -> usize {
+        self.channel as usize
+    }
+
+    fn expected_ticks(&self) -> Option<u32> {
+        Some(self.expected_ticks)
+    }
+
+    fn arm(&mut self, idle: Spim<T>, block: PBox<POOL>) -> Self::One {
+        // Only arm the transfer. Tasks are ignored while the peripheral is
+        // disabled, so the START from the HAL is dropped, and the
+        // synchronized start begins the transfer.
+        let regs = unsafe { &*T::shame_ptr() };
+        regs.enable.write(|w| w.enable().disabled());
+        let txfr = idle.dma_transfer_split(NopSlice, block).map_err(drop).unwrap();
+        regs.enable.write(|w| w.enable().enabled());
+        txfr
+    }
+
+    fn restart(&mut self, idle: Spim<T>, block: PBox<POOL>) -> Self::One {
+        idle.dma_transfer_split(NopSlice, block).map_err(drop).unwrap()
+    }
+
+    fn is_done(&mut self, one: &mut Self::One) -> bool {
+        one.is_done()
+    }
+
+    fn clear_started(&mut self, _one: &mut Self::One) {
+        unsafe {
+            (&*T::shame_ptr())
+                .events_started
+                .write(|w| w.events_started().clear_bit());
+        }
+    }
+
+    fn wait(&mut self, one: Self::One) -> (Spim<T>, PBox<POOL>) {
+        let (_txb, rxb, p) = one.wait();
+        (p, rxb)
+    }
+
+    fn enqueue(&mut self, mut one: Self::One, block: PBox<POOL>) -> Self::Two {
+        // Enable end-to-start shortcut
+        unsafe {
+            (&*T::shame_ptr())
+                .shorts
+                .modify(|_r, w| w.end_start().set_bit());
+        }
+
+        let pending = one
+            .enqueue_next_transfer(NopSlice, block)
+            .map_err(drop)
+            .unwrap();
+        (one, pending)
+    }
+
+    fn exchange(&mut self, two: Self::Two) -> (Self::One, PBox<POOL>) {
+        let (mut transfer, pending) = two;
+        assert!(transfer.is_done());
+        let (_txb, rxb, one) = transfer.exchange_transfer_wait(pending);
+
+        // Disable end-to-start shortcut
+        unsafe {
+            (&*T::shame_ptr())
+                .shorts
+                .modify(|_r, w| w.end_start().clear_bit());
+        }
+
+        (one, rxb)
+    }
+}
 
 pub struct SpimSrc<T, POOL, OtherPool, const N: usize>
 where
     T: Instance + Shame + Send,
-    POOL: heapless::pool::singleton::Pool + 'static,
-    OtherPool: heapless::pool::singleton::Pool + 'static,
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
     PBox<POOL>: WriteBuffer<Word = u8>,
     PBox<POOL>: Debug,
     PBox<OtherPool>: Debug,
 {
-    periph: SpimPeriph<T, POOL>,
-    pool_q: &'static MpMcQueue<InternalReport<POOL, OtherPool>, N>,
-    last_start: u32,
+    core: DoubleBuffer<SpimChannel<T, POOL, OtherPool>, POOL, POOL, OtherPool, N>,
     timer: GlobalRollingTimer,
-    channel: u8,
 }
 
 impl<T, POOL, OtherPool, const N: usize> SpimSrc<T, POOL, OtherPool, N>
 where
     T: Instance + Shame + Send,
-    POOL: heapless::pool::singleton::Pool + 'static,
-    OtherPool: heapless::pool::singleton::Pool + 'static,
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
     PBox<POOL>: WriteBuffer<Word = u8>,
-    <POOL as heapless::pool::singleton::Pool>::Data: AsRef<[u8]>,
+    <POOL as Pool>::Data: AsRef<[u8]>,
     PBox<POOL>: Debug,
     PBox<OtherPool>: Debug,
 {
     pub fn new(
-        periph: SpimPeriph<T, POOL>,
+        spim: Spim<T>,
         pool_q: &'static MpMcQueue<InternalReport<POOL, OtherPool>, N>,
+        freq: Frequency,
         timer: GlobalRollingTimer,
         channel: u8,
+        overflow: &'static Overflow,
     ) -> Self {
+        let source = SpimChannel {
+            channel,
+            expected_ticks: expected_ticks(freq),
+            _periph: PhantomData,
+        };
+
         Self {
-            periph,
-            pool_q,
-            last_start: 0,
+            core: DoubleBuffer::new(
+                source,
+                spim,
+                Blocks::new(pool_q, overflow),
+                pool_q,
+                timer.clone(),
+            ),
             timer,
-            channel,
         }
     }
 
@@ -128,6 +272,7 @@ where
         freq: Frequency,
         timer: GlobalRollingTimer,
         channel: u8,
+        overflow: &'static Overflow,
     ) -> Self {
         let pins = Pins {
             sck: disc_pin.into_push_pull_output(Level::Low),
@@ -140,150 +285,59 @@ where
             .intenset
             .modify(|_r, w| w.stopped().set_bit().end().set_bit().started().set_bit());
 
-        // todo: calculate expected ticks automatically
-        assert_eq!(freq, Frequency::M2, "TODO: UPDATE EXPECTED TICKS");
+        assert!(frequency_hz(freq) <= T::MAX_HZ, "Unsupported SPIM frequency");
 
         let spim = Spim::new(periph, pins, freq, MODE_0, 0x00);
-        let spim_p = SpimPeriph::Idle(spim);
-        SpimSrc::new(spim_p, pool_q, timer, channel)
+        SpimSrc::new(spim, pool_q, freq, timer, channel, overflow)
     }
 
-    pub fn poll(&mut self, fuse: &AtomicBool) {
-        // TODO: removeme
-        unsafe {
-            (&*T::shame_ptr())
-                .events_stopped
-                .write(|w| w.events_stopped().clear_bit());
+    /// Change the sample rate. Only possible while no capture is running.
+    pub fn set_frequency(&mut self, freq: Frequency) -> Result<(), ()> {
+        if frequency_hz(freq) > T::MAX_HZ {
+            return Err(());
         }
 
-        let fuse_blown = fuse.load(Ordering::SeqCst);
-        let new_state = match self.periph.take() {
-            SpimPeriph::Idle(p) if fuse_blown => {
-                // Nothing to do here
-                SpimPeriph::Idle(p)
-            }
-            SpimPeriph::Idle(p) => {
-                if let Some(pbox) = POOL::alloc() {
-                    let pbox = pbox.freeze();
-                    let txfr = p.dma_transfer_split(NopSlice, pbox).map_err(drop).unwrap();
-                    self.last_start = self.timer.get_ticks();
-                    SpimPeriph::OnePending(txfr)
-                } else {
-                    // No data available! Blow the fuse.
-                    defmt::error!("SPIM: Blowing fuse idle-to-one transition");
-                    fuse.store(true, Ordering::SeqCst);
-                    SpimPeriph::Idle(p)
-                }
-            }
-            SpimPeriph::OnePending(mut ts) if fuse_blown => {
-                // Manually clear the started event, as we won't be
-                // clearing/processing it for the second queued write
+        match self.core.parts() {
+            (source, State::Idle(_)) => {
                 unsafe {
                     (&*T::shame_ptr())
-                        .events_started
-                        .write(|w| w.events_started().clear_bit());
-                }
-
-                // We shouldn't enqueue a new transfer, the fuse is blown.
-                //
-                // Attempt to finish any current transfer though, since we already
-                // have the alloc page
-                if ts.is_done() {
-                    let (_txb, rxb, p) = ts.wait();
-
-                    let elapsed = self.timer.ticks_since(self.last_start);
-                    if elapsed > EXPECTED_TICKS {
-                        defmt::warn!("spi deviation: {} elapsed!", elapsed);
-                    }
-                    self.last_start = self.timer.get_ticks();
-
-                    let rpt = InternalReport {
-                        timestamp: 0x01020304,
-                        kind: crate::InternalReportKind::DigitalReport {
-                            channel: self.channel,
-                            payload: rxb,
-                        },
-                    };
-
-                    if let Ok(()) = self.pool_q.enqueue(rpt) {
-                        // defmt::info!("Sent box!");
-                    } else {
-                        defmt::warn!("Failed to send box!");
-                    }
-                    SpimPeriph::Idle(p)
-                } else {
-                    // Not ready yet
-                    SpimPeriph::OnePending(ts)
+                        .frequency
+                        .write(|w| w.frequency().variant(freq));
                 }
+                source.expected_ticks = expected_ticks(freq);
+                Ok(())
             }
-            SpimPeriph::OnePending(mut ts) => {
-                if let Some(pbox) = POOL::alloc() {
-                    let pbox = pbox.freeze();
-
-                    // Enable end-to-start shortcut
-                    unsafe {
-                        (&*T::shame_ptr())
-                            .shorts
-                            .modify(|_r, w| w.end_start().set_bit());
-                    }
-
-                    let p_txfr = ts
-                        .enqueue_next_transfer(NopSlice, pbox)
-                        .map_err(drop)
-                        .unwrap();
-
-                    SpimPeriph::TwoPending {
-                        transfer: ts,
-                        pending: p_txfr,
-                    }
-                } else {
-                    // No data available! Blow the fuse.
-                    defmt::error!("SPIM: Blowing fuse one-to-two transition");
-                    fuse.store(true, Ordering::SeqCst);
-                    SpimPeriph::OnePending(ts)
-                }
-            }
-            SpimPeriph::TwoPending {
-                mut transfer,
-                pending,
-            } => {
-                assert!(transfer.is_done());
-                let (_txb, rxb, one) = transfer.exchange_transfer_wait(pending);
-
-                let elapsed = self.timer.ticks_since(self.last_start);
-                if elapsed >= EXPECTED_TICKS {
-                    defmt::warn!("spi deviation: {} elapsed!", elapsed);
-                }
-                self.last_start = self.timer.get_ticks();
+            _ => Err(()),
+        }
+    }
 
-                // Disable end-to-start shortcut
-                unsafe {
-                    (&*T::shame_ptr())
-                        .shorts
-                        .modify(|_r, w| w.end_start().clear_bit());
-                }
+    /// Sample another pin, given as PSEL bits. Only possible while no
+    /// capture is running.
+    pub fn set_data_pin(&mut self, psel: u32) -> Result<(), ()> {
+        match self.core.parts() {
+            (_source, State::Idle(_)) => {
+                // PSEL may only be changed while the peripheral is disabled
+                let regs = unsafe { &*T::shame_ptr() };
+                regs.enable.write(|w| w.enable().disabled());
+                regs.psel.miso.write(|w| unsafe { w.bits(psel) });
+                regs.enable.write(|w| w.enable().enabled());
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
 
-                let rpt = InternalReport {
-                    timestamp: 0x01020304,
-                    kind: crate::InternalReportKind::DigitalReport {
-                        channel: self.channel,
-                        payload: rxb,
-                    },
-                };
-
-                if let Ok(()) = self.pool_q.enqueue(rpt) {
-                    // defmt::info!("Sent box!");
-                } else {
-                    defmt::warn!("Failed to send box!");
-                }
+    pub fn poll(&mut self, fuse: &AtomicBool) {
+        // Take the timestamp as early as possible, see `DoubleBuffer::poll`
+        let now = self.timer.get_ticks();
 
-                SpimPeriph::OnePending(one)
-            }
-            SpimPeriph::Unstable => {
-                defmt::panic!("SPIM Error!");
-            }
-        };
+        // TODO: removeme
+        unsafe {
+            (&*T::shame_ptr())
+                .events_stopped
+                .write(|w| w.events_stopped().clear_bit());
+        }
 
-        self.periph = new_state;
+        self.core.poll(fuse, now);
     }
 }
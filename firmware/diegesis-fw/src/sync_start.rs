@@ -0,0 +1,125 @@
+//! Starts all capture sources at the same instant.
+//!
+//! Each source arms its peripheral without starting it. A single EGU event
+//! then triggers the START tasks of all of them through PPI, and latches the
+//! current tick of the global timer at the same time.
+
+use nrf52840_hal::{
+    pac::{
+        egu0::{EVENTS_TRIGGERED, TASKS_TRIGGER},
+        timer0::TASKS_START,
+        EGU0, PPI, SPIM0, SPIM1, SPIM2, SPIM3,
+    },
+    ppi::ConfigurablePpi,
+};
+
+use crate::{groundhog_nrf52::GlobalRollingTimer, spim_src::Shame};
+
+/// Compare register of the global timer holding the tick of the last start.
+pub const START_TICK_CC: usize = 2;
+
+pub struct SyncStart<A, B, C> {
+    egu: EGU0,
+    #[allow(dead_code)]
+    ppis: (A, B, C),
+}
+
+impl<A, B, C> SyncStart<A, B, C>
+where
+    A: ConfigurablePpi,
+    B: ConfigurablePpi,
+    C: ConfigurablePpi,
+{
+    /// `sample_timer_start` is the START task of the timer pacing the SAADC.
+    pub fn new(
+        egu: EGU0,
+        mut ppi_a: A,
+        mut ppi_b: B,
+        mut ppi_c: C,
+        sample_timer_start: &TASKS_START,
+    ) -> Self {
+        let event = &egu.events_triggered[0];
+        let capture = GlobalRollingTimer::task_capture(START_TICK_CC)
+            .expect("Global timer must be initialized first");
+
+        // Each channel has one task plus one fork, so three are needed for
+        // the five starts and the timestamp.
+        unsafe {
+            ppi_a.set_event_endpoint(event);
+            ppi_a.set_task_endpoint(&(*SPIM0::shame_ptr()).tasks_start);
+            ppi_a.set_fork_task_endpoint(&(*SPIM1::shame_ptr()).tasks_start);
+
+            ppi_b.set_event_endpoint(event);
+            ppi_b.set_task_endpoint(&(*SPIM2::shame_ptr()).tasks_start);
+            ppi_b.set_fork_task_endpoint(&(*SPIM3::shame_ptr()).tasks_start);
+        }
+
+        ppi_c.set_event_endpoint(event);
+        ppi_c.set_task_endpoint(sample_timer_start);
+        ppi_c.set_fork_task_endpoint(capture);
+
+        ppi_a.enable();
+        ppi_b.enable();
+        ppi_c.enable();
+
+        Self {
+            egu,
+            ppis: (ppi_a, ppi_b, ppi_c),
+        }
+    }
+
+    /// Forget any previous start.
+    pub fn reset(&self) {
+        self.egu.events_triggered[0].reset();
+    }
+
+    /// Start all armed sources, and return the tick they were started at.
+    pub fn trigger(&self) -> u32 {
+        self.reset();
+        trigger_task().write(|w| unsafe { w.bits(1) });
+
+        // Wait for the event, so the capture has happened before we read it
+        loop {
+            if let Some(tick) = self.poll_started() {
+                return tick;
+            }
+        }
+    }
+
+    /// Returns the start tick once the sources were started, by `trigger` or
+    /// through `trigger_task`.
+    pub fn poll_started(&self) -> Option<u32> {
+        let event = &self.egu.events_triggered[0];
+        if event.read().bits() == 0 {
+            return None;
+        }
+        event.reset();
+        Some(GlobalRollingTimer::captured_ticks(START_TICK_CC))
+    }
+}
+
+/// The task starting all armed sources, e.g. to start them from PPI.
+pub fn trigger_task() -> &'static TASKS_TRIGGER {
+    unsafe { &(*EGU0::ptr()).tasks_trigger[0] }
+}
+
+/// The event starting all armed sources, for sources connecting their
+/// START task through a PPI channel of their own.
+pub fn started_event() -> &'static EVENTS_TRIGGERED {
+    unsafe { &(*EGU0::ptr()).events_triggered[0] }
+}
+
+/// Start another source along with the others, through PPI channel `ch`.
+///
+/// # Safety
+///
+/// The caller must own PPI channel `ch`, and `task` must be the address of
+/// a START task.
+pub unsafe fn connect_start(ch: usize, task: u32) {
+    let ppi = &*PPI::ptr();
+    ppi.ch[ch]
+        .eep
+        .write(|w| w.bits(started_event() as *const _ as u32));
+    ppi.ch[ch].tep.write(|w| w.bits(task));
+    ppi.chenset.write(|w| w.bits(1 << ch));
+}
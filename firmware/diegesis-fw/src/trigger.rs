@@ -0,0 +1,40 @@
+//! Shared state of the capture trigger.
+//!
+//! While a trigger is armed, all sources keep capturing, but sample data is
+//! dropped instead of being sent until one of them fires the trigger.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub struct Trigger {
+    armed: AtomicBool,
+    fired: AtomicBool,
+}
+
+impl Trigger {
+    pub const fn new() -> Self {
+        Self {
+            armed: AtomicBool::new(false),
+            fired: AtomicBool::new(false),
+        }
+    }
+
+    /// Forget the trigger of the last capture.
+    pub fn reset(&self) {
+        self.armed.store(false, Ordering::SeqCst);
+        self.fired.store(false, Ordering::SeqCst);
+    }
+
+    pub fn arm(&self) {
+        self.armed.store(true, Ordering::SeqCst);
+    }
+
+    /// Fire the trigger. Returns whether this was the first source to fire it.
+    pub fn fire(&self) -> bool {
+        self.armed.load(Ordering::SeqCst) && !self.fired.swap(true, Ordering::SeqCst)
+    }
+
+    /// Whether sample data should be sent. Always true without a trigger.
+    pub fn is_streaming(&self) -> bool {
+        !self.armed.load(Ordering::SeqCst) || self.fired.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,438 @@
+//! Receive-only UART sniffers, listening in on the pins of digital channels.
+//!
+//! Each UARTE receives into blocks from the digital pool, double buffered
+//! with the ENDRX-to-STARTRX shortcut like the SPIMs. UART traffic is bursty
+//! and slow, so a block is also ended early once the line went quiet: the
+//! receiver is stopped, which ends the block with what it got so far, and
+//! started again on the next one. A byte arriving in the few microseconds
+//! this takes may be lost.
+//!
+//! The pin stays connected to its SPIM, the UARTE only reads it.
+
+use core::{
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    groundhog_nrf52::GlobalRollingTimer,
+    overflow::{Overflow, UART_SOURCE},
+    source::{Blocks, DoubleBuffer, Source, State},
+    sync_start, InternalReport, InternalReportKind, PBox,
+};
+use diegesis_icd::{UartConfig, UartErrors};
+use nrf52840_hal::{
+    pac::{
+        uarte0::{self, baudrate::BAUDRATE_A},
+        UARTE0, UARTE1,
+    },
+    ppi::{Ppi8, Ppi9},
+};
+
+use groundhog::RollingTimer;
+use heapless::{
+    mpmc::MpMcQueue,
+    pool::{
+        singleton::{Box, Pool},
+        Uninit,
+    },
+};
+
+/// Largest block, RXD.MAXCNT counts bytes.
+const BLOCK_BYTES: u32 = 4096;
+
+/// Frames the line has to stay quiet for before a block is ended early.
+const QUIET_FRAMES: u32 = 4;
+
+/// Shortest quiet time before a block is ended early, 5 ms.
+const MIN_QUIET_TICKS: u32 = 5 * 4_000;
+
+pub trait Port: Deref<Target = uarte0::RegisterBlock> {
+    const PORT: u8;
+
+    /// The PPI channel owned through `Ppi`, starting the port with the others.
+    const PPI_CH: usize;
+    type Ppi;
+}
+
+impl Port for UARTE0 {
+    const PORT: u8 = 0;
+    const PPI_CH: usize = 8;
+    type Ppi = Ppi8;
+}
+
+impl Port for UARTE1 {
+    const PORT: u8 = 1;
+    const PPI_CH: usize = 9;
+    type Ppi = Ppi9;
+}
+
+fn baudrate(baud: u32) -> Option<BAUDRATE_A> {
+    Some(match baud {
+        1200 => BAUDRATE_A::BAUD1200,
+        2400 => BAUDRATE_A::BAUD2400,
+        4800 => BAUDRATE_A::BAUD4800,
+        9600 => BAUDRATE_A::BAUD9600,
+        14400 => BAUDRATE_A::BAUD14400,
+        19200 => BAUDRATE_A::BAUD19200,
+        28800 => BAUDRATE_A::BAUD28800,
+        31250 => BAUDRATE_A::BAUD31250,
+        38400 => BAUDRATE_A::BAUD38400,
+        56000 => BAUDRATE_A::BAUD56000,
+        57600 => BAUDRATE_A::BAUD57600,
+        76800 => BAUDRATE_A::BAUD76800,
+        115200 => BAUDRATE_A::BAUD115200,
+        230400 => BAUDRATE_A::BAUD230400,
+        250000 => BAUDRATE_A::BAUD250000,
+        460800 => BAUDRATE_A::BAUD460800,
+        921600 => BAUDRATE_A::BAUD921600,
+        1000000 => BAUDRATE_A::BAUD1M,
+        _ => return None,
+    })
+}
+
+/// Ticks the line has to stay quiet for before a block is ended early.
+fn quiet_ticks(config: &UartConfig) -> u32 {
+    // Start bit, 8 data bits, optional parity bit, stop bit
+    let bits = if config.parity { 11 } else { 10 };
+    let frame_ticks = (4_000_000 * bits) / config.baudrate;
+    (QUIET_FRAMES * frame_ticks).max(MIN_QUIET_TICKS)
+}
+
+/// Receive errors since they were last taken, see ERRORSRC.
+fn take_errors(uarte: &uarte0::RegisterBlock) -> UartErrors {
+    let bits = uarte.errorsrc.read().bits();
+    // Write one to clear
+    uarte.errorsrc.write(|w| unsafe { w.bits(bits) });
+
+    UartErrors {
+        overrun: bits & (1 << 0) != 0,
+        parity: bits & (1 << 1) != 0,
+        framing: bits & (1 << 2) != 0,
+        line_break: bits & (1 << 3) != 0,
+    }
+}
+
+/// A block, and the bytes received into it once it is done.
+pub struct UartBlock<POOL>
+where
+    POOL: Pool,
+{
+    pbox: PBox<POOL>,
+    len: u16,
+    errors: UartErrors,
+}
+
+/// A block being filled by the UARTE.
+pub struct Transfer<U, POOL>
+where
+    POOL: Pool,
+{
+    uarte: U,
+    block: UartBlock<POOL>,
+}
+
+pub struct UartPort<U, POOL, OtherPool> {
+    /// Tick of the interrupt being handled, the timestamp of its report.
+    now: u32,
+    /// Whether the receiver was stopped to end a block early.
+    flushing: bool,
+    _pools: PhantomData<(U, POOL, OtherPool)>,
+}
+
+impl<U, POOL, OtherPool> UartPort<U, POOL, OtherPool>
+where
+    U: Port,
+    POOL: Pool,
+    PBox<POOL>: DerefMut<Target = [u8; 4096]>,
+{
+    fn set_ptr(uarte: &U, block: &UartBlock<POOL>) {
+        uarte
+            .rxd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(block.pbox.as_ptr() as u32) });
+    }
+
+    /// Fill in what the last transfer received into `block`.
+    fn finish_block(uarte: &U, block: &mut UartBlock<POOL>) {
+        block.len = uarte.rxd.amount.read().bits() as u16;
+        block.errors = take_errors(uarte);
+    }
+}
+
+impl<U, POOL, OtherPool> Source for UartPort<U, POOL, OtherPool>
+where
+    U: Port,
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<OtherPool>: Debug,
+{
+    type Uninit = Box<POOL, Uninit>;
+    type Block = UartBlock<POOL>;
+    type Report = InternalReport<POOL, OtherPool>;
+    type Idle = U;
+    type One = Transfer<U, POOL>;
+    type Two = (Transfer<U, POOL>, UartBlock<POOL>);
+
+    const NAME: &'static str = "UART";
+
+    fn block(&mut self, pbox: Box<POOL, Uninit>) -> UartBlock<POOL> {
+        UartBlock {
+            pbox: pbox.freeze(),
+            len: 0,
+            errors: UartErrors::default(),
+        }
+    }
+
+    fn report(&mut self, _block_start: u32, seq: u32, block: UartBlock<POOL>) -> Self::Report {
+        // Blocks are ended by the data, not by time, so they are stamped
+        // with when they ended rather than when they started
+        InternalReport {
+            timestamp: self.now,
+            seq,
+            kind: InternalReportKind::UartReport {
+                port: U::PORT,
+                errors: block.errors,
+                len: block.len,
+                payload: block.pbox,
+            },
+        }
+    }
+
+    fn lost_index(&self) -> usize {
+        UART_SOURCE + usize::from(U::PORT)
+    }
+
+    fn arm(&mut self, uarte: U, block: UartBlock<POOL>) -> Self::One {
+        // Only arm the transfer, the synchronized start begins it
+        Self::set_ptr(&uarte, &block);
+        take_errors(&uarte);
+        uarte.enable.write(|w| w.enable().enabled());
+        Transfer { uarte, block }
+    }
+
+    fn restart(&mut self, uarte: U, block: UartBlock<POOL>) -> Self::One {
+        let one = self.arm(uarte, block);
+        one.uarte.tasks_startrx.write(|w| unsafe { w.bits(1) });
+        one
+    }
+
+    fn is_done(&mut self, one: &mut Self::One) -> bool {
+        one.uarte.events_endrx.read().bits() != 0
+    }
+
+    fn clear_started(&mut self, one: &mut Self::One) {
+        one.uarte.events_rxstarted.reset();
+    }
+
+    fn wait(&mut self, one: Self::One) -> (U, UartBlock<POOL>) {
+        let Transfer { uarte, mut block } = one;
+
+        // The receiver keeps going after ENDRX, unless it was stopped already
+        if !self.flushing {
+            uarte.tasks_stoprx.write(|w| unsafe { w.bits(1) });
+        }
+        self.flushing = false;
+
+        // RXTO only comes after the last ENDRX
+        while uarte.events_rxto.read().bits() == 0 {}
+        uarte.events_rxto.reset();
+        uarte.events_endrx.reset();
+        uarte.events_rxstarted.reset();
+        Self::finish_block(&uarte, &mut block);
+
+        // Tasks are ignored while disabled, so a later synchronized start
+        // can't restart it with a stale pointer
+        uarte.enable.write(|w| w.enable().disabled());
+
+        (uarte, block)
+    }
+
+    fn enqueue(&mut self, one: Self::One, block: UartBlock<POOL>) -> Self::Two {
+        // Latched once the current block is full, or the receiver was stopped
+        one.uarte.events_rxstarted.reset();
+        Self::set_ptr(&one.uarte, &block);
+        one.uarte.shorts.modify(|_, w| w.endrx_startrx().enabled());
+        (one, block)
+    }
+
+    fn exchange(&mut self, two: Self::Two) -> (Self::One, UartBlock<POOL>) {
+        let (mut current, next) = two;
+        assert!(current.uarte.events_endrx.read().bits() != 0);
+        current.uarte.events_endrx.reset();
+        current
+            .uarte
+            .shorts
+            .modify(|_, w| w.endrx_startrx().disabled());
+
+        if self.flushing {
+            // Stopped early, the shortcut was disabled first. Start the next
+            // block once the receiver has come to a stop.
+            while current.uarte.events_rxto.read().bits() == 0 {}
+            current.uarte.events_rxto.reset();
+            current.uarte.events_endrx.reset();
+            current.uarte.tasks_startrx.write(|w| unsafe { w.bits(1) });
+            self.flushing = false;
+        }
+        Self::finish_block(&current.uarte, &mut current.block);
+
+        let one = Transfer {
+            uarte: current.uarte,
+            block: next,
+        };
+        (one, current.block)
+    }
+}
+
+pub struct UartSrc<U, POOL, OtherPool, const N: usize>
+where
+    U: Port,
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<OtherPool>: Debug,
+{
+    core: DoubleBuffer<UartPort<U, POOL, OtherPool>, POOL, POOL, OtherPool, N>,
+    timer: GlobalRollingTimer,
+    /// PSEL values of the digital channels, see `GpioteTrigger`.
+    pins: [u32; 4],
+    config: Option<UartConfig>,
+    quiet_ticks: u32,
+    /// Tick the receiver was last seen busy, since the last block ended.
+    last_rx: Option<u32>,
+    _ppi: U::Ppi,
+}
+
+impl<U, POOL, OtherPool, const N: usize> UartSrc<U, POOL, OtherPool, N>
+where
+    U: Port,
+    POOL: Pool + 'static,
+    OtherPool: Pool + 'static,
+    PBox<POOL>: Debug + DerefMut<Target = [u8; 4096]>,
+    PBox<OtherPool>: Debug,
+{
+    /// The sniffer starts out disabled, see `set_config`.
+    pub fn from_parts(
+        uarte: U,
+        ppi: U::Ppi,
+        pins: [u32; 4],
+        pool_q: &'static MpMcQueue<InternalReport<POOL, OtherPool>, N>,
+        timer: GlobalRollingTimer,
+        overflow: &'static Overflow,
+    ) -> Self {
+        uarte.enable.write(|w| w.enable().disabled());
+        uarte.rxd.maxcnt.write(|w| unsafe { w.bits(BLOCK_BYTES) });
+        uarte
+            .intenset
+            .write(|w| w.rxstarted().set_bit().endrx().set_bit());
+
+        // SAFETY: We own the PPI channel
+        unsafe { sync_start::connect_start(U::PPI_CH, &uarte.tasks_startrx as *const _ as u32) };
+
+        Self {
+            core: DoubleBuffer::new(
+                UartPort {
+                    now: 0,
+                    flushing: false,
+                    _pools: PhantomData,
+                },
+                uarte,
+                Blocks::new(pool_q, overflow),
+                pool_q,
+                timer.clone(),
+            ),
+            timer,
+            pins,
+            config: None,
+            quiet_ticks: MIN_QUIET_TICKS,
+            last_rx: None,
+            _ppi: ppi,
+        }
+    }
+
+    /// Start or stop sniffing with the next capture. Only possible while no
+    /// capture is running.
+    pub fn set_config(&mut self, config: Option<UartConfig>) -> Result<(), ()> {
+        let uarte = match self.core.parts() {
+            (_, State::Idle(uarte)) => uarte,
+            _ => return Err(()),
+        };
+
+        if let Some(config) = config {
+            let pin = *self.pins.get(usize::from(config.channel)).ok_or(())?;
+            let baud = baudrate(config.baudrate).ok_or(())?;
+
+            uarte.psel.rxd.write(|w| unsafe { w.bits(pin) });
+            uarte.baudrate.write(|w| w.baudrate().variant(baud));
+            uarte.config.write(|w| {
+                let w = w.hwfc().clear_bit();
+                if config.parity {
+                    w.parity().included()
+                } else {
+                    w.parity().excluded()
+                }
+            });
+            self.quiet_ticks = quiet_ticks(&config);
+        }
+
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn poll(&mut self, fuse: &AtomicBool) {
+        // Take the timestamp as early as possible, see `DoubleBuffer::poll`
+        let now = self.timer.get_ticks();
+
+        let enabled = self.config.is_some();
+        match self.core.parts() {
+            (_, State::Idle(_)) if !enabled => return,
+            // Only an ENDRX ends a block
+            (_, State::TwoPending((current, _)))
+                if current.uarte.events_endrx.read().bits() == 0 =>
+            {
+                return
+            }
+            (source, _) => source.now = now,
+        }
+
+        self.core.poll(fuse, now);
+    }
+
+    /// End the current block early once the line went quiet, or the fuse
+    /// blew. Call this regularly, the bytes are only sent once it did.
+    pub fn flush(&mut self, fuse: &AtomicBool) {
+        let now = self.timer.get_ticks();
+        let fuse_blown = fuse.load(Ordering::SeqCst);
+
+        let (source, uarte, queued) = match self.core.parts() {
+            (source, State::OnePending(current)) => (source, &current.uarte, false),
+            (source, State::TwoPending((current, _))) => (source, &current.uarte, true),
+            _ => return,
+        };
+        if source.flushing {
+            // Waiting for the ENDRX of the last stop
+            return;
+        }
+
+        if uarte.events_rxdrdy.read().bits() != 0 {
+            uarte.events_rxdrdy.reset();
+            self.last_rx = Some(now);
+        }
+        let quiet = self
+            .last_rx
+            .map(|tick| now.wrapping_sub(tick) >= self.quiet_ticks)
+            .unwrap_or(false);
+
+        // Without a queued block the receiver would not start again, wait
+        // for the next one to be queued, unless stopping anyway
+        if (quiet && queued) || fuse_blown {
+            self.last_rx = None;
+            source.flushing = true;
+            uarte.shorts.modify(|_, w| w.endrx_startrx().disabled());
+            uarte.tasks_stoprx.write(|w| unsafe { w.bits(1) });
+        }
+    }
+}
@@ -8,6 +8,32 @@ use diegesis_fw as _; // memory layout + panic handler
 #[defmt_test::tests]
 mod tests {
     use defmt::{assert, assert_eq};
+    use diegesis_fw::command::CommandReader;
+    use diegesis_icd::{AnalogConfig, AnalogInput, AnalogSource, Command};
+
+    #[test]
+    fn largest_command_is_read() {
+        let mut config = AnalogConfig {
+            oversample: 8,
+            sample_period_us: u32::MAX,
+            ..AnalogConfig::default()
+        };
+        for (i, input) in config.inputs.iter_mut().enumerate() {
+            let ain = AnalogSource::Ain(i as u8);
+            *input = Some(AnalogInput::differential(ain, AnalogSource::Ain(7)));
+        }
+        let cmd = Command::SetAnalogConfig(config);
+
+        let mut buf = [0u8; Command::MAX_ENCODED_LEN];
+        let frame = postcard::to_slice_cobs(&cmd, &mut buf).unwrap();
+        let (terminator, bytes) = frame.split_last().unwrap();
+
+        let mut reader = CommandReader::new();
+        for byte in bytes {
+            assert!(reader.push(*byte).is_none());
+        }
+        assert!(reader.push(*terminator) == Some(cmd));
+    }
 
     #[test]
     fn assert_true() {
@@ -0,0 +1,187 @@
+//! Estimation and correction of the clock offset and drift between channels.
+//!
+//! Each acquisition peripheral runs from its own start, and blocks may take
+//! slightly longer than nominal (e.g. the SPIMs insert small gaps between
+//! bytes). Using the start tick the device reports for every block, a linear
+//! clock model is fitted per channel, which is then used to map samples of
+//! one channel onto another.
+
+use std::collections::BTreeMap;
+
+use crate::timeline::{ChannelKey, ChannelTimeline, Timeline};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockFit {
+    /// Sequence number of the first block of the channel.
+    pub first_seq: u32,
+    /// Fitted start tick of the first block.
+    pub start_tick: f64,
+    /// Fitted duration of a single block, in ticks.
+    pub block_ticks: f64,
+    /// Duration of a single block according to the nominal sample rate.
+    pub nominal_block_ticks: f64,
+    pub samples_per_block: u64,
+}
+
+impl ClockFit {
+    /// Fit a clock model to the block start ticks of a channel. At least two
+    /// blocks are required.
+    ///
+    /// Block timestamps are taken by the device when it services the
+    /// interrupt for a block, so they can only ever be late. After a least
+    /// squares fit, the model is therefore shifted onto the earliest
+    /// observed block start.
+    pub fn fit(chan: &ChannelTimeline) -> Option<Self> {
+        let first_seq = chan.blocks().next()?.seq;
+        let points: Vec<(f64, f64)> = chan
+            .blocks()
+            .map(|b| (f64::from(b.seq - first_seq), b.start_tick as f64))
+            .collect();
+
+        if points.len() < 2 {
+            return None;
+        }
+
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let (cov, var) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+            let dx = x - mean_x;
+            (cov + dx * (y - mean_y), var + dx * dx)
+        });
+
+        let block_ticks = cov / var;
+        let intercept = mean_y - block_ticks * mean_x;
+        let min_residual = points
+            .iter()
+            .map(|(x, y)| y - (intercept + block_ticks * x))
+            .fold(f64::INFINITY, f64::min);
+
+        Some(Self {
+            first_seq,
+            start_tick: intercept + min_residual,
+            block_ticks,
+            nominal_block_ticks: chan.block_ticks() as f64,
+            samples_per_block: chan.key().samples_per_block(),
+        })
+    }
+
+    /// Drift of the channel clock against the nominal sample rate, in ppm.
+    /// Positive values mean the channel samples slower than nominal.
+    pub fn drift_ppm(&self) -> f64 {
+        ((self.block_ticks / self.nominal_block_ticks) - 1.0) * 1_000_000.0
+    }
+
+    /// Corrected tick at which the sample at `idx` was taken.
+    pub fn tick_of(&self, idx: f64) -> f64 {
+        self.start_tick + (idx / self.samples_per_block as f64) * self.block_ticks
+    }
+
+    /// Corrected (fractional) index of the sample taken at `tick`.
+    pub fn index_at(&self, tick: f64) -> f64 {
+        ((tick - self.start_tick) / self.block_ticks) * self.samples_per_block as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Alignment {
+    fits: BTreeMap<ChannelKey, ClockFit>,
+}
+
+impl Alignment {
+    /// Estimate the clock of every channel with at least two blocks.
+    pub fn estimate(timeline: &Timeline) -> Self {
+        let fits = timeline
+            .channels()
+            .filter_map(|chan| Some((chan.key(), ClockFit::fit(chan)?)))
+            .collect();
+        Self { fits }
+    }
+
+    pub fn fit(&self, key: ChannelKey) -> Option<&ClockFit> {
+        self.fits.get(&key)
+    }
+
+    /// Ticks by which the first sample of `a` was taken after the first sample of `b`.
+    pub fn offset(&self, a: ChannelKey, b: ChannelKey) -> Option<f64> {
+        Some(self.fit(a)?.start_tick - self.fit(b)?.start_tick)
+    }
+
+    /// Index of the sample of channel `to` that was taken closest to the
+    /// sample at `idx` of channel `from`.
+    pub fn map_index(&self, from: ChannelKey, idx: u64, to: ChannelKey) -> Option<u64> {
+        let tick = self.fit(from)?.tick_of(idx as f64);
+        let mapped = self.fit(to)?.index_at(tick).round();
+        if mapped < 0.0 {
+            None
+        } else {
+            Some(mapped as u64)
+        }
+    }
+
+    /// Get the digital sample of a channel at a given (corrected) tick.
+    pub fn digital_at(&self, timeline: &Timeline, key: ChannelKey, tick: f64) -> Option<bool> {
+        let idx = self.fit(key)?.index_at(tick);
+        if idx < 0.0 {
+            return None;
+        }
+        timeline.channel(key)?.digital(idx as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use diegesis_icd::{DataReport, DigitalEncoding, Managed, ReportKind};
+
+    /// Push blocks with the given start offset and block duration, plus some
+    /// interrupt latency on every timestamp.
+    fn push_channel(timeline: &mut Timeline, channel: u8, start: f64, block_ticks: f64) {
+        for seq in 0..200u32 {
+            let jitter = f64::from((seq * 7919) % 13);
+            let timestamp = start + f64::from(seq) * block_ticks + jitter;
+            timeline.push(DataReport {
+                timestamp: timestamp as u32,
+                seq,
+                kind: ReportKind::DigitalPin {
+                    channel,
+                    encoding: DigitalEncoding::Raw,
+                },
+                payload: Managed::Owned(Box::new([0x0F; 4096])),
+            });
+        }
+    }
+
+    #[test]
+    fn offset_and_drift() {
+        let mut timeline = Timeline::new();
+        push_channel(&mut timeline, 0, 10_000.0, 65536.0);
+        push_channel(&mut timeline, 1, 12_500.0, 65536.0 + 1310.0);
+
+        let align = Alignment::estimate(&timeline);
+        let (a, b) = (ChannelKey::Digital(0), ChannelKey::Digital(1));
+
+        let offset = align.offset(b, a).unwrap();
+        assert!((offset - 2500.0).abs() < 2.0, "{}", offset);
+
+        let drift = align.fit(b).unwrap().drift_ppm();
+        assert!((drift - 19_989.0).abs() < 50.0, "{}", drift);
+        assert!(align.fit(a).unwrap().drift_ppm().abs() < 50.0);
+
+        // After 150 blocks, channel 1 has fallen behind by ~3 blocks worth of samples.
+        let idx = 150 * 32768;
+        let mapped = align.map_index(a, idx, b).unwrap();
+        let expected = ((150.0 * 65536.0 - 2500.0) / (65536.0 + 1310.0)) * 32768.0;
+        assert!(
+            (mapped as f64 - expected).abs() < 4.0,
+            "{} vs {}",
+            mapped,
+            expected
+        );
+
+        assert_eq!(
+            align.digital_at(&timeline, b, align.fit(b).unwrap().tick_of(4.5)),
+            Some(true)
+        );
+    }
+}
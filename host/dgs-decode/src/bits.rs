@@ -0,0 +1,17 @@
+//! Helpers for working with raw digital channel payloads.
+//!
+//! Digital channels are sampled by a SPIM in MODE 0, so each payload byte
+//! holds eight consecutive samples, oldest sample in the most significant bit.
+
+/// Iterate over the individual samples of a digital payload, in capture order.
+pub fn samples(bytes: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| (byte >> bit) & 0x01 != 0))
+}
+
+/// Get a single sample out of a digital payload.
+pub fn sample(bytes: &[u8], idx: usize) -> Option<bool> {
+    let byte = bytes.get(idx / 8)?;
+    Some((byte >> (7 - (idx % 8))) & 0x01 != 0)
+}
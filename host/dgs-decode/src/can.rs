@@ -0,0 +1,438 @@
+//! CAN 2.0A/B frame decoder, working from a single digital channel tapped
+//! on the RX line of a CAN transceiver.
+//!
+//! The decoder re-synchronizes its bit timing on every edge, so a handful of
+//! samples per bit is enough: at 2 MHz sampling, buses from 125 kbit/s (16
+//! samples per bit) up to 500 kbit/s (4 samples per bit) decode reliably.
+
+use crate::bits;
+
+/// Recessive bits required before a dominant bit is accepted as a start of frame.
+///
+/// Stuffing guarantees there are never more than five identical bits in a row
+/// inside a frame, so seven recessive bits (the length of the end of frame
+/// field) can only be seen between frames.
+const IDLE_BITS: u32 = 7;
+
+/// Generator polynomial of the CAN CRC-15.
+const CRC15_POLY: u16 = 0x4599;
+
+/// Fixed point scale used for bit timing calculations.
+const TIMING_SCALE: u64 = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CanConfig {
+    /// Sample rate of the digital channel, in Hz.
+    pub sample_rate: u32,
+    /// Nominal bitrate of the bus, in bit/s.
+    pub bitrate: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanId {
+    /// 11-bit identifier (CAN 2.0A).
+    Standard(u16),
+    /// 29-bit identifier (CAN 2.0B).
+    Extended(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanFrame {
+    /// Index of the sample where the start of frame bit was detected.
+    pub start_sample: u64,
+    pub id: CanId,
+    /// Remote transmission request.
+    pub rtr: bool,
+    /// Data length code, as sent on the bus.
+    pub dlc: u8,
+    pub data: Vec<u8>,
+    /// CRC-15 as sent on the bus.
+    pub crc: u16,
+    /// Whether the received CRC matches the one calculated over the frame.
+    pub crc_ok: bool,
+    /// Whether any node drove the ACK slot dominant.
+    pub acked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanError {
+    /// Six consecutive identical bits were seen in the stuffed part of a frame.
+    Stuff { start_sample: u64, sample: u64 },
+    /// A fixed-form bit (CRC or ACK delimiter) was not recessive.
+    Form { start_sample: u64, sample: u64 },
+}
+
+pub type CanEvent = Result<CanFrame, CanError>;
+
+enum State {
+    /// Waiting for a start of frame, counting consecutive recessive bits.
+    Idle { recessive: u32 },
+    /// Receiving the bit-stuffed part of a frame (SOF up to and including the CRC).
+    Stuffed(Stuffed),
+    /// Receiving the CRC delimiter, ACK slot, and ACK delimiter.
+    Trailer { frame: CanFrame, position: u8 },
+}
+
+struct Stuffed {
+    start_sample: u64,
+    /// De-stuffed bits received so far, starting with the SOF.
+    bits: Vec<bool>,
+    run_level: bool,
+    run_len: u8,
+}
+
+impl Stuffed {
+    fn new(start_sample: u64) -> Self {
+        Self {
+            start_sample,
+            bits: vec![false],
+            run_level: false,
+            run_len: 1,
+        }
+    }
+
+    /// Total number of de-stuffed bits from SOF to the end of the CRC, if
+    /// enough of the header has been received to know it.
+    fn expected_len(&self) -> Option<usize> {
+        let bits = &self.bits;
+        if bits.len() < 14 {
+            return None;
+        }
+
+        let extended = bits[13];
+        let dlc_end = if extended { 39 } else { 19 };
+        if bits.len() < dlc_end {
+            return None;
+        }
+
+        let rtr = if extended { bits[32] } else { bits[12] };
+        let dlc = field(&bits[dlc_end - 4..dlc_end]) as usize;
+        let data_bits = if rtr { 0 } else { dlc.min(8) * 8 };
+
+        Some(dlc_end + data_bits + 15)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.expected_len() == Some(self.bits.len())
+    }
+
+    fn into_frame(self) -> CanFrame {
+        let bits = &self.bits;
+        let extended = bits[13];
+        let id_a = field(&bits[1..12]);
+
+        let (id, rtr, dlc_end) = if extended {
+            let id_b = field(&bits[14..32]);
+            (CanId::Extended((id_a << 18) | id_b), bits[32], 39)
+        } else {
+            (CanId::Standard(id_a as u16), bits[12], 19)
+        };
+
+        let dlc = field(&bits[dlc_end - 4..dlc_end]) as u8;
+        let crc_start = bits.len() - 15;
+        let data = bits[dlc_end..crc_start]
+            .chunks(8)
+            .map(|byte| field(byte) as u8)
+            .collect();
+        let crc = field(&bits[crc_start..]) as u16;
+
+        CanFrame {
+            start_sample: self.start_sample,
+            id,
+            rtr,
+            dlc,
+            data,
+            crc,
+            crc_ok: crc15(&bits[..crc_start]) == crc,
+            acked: false,
+        }
+    }
+}
+
+pub struct CanDecoder {
+    /// Samples per bit, scaled by `TIMING_SCALE`.
+    samples_per_bit: u64,
+    sample_idx: u64,
+    level: bool,
+    samples_since_edge: u64,
+    bits_since_edge: u64,
+    state: State,
+}
+
+impl CanDecoder {
+    pub fn new(config: CanConfig) -> Self {
+        let samples_per_bit =
+            (u64::from(config.sample_rate) * TIMING_SCALE) / u64::from(config.bitrate);
+        assert!(
+            samples_per_bit >= 2 * TIMING_SCALE,
+            "Need at least two samples per bit to decode CAN"
+        );
+
+        Self {
+            samples_per_bit,
+            sample_idx: 0,
+            level: true,
+            samples_since_edge: 0,
+            bits_since_edge: 0,
+            state: State::Idle { recessive: 0 },
+        }
+    }
+
+    /// Decode a digital channel payload, as received from the device.
+    ///
+    /// Frames may span multiple payloads, so consecutive payloads of a channel
+    /// should all be fed to the same decoder.
+    pub fn decode(&mut self, payload: &[u8]) -> Vec<CanEvent> {
+        bits::samples(payload)
+            .filter_map(|level| self.push(level))
+            .collect()
+    }
+
+    /// Feed a single sample of the RX line into the decoder.
+    pub fn push(&mut self, level: bool) -> Option<CanEvent> {
+        if level != self.level {
+            // Re-synchronize on every edge
+            self.level = level;
+            self.samples_since_edge = 0;
+            self.bits_since_edge = 0;
+        }
+
+        // Sample each bit in the middle of its bit time
+        let sample_point =
+            (self.bits_since_edge * self.samples_per_bit) + (self.samples_per_bit / 2);
+        let event = if (self.samples_since_edge * TIMING_SCALE) >= sample_point {
+            self.bits_since_edge += 1;
+            self.bit(level)
+        } else {
+            None
+        };
+
+        self.samples_since_edge += 1;
+        self.sample_idx += 1;
+        event
+    }
+
+    fn bit(&mut self, level: bool) -> Option<CanEvent> {
+        let sample = self.sample_idx;
+        let state = core::mem::replace(&mut self.state, State::Idle { recessive: 0 });
+
+        let (new_state, event) = match state {
+            State::Idle { recessive } if level => (
+                State::Idle {
+                    recessive: recessive.saturating_add(1),
+                },
+                None,
+            ),
+            State::Idle { recessive } if recessive >= IDLE_BITS => {
+                (State::Stuffed(Stuffed::new(sample)), None)
+            }
+            State::Idle { .. } => (State::Idle { recessive: 0 }, None),
+            State::Stuffed(mut stuffed) => {
+                if stuffed.run_len == 5 {
+                    if level == stuffed.run_level {
+                        let err = CanError::Stuff {
+                            start_sample: stuffed.start_sample,
+                            sample,
+                        };
+                        return Some(Err(err));
+                    }
+
+                    // Discard the stuff bit. It does start a new run, though.
+                    stuffed.run_level = level;
+                    stuffed.run_len = 1;
+                } else {
+                    if level == stuffed.run_level {
+                        stuffed.run_len += 1;
+                    } else {
+                        stuffed.run_level = level;
+                        stuffed.run_len = 1;
+                    }
+                    stuffed.bits.push(level);
+                }
+
+                // A stuff bit may still follow the last bit of the CRC
+                if stuffed.is_complete() && stuffed.run_len < 5 {
+                    let trailer = State::Trailer {
+                        frame: stuffed.into_frame(),
+                        position: 0,
+                    };
+                    (trailer, None)
+                } else {
+                    (State::Stuffed(stuffed), None)
+                }
+            }
+            State::Trailer {
+                mut frame,
+                position,
+            } => match position {
+                // CRC and ACK delimiters must be recessive
+                0 | 2 if !level => {
+                    let err = CanError::Form {
+                        start_sample: frame.start_sample,
+                        sample,
+                    };
+                    (State::Idle { recessive: 0 }, Some(Err(err)))
+                }
+                0 => (State::Trailer { frame, position: 1 }, None),
+                1 => {
+                    frame.acked = !level;
+                    (State::Trailer { frame, position: 2 }, None)
+                }
+                _ => (State::Idle { recessive: 0 }, Some(Ok(frame))),
+            },
+        };
+
+        self.state = new_state;
+        event
+    }
+}
+
+/// Interpret a run of bits as an MSB-first unsigned integer.
+fn field(bits: &[bool]) -> u32 {
+    bits.iter().fold(0, |acc, bit| (acc << 1) | u32::from(*bit))
+}
+
+fn crc15(bits: &[bool]) -> u16 {
+    bits.iter().fold(0u16, |crc, bit| {
+        let crc_next = *bit ^ ((crc >> 14) & 0x01 != 0);
+        let crc = (crc << 1) & 0x7FFF;
+        if crc_next {
+            crc ^ CRC15_POLY
+        } else {
+            crc
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_field(bits: &mut Vec<bool>, value: u32, width: usize) {
+        (0..width)
+            .rev()
+            .for_each(|i| bits.push((value >> i) & 0x01 != 0));
+    }
+
+    /// Build the bus-level bits of a frame, including idle time before and after.
+    fn frame_bits(id: CanId, rtr: bool, data: &[u8]) -> Vec<bool> {
+        let mut raw = vec![false];
+        match id {
+            CanId::Standard(id) => {
+                push_field(&mut raw, id.into(), 11);
+                raw.extend_from_slice(&[rtr, false, false]);
+            }
+            CanId::Extended(id) => {
+                push_field(&mut raw, id >> 18, 11);
+                raw.extend_from_slice(&[true, true]);
+                push_field(&mut raw, id & 0x3FFFF, 18);
+                raw.extend_from_slice(&[rtr, false, false]);
+            }
+        }
+        push_field(&mut raw, data.len() as u32, 4);
+        if !rtr {
+            data.iter()
+                .for_each(|b| push_field(&mut raw, (*b).into(), 8));
+        }
+        let crc = crc15(&raw);
+        push_field(&mut raw, crc.into(), 15);
+
+        let mut bus = vec![true; 12];
+        let mut run = (false, 0);
+        for bit in raw {
+            if run.1 == 5 {
+                bus.push(!run.0);
+                run = (!run.0, 1);
+            }
+            if bit == run.0 {
+                run.1 += 1;
+            } else {
+                run = (bit, 1);
+            }
+            bus.push(bit);
+        }
+        if run.1 == 5 {
+            bus.push(!run.0);
+        }
+
+        // CRC delimiter, ACK slot, ACK delimiter, EOF, intermission
+        bus.extend_from_slice(&[true, false, true]);
+        bus.extend_from_slice(&[true; 10]);
+        bus
+    }
+
+    /// Sample bus bits with the given number of samples per bit, packed into
+    /// a digital channel payload.
+    fn sample(bus: &[bool], samples_per_bit: f64) -> Vec<u8> {
+        let total = (bus.len() as f64 * samples_per_bit) as usize;
+        let mut payload = vec![0u8; total.div_ceil(8)];
+        for i in 0..total {
+            let bit = bus[((i as f64) / samples_per_bit) as usize];
+            if bit {
+                payload[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        payload
+    }
+
+    #[test]
+    fn standard_frame_500k() {
+        let bus = frame_bits(CanId::Standard(0x123), false, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        let mut dec = CanDecoder::new(CanConfig {
+            sample_rate: 2_000_000,
+            bitrate: 500_000,
+        });
+
+        let events = dec.decode(&sample(&bus, 4.0));
+        assert_eq!(events.len(), 1);
+        let frame = events[0].clone().unwrap();
+        assert_eq!(frame.id, CanId::Standard(0x123));
+        assert_eq!(frame.data, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert!(frame.crc_ok);
+        assert!(frame.acked);
+        assert!(!frame.rtr);
+    }
+
+    #[test]
+    fn extended_frames_with_clock_error() {
+        let mut bus = frame_bits(CanId::Extended(0x1ABC_DEF0), false, &[0x00; 8]);
+        bus.extend(frame_bits(CanId::Extended(0x0000_0042), true, &[0; 3]));
+        let mut dec = CanDecoder::new(CanConfig {
+            sample_rate: 2_000_000,
+            bitrate: 125_000,
+        });
+
+        // Bus runs 1% slow compared to the nominal bitrate
+        let events = dec.decode(&sample(&bus, 16.16));
+        assert_eq!(events.len(), 2);
+
+        let first = events[0].clone().unwrap();
+        assert_eq!(first.id, CanId::Extended(0x1ABC_DEF0));
+        assert_eq!(first.data, &[0x00; 8]);
+        assert!(first.crc_ok);
+
+        let second = events[1].clone().unwrap();
+        assert_eq!(second.id, CanId::Extended(0x42));
+        assert!(second.rtr);
+        assert_eq!(second.dlc, 3);
+        assert!(second.data.is_empty());
+        assert!(second.crc_ok);
+    }
+
+    #[test]
+    fn stuff_error() {
+        let mut bus = frame_bits(CanId::Standard(0x7FF), false, &[0x55]);
+
+        // Replace the stuff bit after the five recessive ID bits
+        let idx = bus.iter().skip(12).position(|b| !b).unwrap() + 12 + 6;
+        assert!(!bus[idx]);
+        bus[idx] = true;
+
+        let mut dec = CanDecoder::new(CanConfig {
+            sample_rate: 2_000_000,
+            bitrate: 250_000,
+        });
+        let events = dec.decode(&sample(&bus, 8.0));
+        assert!(matches!(events[0], Err(CanError::Stuff { .. })));
+    }
+}
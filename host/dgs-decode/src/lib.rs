@@ -0,0 +1,7 @@
+//! Host-side decoding of diegesis captures.
+
+pub mod align;
+pub mod bits;
+pub mod can;
+pub mod timeline;
+pub mod wav;
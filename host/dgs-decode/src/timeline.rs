@@ -0,0 +1,753 @@
+//! Reconstruction of continuous per-channel captures out of the disjoint
+//! blocks sent by the device.
+//!
+//! Reports of all channels arrive interleaved, and not necessarily in order.
+//! The `Timeline` sorts them back into per-channel streams using the sequence
+//! number of each block, and places them in time using their start ticks.
+
+use std::collections::BTreeMap;
+
+use diegesis_icd::{
+    delta, rle, AnalogConfig, AnalogEncoding, AnalogSettings, DataReport, DigitalEncoding, Managed,
+    Measurement, ReportKind, TriggerSource, UartErrors,
+};
+
+use crate::bits;
+
+/// Tick rate of the device's rolling timer, used for all report timestamps.
+pub const TICKS_PER_SECOND: u64 = 4_000_000;
+
+/// Unwrapped tick assigned to the first timestamp seen by a `Timeline`.
+///
+/// Leaves room for channels whose first block started before that one.
+pub const EPOCH_TICK: u64 = 1 << 32;
+
+/// Size of a single report payload, in bytes.
+pub const BLOCK_BYTES: usize = 4096;
+
+/// Deviations from the nominal block timing smaller than
+/// `1 / TOLERANCE_DIVISOR` of a block are not reported as gaps or overlaps.
+const TOLERANCE_DIVISOR: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChannelKey {
+    Digital(u8),
+    /// Analog channels are keyed by the bitflag of the channels scanned together.
+    Analog(u8),
+    /// The microphone.
+    Audio,
+}
+
+impl ChannelKey {
+    /// The channel a report belongs to, if it carries sample data.
+    pub fn of(kind: &ReportKind) -> Option<Self> {
+        match *kind {
+            ReportKind::DigitalPin { channel, .. } => Some(ChannelKey::Digital(channel)),
+            ReportKind::AnalogPin {
+                channel_bitflag, ..
+            } => Some(ChannelKey::Analog(channel_bitflag)),
+            ReportKind::Audio { .. } => Some(ChannelKey::Audio),
+            ReportKind::CaptureStart
+            | ReportKind::AnalogSettings { .. }
+            | ReportKind::AnalogCalibrated { .. }
+            | ReportKind::Trigger { .. }
+            | ReportKind::CaptureRestart { .. }
+            | ReportKind::Idle { .. }
+            | ReportKind::UartBytes { .. }
+            | ReportKind::Measurement { .. } => None,
+        }
+    }
+
+    fn default_sample_rate(&self) -> u32 {
+        match self {
+            ChannelKey::Digital(_) => 2_000_000,
+            ChannelKey::Analog(_) => AnalogConfig::default().sample_rate(),
+            ChannelKey::Audio => 16_000,
+        }
+    }
+
+    /// Number of inputs sampled in each sample of this channel.
+    pub fn width(&self) -> usize {
+        match self {
+            ChannelKey::Digital(_) | ChannelKey::Audio => 1,
+            ChannelKey::Analog(bitflag) => (bitflag.count_ones() as usize).max(1),
+        }
+    }
+
+    pub fn samples_per_block(&self) -> u64 {
+        match self {
+            ChannelKey::Digital(_) => (BLOCK_BYTES * 8) as u64,
+            ChannelKey::Analog(_) | ChannelKey::Audio => (BLOCK_BYTES / 2 / self.width()) as u64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub seq: u32,
+    /// Start tick, as reported by the device.
+    pub timestamp: u32,
+    /// Start tick, unwrapped onto a time base shared by all channels of the timeline.
+    pub start_tick: u64,
+    pub payload: Box<[u8]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discontinuity {
+    /// The next block started later than expected. `missing_blocks` counts
+    /// blocks that were captured by the device, but never received.
+    Gap {
+        after_seq: u32,
+        missing_blocks: u32,
+        ticks: u64,
+    },
+    /// The next block started before the previous one was expected to end.
+    Overlap { after_seq: u32, ticks: u64 },
+}
+
+#[derive(Debug)]
+pub struct ChannelTimeline {
+    key: ChannelKey,
+    sample_rate: u32,
+    /// A timestamp and its unwrapped tick, from around when the channel was
+    /// first seen. The first block is unwrapped relative to it.
+    anchor: (u32, u64),
+    blocks: BTreeMap<u32, Block>,
+    by_tick: BTreeMap<u64, u32>,
+}
+
+impl ChannelTimeline {
+    fn new(key: ChannelKey, sample_rate: u32, anchor: (u32, u64)) -> Self {
+        Self {
+            key,
+            sample_rate,
+            anchor,
+            blocks: BTreeMap::new(),
+            by_tick: BTreeMap::new(),
+        }
+    }
+
+    pub fn key(&self) -> ChannelKey {
+        self.key
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.values()
+    }
+
+    /// Nominal duration of a single block, in ticks.
+    pub fn block_ticks(&self) -> u64 {
+        (self.key.samples_per_block() * TICKS_PER_SECOND) / u64::from(self.sample_rate)
+    }
+
+    /// Number of samples from the start of the first block to the end of the
+    /// last one, including any missing blocks in between.
+    pub fn len(&self) -> u64 {
+        match (self.blocks.keys().next(), self.blocks.keys().next_back()) {
+            (Some(first), Some(last)) => u64::from(last - first + 1) * self.key.samples_per_block(),
+            _ => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the unwrapped start tick of the block.
+    fn insert(&mut self, block: Block) -> u64 {
+        let seq = block.seq;
+        let in_order = self
+            .blocks
+            .keys()
+            .next_back()
+            .map(|last| *last < seq)
+            .unwrap_or(true);
+        self.blocks.insert(seq, block);
+
+        if in_order {
+            let prev = self
+                .blocks
+                .range(..seq)
+                .next_back()
+                .map(|(_, b)| (b.timestamp, b.start_tick));
+            let block = self.blocks.get_mut(&seq).unwrap();
+            block.start_tick = unwrap_tick(prev, self.anchor, block.timestamp);
+            self.by_tick.insert(block.start_tick, seq);
+        } else {
+            self.rebuild();
+        }
+        self.blocks[&seq].start_tick
+    }
+
+    fn rebuild(&mut self) {
+        self.by_tick.clear();
+        let mut prev = None;
+        for block in self.blocks.values_mut() {
+            block.start_tick = unwrap_tick(prev, self.anchor, block.timestamp);
+            prev = Some((block.timestamp, block.start_tick));
+            self.by_tick.insert(block.start_tick, block.seq);
+        }
+    }
+
+    /// Find the block holding the sample at `idx`, and the sample's offset in it.
+    fn locate(&self, idx: u64) -> Option<(&Block, usize)> {
+        let first = *self.blocks.keys().next()?;
+        let per_block = self.key.samples_per_block();
+        let seq = u64::from(first) + (idx / per_block);
+        if seq > u64::from(u32::MAX) {
+            return None;
+        }
+        let block = self.blocks.get(&(seq as u32))?;
+        Some((block, (idx % per_block) as usize))
+    }
+
+    /// Get a digital sample. Returns `None` for samples in missing blocks.
+    pub fn digital(&self, idx: u64) -> Option<bool> {
+        if !matches!(self.key, ChannelKey::Digital(_)) {
+            return None;
+        }
+        let (block, offset) = self.locate(idx)?;
+        bits::sample(&block.payload[..], offset)
+    }
+
+    /// Get an analog or audio sample, containing one value per input of the
+    /// channel. Returns `None` for samples in missing blocks.
+    pub fn analog(&self, idx: u64) -> Option<Vec<i16>> {
+        if let ChannelKey::Digital(_) = self.key {
+            return None;
+        }
+        let (block, offset) = self.locate(idx)?;
+        let width = self.key.width();
+        let start = offset * width * 2;
+        let values = block
+            .payload
+            .get(start..start + (width * 2))?
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        Some(values)
+    }
+
+    /// Tick at which the sample at `idx` was taken, based on the start tick
+    /// of the block that holds it.
+    pub fn tick_of(&self, idx: u64) -> Option<u64> {
+        let (block, offset) = self.locate(idx)?;
+        Some(block.start_tick + (offset as u64 * TICKS_PER_SECOND) / u64::from(self.sample_rate))
+    }
+
+    /// Index of the sample taken at `tick`. Returns `None` if no received
+    /// block covers that tick.
+    pub fn index_at(&self, tick: u64) -> Option<u64> {
+        let (start, seq) = self.by_tick.range(..=tick).next_back()?;
+        let offset = ((tick - start) * u64::from(self.sample_rate)) / TICKS_PER_SECOND;
+        let per_block = self.key.samples_per_block();
+        if offset >= per_block {
+            return None;
+        }
+        let first = *self.blocks.keys().next()?;
+        Some((u64::from(*seq - first) * per_block) + offset)
+    }
+
+    /// Compare each pair of consecutive blocks against the nominal block timing.
+    pub fn discontinuities(&self) -> Vec<Discontinuity> {
+        let block_ticks = self.block_ticks();
+        let tolerance = block_ticks / TOLERANCE_DIVISOR;
+
+        self.blocks
+            .values()
+            .zip(self.blocks.values().skip(1))
+            .filter_map(|(prev, next)| {
+                let missing_blocks = next.seq - prev.seq - 1;
+                let expected = prev.start_tick + (u64::from(missing_blocks + 1) * block_ticks);
+
+                if missing_blocks != 0 || next.start_tick > expected + tolerance {
+                    Some(Discontinuity::Gap {
+                        after_seq: prev.seq,
+                        missing_blocks,
+                        ticks: next
+                            .start_tick
+                            .saturating_sub(prev.start_tick + block_ticks),
+                    })
+                } else if next.start_tick + tolerance < expected {
+                    Some(Discontinuity::Overlap {
+                        after_seq: prev.seq,
+                        ticks: expected - next.start_tick,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Unwrap `timestamp` to the tick closest to `near`, a timestamp and its
+/// unwrapped tick. Only right within half a wrap of `near`, about 9 minutes
+/// either way.
+fn unwrap_near(near: (u32, u64), timestamp: u32) -> u64 {
+    let offset = timestamp.wrapping_sub(near.0) as i32;
+    (near.1 as i64 + i64::from(offset)) as u64
+}
+
+fn unwrap_tick(prev: Option<(u32, u64)>, anchor: (u32, u64), timestamp: u32) -> u64 {
+    match prev {
+        Some((prev_ts, prev_tick)) => prev_tick + u64::from(timestamp.wrapping_sub(prev_ts)),
+        None => unwrap_near(anchor, timestamp),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Timeline {
+    channels: BTreeMap<ChannelKey, ChannelTimeline>,
+    sample_rates: BTreeMap<ChannelKey, u32>,
+    /// The latest tick seen so far, and its timestamp. Markers are unwrapped
+    /// relative to it, as they arrive close to the blocks around them, however
+    /// long the capture has been running.
+    latest: Option<(u32, u64)>,
+    capture_start: Option<u64>,
+    analog_settings: BTreeMap<ChannelKey, AnalogSettings>,
+    calibrations: Vec<Calibration>,
+    triggers: Vec<(u64, TriggerSource)>,
+    restarts: Vec<(u64, u8)>,
+    uart: Vec<UartChunk>,
+    measurements: Vec<(u64, u8, Measurement)>,
+}
+
+/// An offset recalibration of the analog channels during a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    pub key: ChannelKey,
+    pub tick: u64,
+    /// Die temperature, in 0.25°C steps.
+    pub temperature: i32,
+}
+
+/// Bytes received by a UART sniffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UartChunk {
+    pub port: u8,
+    /// Tick the bytes were sent at, shortly after the last one came in.
+    pub tick: u64,
+    pub errors: UartErrors,
+    pub bytes: Vec<u8>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the sample rate of a channel, used to place its samples in time.
+    ///
+    /// Defaults to 2 MHz for digital channels, and the rate of the default
+    /// `AnalogConfig` for analog channels.
+    /// Audio reports carry their own sample rate.
+    pub fn set_sample_rate(&mut self, key: ChannelKey, sample_rate: u32) {
+        self.sample_rates.insert(key, sample_rate);
+        if let Some(chan) = self.channels.get_mut(&key) {
+            chan.sample_rate = sample_rate;
+        }
+    }
+
+    /// Add a report to the timeline.
+    pub fn push(&mut self, report: DataReport<'_>) {
+        let key = match ChannelKey::of(&report.kind) {
+            Some(key) => key,
+            None => {
+                match report.kind {
+                    ReportKind::CaptureStart => {
+                        self.capture_start = Some(self.marker_tick(report.timestamp));
+                    }
+                    ReportKind::AnalogSettings {
+                        channel_bitflag,
+                        settings,
+                    } => {
+                        let key = ChannelKey::Analog(channel_bitflag);
+                        self.set_sample_rate(key, settings.config.sample_rate());
+                        self.analog_settings.insert(key, settings);
+                    }
+                    ReportKind::AnalogCalibrated {
+                        channel_bitflag,
+                        temperature,
+                    } => {
+                        let tick = self.marker_tick(report.timestamp);
+                        self.calibrations.push(Calibration {
+                            key: ChannelKey::Analog(channel_bitflag),
+                            tick,
+                            temperature,
+                        });
+                    }
+                    ReportKind::Trigger { source } => {
+                        let tick = self.marker_tick(report.timestamp);
+                        self.triggers.push((tick, source));
+                    }
+                    ReportKind::CaptureRestart { attempt } => {
+                        let tick = self.marker_tick(report.timestamp);
+                        self.restarts.push((tick, attempt));
+                    }
+                    ReportKind::Measurement {
+                        channel,
+                        measurement,
+                    } => {
+                        let tick = self.marker_tick(report.timestamp);
+                        self.measurements.push((tick, channel, measurement));
+                    }
+                    ReportKind::UartBytes { port, errors } if !report.payload.is_empty() => {
+                        let tick = self.marker_tick(report.timestamp);
+                        self.uart.push(UartChunk {
+                            port,
+                            tick,
+                            errors,
+                            bytes: report.payload.to_vec(),
+                        });
+                    }
+                    ReportKind::Idle {
+                        channel,
+                        level,
+                        blocks,
+                    } => {
+                        // Expanded back into the blocks it replaced, spaced
+                        // by the nominal block duration
+                        let key = ChannelKey::Digital(channel);
+                        let fill = if level { 0xFF } else { 0x00 };
+                        let block_ticks = self.channel_mut(key, report.timestamp).block_ticks();
+                        for i in 0..blocks {
+                            let offset = (u64::from(i) * block_ticks) as u32;
+                            let timestamp = report.timestamp.wrapping_add(offset);
+                            let tick = self.channel_mut(key, report.timestamp).insert(Block {
+                                seq: report.seq.wrapping_add(i),
+                                timestamp,
+                                start_tick: 0,
+                                payload: vec![fill; BLOCK_BYTES].into_boxed_slice(),
+                            });
+                            self.seen(timestamp, tick);
+                        }
+                    }
+                    _ => {}
+                }
+                return;
+            }
+        };
+        if let ReportKind::Audio { sample_rate } = report.kind {
+            self.set_sample_rate(key, sample_rate);
+        }
+
+        let payload = match report.payload {
+            Managed::Owned(payload) => payload,
+            Managed::Borrowed(payload) => payload.into(),
+        };
+        let payload = match report.kind {
+            ReportKind::DigitalPin {
+                encoding: DigitalEncoding::RunLength,
+                ..
+            } => {
+                let mut decoded = vec![0; BLOCK_BYTES].into_boxed_slice();
+                match rle::decode(&payload, &mut decoded) {
+                    Some(BLOCK_BYTES) => decoded,
+                    // Dropped, like any other block lost on the way
+                    _ => return,
+                }
+            }
+            ReportKind::AnalogPin {
+                encoding: AnalogEncoding::Delta,
+                ..
+            } => {
+                let mut decoded = vec![0; BLOCK_BYTES].into_boxed_slice();
+                match delta::decode(&payload, key.width(), &mut decoded) {
+                    Some(BLOCK_BYTES) => decoded,
+                    _ => return,
+                }
+            }
+            _ => payload,
+        };
+
+        let tick = self.channel_mut(key, report.timestamp).insert(Block {
+            seq: report.seq,
+            timestamp: report.timestamp,
+            start_tick: 0,
+            payload,
+        });
+        self.seen(report.timestamp, tick);
+    }
+
+    /// Unwrap the timestamp of a marker, see `latest`.
+    fn marker_tick(&mut self, timestamp: u32) -> u64 {
+        let latest = *self.latest.get_or_insert((timestamp, EPOCH_TICK));
+        let tick = unwrap_near(latest, timestamp);
+        self.seen(timestamp, tick);
+        tick
+    }
+
+    fn seen(&mut self, timestamp: u32, tick: u64) {
+        if self.latest.is_none_or(|(_, latest)| tick > latest) {
+            self.latest = Some((timestamp, tick));
+        }
+    }
+
+    fn channel_mut(&mut self, key: ChannelKey, timestamp: u32) -> &mut ChannelTimeline {
+        let anchor = *self.latest.get_or_insert((timestamp, EPOCH_TICK));
+        let sample_rate = self
+            .sample_rates
+            .get(&key)
+            .copied()
+            .unwrap_or_else(|| key.default_sample_rate());
+
+        self.channels
+            .entry(key)
+            .or_insert_with(|| ChannelTimeline::new(key, sample_rate, anchor))
+    }
+
+    /// Tick at which all channels were started, if the device reported it.
+    pub fn capture_start(&self) -> Option<u64> {
+        self.capture_start
+    }
+
+    /// Settings last reported for an analog channel, used to convert its codes to volts.
+    pub fn analog_settings(&self, key: ChannelKey) -> Option<&AnalogSettings> {
+        self.analog_settings.get(&key)
+    }
+
+    pub fn calibrations(&self) -> &[Calibration] {
+        &self.calibrations
+    }
+
+    /// Ticks at which the capture trigger fired, and what fired it.
+    pub fn triggers(&self) -> &[(u64, TriggerSource)] {
+        &self.triggers
+    }
+
+    /// Ticks at which the device restarted the capture on its own, and the
+    /// number of the attempt. Blocks are missing since the previous stop.
+    pub fn restarts(&self) -> &[(u64, u8)] {
+        &self.restarts
+    }
+
+    /// Ticks at which a measurement gate ended, the digital channel
+    /// measured, and what was seen during the gate.
+    pub fn measurements(&self) -> &[(u64, u8, Measurement)] {
+        &self.measurements
+    }
+
+    /// Bytes received by UART sniffer `port`, in the order they came in.
+    pub fn uart(&self, port: u8) -> impl Iterator<Item = &UartChunk> {
+        self.uart.iter().filter(move |chunk| chunk.port == port)
+    }
+
+    pub fn channel(&self, key: ChannelKey) -> Option<&ChannelTimeline> {
+        self.channels.get(&key)
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &ChannelTimeline> {
+        self.channels.values()
+    }
+
+    pub fn discontinuities(&self) -> Vec<(ChannelKey, Discontinuity)> {
+        self.channels
+            .values()
+            .flat_map(|chan| {
+                chan.discontinuities()
+                    .into_iter()
+                    .map(move |disc| (chan.key, disc))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn digital(channel: u8, seq: u32, timestamp: u32, fill: u8) -> DataReport<'static> {
+        DataReport {
+            timestamp,
+            seq,
+            kind: ReportKind::DigitalPin {
+                channel,
+                encoding: DigitalEncoding::Raw,
+            },
+            payload: Managed::Owned(Box::new([fill; BLOCK_BYTES])),
+        }
+    }
+
+    #[test]
+    fn out_of_order_merge() {
+        let mut timeline = Timeline::new();
+        timeline.push(digital(0, 11, 65536, 0xFF));
+        timeline.push(digital(1, 3, 100, 0xFF));
+        timeline.push(digital(0, 10, 0, 0x00));
+        timeline.push(digital(0, 12, 131072, 0x80));
+
+        let chan = timeline.channel(ChannelKey::Digital(0)).unwrap();
+        assert_eq!(chan.len(), 3 * 32768);
+        assert_eq!(chan.digital(0), Some(false));
+        assert_eq!(chan.digital(32768), Some(true));
+        assert_eq!(chan.digital(65536), Some(true));
+        assert_eq!(chan.digital(65537), Some(false));
+        assert_eq!(chan.digital(3 * 32768), None);
+
+        // The first report received defines the epoch
+        assert_eq!(chan.tick_of(32768 + 2), Some(EPOCH_TICK + 4));
+        assert_eq!(chan.index_at(EPOCH_TICK + 4), Some(32768 + 2));
+
+        let other = timeline.channel(ChannelKey::Digital(1)).unwrap();
+        assert_eq!(other.tick_of(0), Some(EPOCH_TICK - 65436));
+        assert!(timeline.discontinuities().is_empty());
+    }
+
+    #[test]
+    fn gaps_and_overlaps() {
+        let mut timeline = Timeline::new();
+
+        // Timestamps wrap between the first and second block
+        let start = u32::MAX - 100;
+        timeline.push(DataReport {
+            timestamp: start,
+            seq: 0,
+            kind: ReportKind::CaptureStart,
+            payload: Managed::Borrowed(&mut []),
+        });
+        timeline.push(digital(2, 0, start, 0x00));
+        timeline.push(digital(2, 1, start.wrapping_add(65536), 0x00));
+        timeline.push(digital(2, 3, start.wrapping_add(4 * 65536), 0x00));
+        timeline.push(digital(2, 4, start.wrapping_add(4 * 65536 + 30000), 0x00));
+
+        let chan = timeline.channel(ChannelKey::Digital(2)).unwrap();
+        assert_eq!(timeline.capture_start(), Some(EPOCH_TICK));
+        assert_eq!(chan.tick_of(0), Some(EPOCH_TICK));
+        assert_eq!(chan.digital(2 * 32768), None);
+        assert_eq!(
+            chan.discontinuities(),
+            &[
+                Discontinuity::Gap {
+                    after_seq: 1,
+                    missing_blocks: 1,
+                    ticks: 2 * 65536,
+                },
+                Discontinuity::Overlap {
+                    after_seq: 3,
+                    ticks: 65536 - 30000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_runs_expand() {
+        let mut timeline = Timeline::new();
+        timeline.push(digital(0, 0, 0, 0x5A));
+        timeline.push(DataReport {
+            timestamp: 65536,
+            seq: 1,
+            kind: ReportKind::Idle {
+                channel: 0,
+                level: true,
+                blocks: 3,
+            },
+            payload: Managed::Borrowed(&mut []),
+        });
+        let mut encoded = [0u8; 8];
+        let used = rle::encode(&[0x00; BLOCK_BYTES], &mut encoded).unwrap();
+        timeline.push(DataReport {
+            timestamp: 4 * 65536,
+            seq: 4,
+            kind: ReportKind::DigitalPin {
+                channel: 0,
+                encoding: DigitalEncoding::RunLength,
+            },
+            payload: Managed::Borrowed(&mut encoded[..used]),
+        });
+
+        let chan = timeline.channel(ChannelKey::Digital(0)).unwrap();
+        assert_eq!(chan.len(), 5 * 32768);
+        assert_eq!(chan.digital(4 * 32768 + 9), Some(false));
+        assert_eq!(chan.digital(3 * 32768 + 7), Some(true));
+        assert_eq!(chan.tick_of(3 * 32768), Some(EPOCH_TICK + 3 * 65536));
+        assert!(chan.discontinuities().is_empty());
+    }
+
+    #[test]
+    fn uart_bytes_are_kept_per_port() {
+        let mut timeline = Timeline::new();
+        timeline.push(digital(0, 0, 0, 0x5A));
+        let errors = UartErrors {
+            framing: true,
+            ..UartErrors::default()
+        };
+        for (timestamp, port, bytes) in [(100, 0, &b"AT"[..]), (200, 1, b"OK"), (300, 0, b"")] {
+            timeline.push(DataReport {
+                timestamp,
+                seq: 0,
+                kind: ReportKind::UartBytes { port, errors },
+                payload: Managed::Owned(bytes.into()),
+            });
+        }
+
+        let chunks: Vec<_> = timeline.uart(0).collect();
+        assert_eq!(
+            chunks,
+            [&UartChunk {
+                port: 0,
+                tick: EPOCH_TICK + 100,
+                errors,
+                bytes: b"AT".to_vec(),
+            }]
+        );
+        assert_eq!(timeline.uart(1).count(), 1);
+        assert!(timeline.channel(ChannelKey::Digital(0)).is_some());
+    }
+
+    #[test]
+    fn markers_after_the_timer_wraps() {
+        let mut timeline = Timeline::new();
+        // At 1 kHz a block spans 131_072_000 ticks, so 33 blocks wrap the timer
+        timeline.set_sample_rate(ChannelKey::Digital(0), 1000);
+        let block_ticks = 131_072_000u32;
+        for seq in 0..40 {
+            timeline.push(digital(0, seq, seq.wrapping_mul(block_ticks), 0x00));
+        }
+        let source = TriggerSource::Analog {
+            channel_bitflag: 0b1,
+            index: 0,
+        };
+        timeline.push(DataReport {
+            timestamp: 35u32.wrapping_mul(block_ticks).wrapping_add(100),
+            seq: 0,
+            kind: ReportKind::Trigger { source },
+            payload: Managed::Borrowed(&mut []),
+        });
+
+        let tick = EPOCH_TICK + 35 * u64::from(block_ticks) + 100;
+        assert_eq!(timeline.triggers(), [(tick, source)]);
+        let chan = timeline.channel(ChannelKey::Digital(0)).unwrap();
+        assert_eq!(chan.index_at(tick), Some(35 * 32768));
+        assert!(timeline.discontinuities().is_empty());
+    }
+
+    #[test]
+    fn measurements_are_kept() {
+        let mut timeline = Timeline::new();
+        let measurement = Measurement {
+            gate_ticks: 1_600_000,
+            edges: 20,
+            high_ticks: None,
+            low_ticks: Some(800_000),
+        };
+        timeline.push(DataReport {
+            timestamp: 400_000,
+            seq: 0,
+            kind: ReportKind::Measurement {
+                channel: 2,
+                measurement,
+            },
+            payload: Managed::Borrowed(&mut []),
+        });
+
+        assert_eq!(timeline.measurements(), [(EPOCH_TICK, 2, measurement)]);
+        assert_eq!(timeline.measurements()[0].2.frequency_hz(), 100.0);
+        assert_eq!(timeline.channels().count(), 0);
+    }
+}
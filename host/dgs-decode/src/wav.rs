@@ -0,0 +1,146 @@
+//! WAV export of audio and analog channels.
+//!
+//! Samples are written as they were captured, as 16 bit PCM. Missing blocks
+//! are filled with silence, so the file keeps the timing of the capture and
+//! stays in step with the other channels.
+
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use crate::timeline::{ChannelKey, ChannelTimeline};
+
+/// Size of the header, from the start of the file to the sample data.
+const HEADER_BYTES: u32 = 44;
+
+/// Write an audio or analog channel as a WAV file, with one WAV channel per
+/// input. Analog channels are written as raw SAADC codes.
+pub fn write<W: Write>(chan: &ChannelTimeline, mut out: W) -> io::Result<()> {
+    let key = chan.key();
+    if let ChannelKey::Digital(_) = key {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "digital channels can't be written as WAV",
+        ));
+    }
+
+    let channels = key.width() as u16;
+    let block_len = key.samples_per_block() as usize * usize::from(channels) * 2;
+    let data_len = chan
+        .len()
+        .checked_mul(u64::from(channels) * 2)
+        .and_then(|len| u32::try_from(len + u64::from(HEADER_BYTES)).ok())
+        .map(|len| len - HEADER_BYTES)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too long for a WAV file"))?;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(HEADER_BYTES - 8 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // Integer PCM
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&chan.sample_rate().to_le_bytes())?;
+    out.write_all(&(chan.sample_rate() * u32::from(channels) * 2).to_le_bytes())?;
+    out.write_all(&(channels * 2).to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+
+    let silence = vec![0; block_len];
+    let mut next_seq = None;
+    for block in chan.blocks() {
+        for _ in next_seq.unwrap_or(block.seq)..block.seq {
+            out.write_all(&silence)?;
+        }
+
+        // Analog blocks end with part of a scan, which is dropped
+        let samples = &block.payload[..block_len.min(block.payload.len())];
+        out.write_all(samples)?;
+        out.write_all(&silence[samples.len()..])?;
+
+        next_seq = Some(block.seq + 1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timeline::{Timeline, BLOCK_BYTES};
+    use diegesis_icd::{DataReport, Managed, ReportKind};
+
+    fn audio(seq: u32, timestamp: u32, sample: i16) -> DataReport<'static> {
+        let payload: Vec<u8> = (0..BLOCK_BYTES / 2)
+            .flat_map(|_| sample.to_le_bytes().to_vec())
+            .collect();
+        DataReport {
+            timestamp,
+            seq,
+            kind: ReportKind::Audio {
+                sample_rate: 16_000,
+            },
+            payload: Managed::Owned(payload.into_boxed_slice()),
+        }
+    }
+
+    fn u32_at(wav: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            wav[offset],
+            wav[offset + 1],
+            wav[offset + 2],
+            wav[offset + 3],
+        ])
+    }
+
+    #[test]
+    fn missing_blocks_are_silent() {
+        let mut timeline = Timeline::new();
+        timeline.push(audio(0, 0, 1000));
+        timeline.push(audio(2, 1_024_000, -1000));
+
+        let chan = timeline.channel(ChannelKey::Audio).unwrap();
+        assert_eq!(chan.sample_rate(), 16_000);
+
+        let mut wav = Vec::new();
+        write(chan, &mut wav).unwrap();
+
+        let data_len = 3 * BLOCK_BYTES;
+        assert_eq!(wav.len(), 44 + data_len);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4), (36 + data_len) as u32);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&wav, 24), 16_000);
+        assert_eq!(u32_at(&wav, 28), 32_000);
+        assert_eq!(u32_at(&wav, 40), data_len as u32);
+
+        let samples: Vec<i16> = wav[44..]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples[0], 1000);
+        assert_eq!(samples[2048], 0);
+        assert_eq!(samples[4095], 0);
+        assert_eq!(samples[4096], -1000);
+    }
+
+    #[test]
+    fn digital_channels_are_rejected() {
+        let mut timeline = Timeline::new();
+        timeline.push(DataReport {
+            timestamp: 0,
+            seq: 0,
+            kind: ReportKind::DigitalPin {
+                channel: 0,
+                encoding: diegesis_icd::DigitalEncoding::Raw,
+            },
+            payload: Managed::Owned(Box::new([0; BLOCK_BYTES])),
+        });
+
+        let chan = timeline.channel(ChannelKey::Digital(0)).unwrap();
+        assert!(write(chan, Vec::new()).is_err());
+    }
+}
@@ -1,8 +1,28 @@
 use std::io::{self, Write};
 use std::time::{Duration, Instant};
 use kolben::rlercobs;
+use diegesis_icd::{Command, DigitalRate};
+
+/// Parse `<channel>=<hz>` arguments into digital sample rate commands.
+fn rate_commands() -> Vec<Command> {
+    std::env::args()
+        .skip(1)
+        .map(|arg| {
+            let parsed = arg.split_once('=').and_then(|(chan, hz)| {
+                let channel = chan.parse().ok()?;
+                let rate = DigitalRate::from_hz(hz.parse().ok()?)?;
+                Some(Command::SetDigitalRate { channel, rate })
+            });
+            parsed.unwrap_or_else(|| {
+                eprintln!("Bad argument \"{}\", expected <channel>=<hz>", arg);
+                ::std::process::exit(1);
+            })
+        })
+        .collect()
+}
 
 fn main() {
+    let commands = rate_commands();
     let mut dgs_port = None;
     let ports = serialport::available_ports().unwrap();
     for port in ports {
@@ -35,6 +55,13 @@ fn main() {
 
     match port {
         Ok(mut port) => {
+            for cmd in commands.iter() {
+                let mut cmd_buf = [0u8; 64];
+                let used = postcard::to_slice_cobs(cmd, &mut cmd_buf).unwrap();
+                port.write_all(used).unwrap();
+                println!("Sent {:?}", cmd);
+            }
+
             let mut serial_buf: Vec<u8> = vec![0; 1000];
             println!("Receiving data on {}:", &dgs_port);
             loop {
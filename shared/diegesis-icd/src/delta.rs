@@ -0,0 +1,60 @@
+//! Delta encoding of analog payloads, see `AnalogEncoding::Delta`.
+//!
+//! Payloads hold little endian `i16` samples, interleaving `channels`
+//! inputs. Each sample is encoded as the difference to the previous sample
+//! of the same input (0 for the first one), zigzag mapped and written as a
+//! LEB128 varint. Slowly varying inputs take a single byte per sample.
+
+use core::convert::TryFrom;
+
+use crate::varint::{push_varint, take_varint};
+
+/// Encode `bytes` into `out`. Returns the encoded length, or `None` if it
+/// doesn't fit, in which case encoding stops early.
+pub fn encode(bytes: &[u8], channels: usize, out: &mut [u8]) -> Option<usize> {
+    let mut prev = [0i32; 8];
+    let channels = channels.clamp(1, prev.len());
+    let mut used = 0;
+
+    for (i, sample) in bytes.chunks_exact(2).enumerate() {
+        let value = i32::from(i16::from_le_bytes([sample[0], sample[1]]));
+        let prev = &mut prev[i % channels];
+        push_varint(out, &mut used, zigzag(value - *prev))?;
+        *prev = value;
+    }
+
+    Some(used)
+}
+
+/// Decode `data` into `out`. Returns the decoded length, or `None` if `data`
+/// is malformed or doesn't fit.
+pub fn decode(data: &[u8], channels: usize, out: &mut [u8]) -> Option<usize> {
+    let mut prev = [0i32; 8];
+    let channels = channels.clamp(1, prev.len());
+    let mut rest = data;
+    let mut used = 0;
+
+    let mut i = 0;
+    while !rest.is_empty() {
+        let (zz, remaining) = take_varint(rest)?;
+        rest = remaining;
+
+        let prev = &mut prev[i % channels];
+        let value = prev.checked_add(unzigzag(zz))?;
+        let sample = i16::try_from(value).ok()?;
+        out.get_mut(used..used + 2)?.copy_from_slice(&sample.to_le_bytes());
+        used += 2;
+        *prev = value;
+        i += 1;
+    }
+
+    Some(used)
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
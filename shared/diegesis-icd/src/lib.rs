@@ -1,39 +1,99 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
 use serde::{Serialize, Deserialize};
-use serde::ser::{Serializer, SerializeTuple};
+use serde::ser::Serializer;
 pub use managed::Managed;
 
+pub mod delta;
+pub mod rle;
+mod varint;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ReportKind {
-    DigitalPin { channel: u8 },
-    AnalogPin { channel_bitflag: u8, },
+    /// Samples of a digital channel, see `DigitalEncoding`.
+    DigitalPin { channel: u8, encoding: DigitalEncoding },
+
+    /// Interleaved samples of all scanned analog channels, one bit per
+    /// channel in `channel_bitflag`. See `AnalogEncoding`.
+    ///
+    /// Bit `n` is the `n`th channel in scan order, whose input is reported
+    /// in `AnalogSettings`. Before channels could be differential or
+    /// internal, each bit was an AIN pin instead.
+    AnalogPin { channel_bitflag: u8, encoding: AnalogEncoding },
+
+    /// All capture sources were started at once, at `timestamp`. Has no payload.
+    CaptureStart,
+
+    /// Settings used by the analog channels in `channel_bitflag` from here
+    /// on. Has no payload.
+    AnalogSettings { channel_bitflag: u8, settings: AnalogSettings },
+
+    /// The offset of the analog channels in `channel_bitflag` was
+    /// recalibrated at `timestamp`, at a die temperature of `temperature`
+    /// quarter degrees Celsius. Samples were paused meanwhile. Has no payload.
+    AnalogCalibrated { channel_bitflag: u8, temperature: i32 },
+
+    /// The capture trigger fired at `timestamp`. Sample data is only sent
+    /// from here on, besides the last few blocks before it. Has no payload.
+    Trigger { source: TriggerSource },
+
+    /// The capture was restarted on its own after running out of buffers,
+    /// see `AutoRestart`. `attempt` counts from 1. Has no payload.
+    CaptureRestart { attempt: u8 },
+
+    /// `blocks` consecutive blocks of a digital channel, starting with
+    /// sequence number `seq` at `timestamp`, during which the line stayed
+    /// high or low. Has no payload.
+    Idle { channel: u8, level: bool, blocks: u32 },
+
+    /// Mono audio from the microphone, as little endian `i16` PCM samples
+    /// taken at `sample_rate` Hz.
+    Audio { sample_rate: u32 },
+
+    /// Bytes received by UART sniffer `port`, see `UartConfig`. Sent once
+    /// the line went quiet for a few milliseconds, or a block filled up.
+    /// `timestamp` is the tick they were sent at, within those few
+    /// milliseconds of the last byte.
+    UartBytes { port: u8, errors: UartErrors },
+
+    /// Frequency and pulse widths on digital channel `channel`, measured
+    /// over the gate ending at `timestamp`. See `MeasurementConfig`. Has no
+    /// payload.
+    Measurement { channel: u8, measurement: Measurement },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TriggerSource {
+    /// Analog channel `index` (in scan order) of the scan in `channel_bitflag`.
+    Analog { channel_bitflag: u8, index: u8 },
+    /// The configured `DigitalTrigger`.
+    Digital(DigitalTrigger),
 }
 
 #[cfg(feature = "use-std")]
-use serde::de::{Deserializer, Visitor, SeqAccess};
+use serde::de::{Deserializer, Visitor, SeqAccess, Error};
 
 #[derive(Serialize, Debug)]
 #[cfg_attr(feature = "use-std", derive(Deserialize))]
 pub struct DataReport<'a> {
     pub timestamp: u32,
 
+    /// Per-channel block counter, incremented for every block the channel
+    /// captured, whether or not it could be sent.
+    pub seq: u32,
+
     pub kind: ReportKind,
 
     #[serde(serialize_with = "slicer")]
     #[cfg_attr(feature = "use-std", serde(deserialize_with = "unslicer"))]
-    pub payload: Managed<'a, [u8; 4096]>,
+    pub payload: Managed<'a, [u8]>,
 }
 
-fn slicer<'a, S>(pb: &'a Managed<'a, [u8; 4096]>, s: S) -> Result<S::Ok, S::Error>
+fn slicer<'a, S>(pb: &'a Managed<'a, [u8]>, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let mut seq = s.serialize_tuple(4096)?;
-    for element in pb.iter() {
-        seq.serialize_element(element)?;
-    }
-    seq.end()
+    s.serialize_bytes(pb)
 }
 
 #[cfg(feature = "use-std")]
@@ -41,36 +101,682 @@ pub struct BVisitor;
 
 #[cfg(feature = "use-std")]
 impl<'de> Visitor<'de> for BVisitor {
-    type Value = Managed<'static, [u8; 4096]>;
+    type Value = Managed<'static, [u8]>;
 
     fn expecting(&self, _: &mut core::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         Ok(())
     }
 
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        Ok(Managed::Owned(v.into()))
+    }
+
     fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
     where
         A: SeqAccess<'de>,
     {
-        let mut data = Box::new([0u8; 4096]);
-        data.iter_mut().try_for_each(|b| {
-            *b = seq.next_element()?.unwrap();
-            Ok(())
-        })?;
-        Ok(Managed::Owned(data))
+        let mut data = Vec::new();
+        while let Some(b) = seq.next_element()? {
+            data.push(b);
+        }
+        Ok(Managed::Owned(data.into_boxed_slice()))
     }
 }
 
 #[cfg(feature = "use-std")]
-fn unslicer<'de, D>(des: D) -> Result<Managed<'static, [u8; 4096]>, D::Error>
+fn unslicer<'de, D>(des: D) -> Result<Managed<'static, [u8]>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    des.deserialize_tuple(4096, BVisitor)
+    des.deserialize_bytes(BVisitor)
+}
+
+/// How the payload of a `ReportKind::DigitalPin` is encoded. Picked per
+/// block by the device, whichever is smaller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DigitalEncoding {
+    /// Eight samples per byte, oldest sample in the most significant bit.
+    Raw,
+    /// Lengths of the runs of equal samples, see `rle`.
+    RunLength,
+}
+
+/// How the payload of a `ReportKind::AnalogPin` is encoded. Picked per
+/// block by the device, whichever is smaller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalogEncoding {
+    /// Little endian `i16` samples.
+    Raw,
+    /// Differences between consecutive samples, see `delta`.
+    Delta,
+}
+
+/// Commands sent from the host, each COBS framed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// Set the sample rate of a digital channel. Only applied while stopped.
+    /// Channel 4 is sampled by the I2S, which only supports 1, 2 and 4 MHz.
+    SetDigitalRate { channel: u8, rate: DigitalRate },
+
+    /// Configure the analog channels. Only applied while stopped.
+    SetAnalogConfig(AnalogConfig),
+
+    /// Set or clear the analog trigger. Only applied while stopped.
+    SetAnalogTrigger(Option<AnalogTrigger>),
+
+    /// Set or clear the digital trigger. Only applied while stopped.
+    SetDigitalTrigger(Option<DigitalTrigger>),
+
+    /// Set what happens when the device runs out of sample buffers. Only
+    /// applied while stopped.
+    SetOverflowPolicy(OverflowPolicy),
+
+    /// Set or clear automatic restarts. Only applied while stopped.
+    SetAutoRestart(Option<AutoRestart>),
+
+    /// Only send digital blocks with activity on the line, and replace
+    /// the others with `ReportKind::Idle`. Only applied while stopped.
+    SetActivityOnly(bool),
+
+    /// Allow run-length encoding of digital blocks. Only applied while
+    /// stopped.
+    SetDigitalCompression(bool),
+
+    /// Allow delta encoding of analog blocks. Only applied while stopped.
+    SetAnalogCompression(bool),
+
+    /// Start or stop sniffing UART traffic on a digital channel's pin with
+    /// UART `port` (0 or 1). Only applied while stopped.
+    SetUartSniffer { port: u8, config: Option<UartConfig> },
+
+    /// Start or stop measuring a digital channel. Measurements are sent
+    /// whether a capture is running or not. Only applied while stopped.
+    SetMeasurement(Option<MeasurementConfig>),
+
+    /// Sample a digital channel through the comparator, or back from its
+    /// own pin. Only applied while stopped.
+    SetComparator(Option<ComparatorConfig>),
+}
+
+impl Command {
+    /// Longest COBS frame of a command, including the terminating zero.
+    /// `SetAnalogConfig` with every channel differential is the largest.
+    pub const MAX_ENCODED_LEN: usize = {
+        let serialized = 1 + AnalogConfig::MAX_SERIALIZED_LEN;
+        // COBS adds a byte per 254, on top of the terminator
+        serialized + (serialized / 254) + 1 + 1
+    };
+}
+
+/// Samples an analog input as a digital channel, for logic levels other
+/// than the 3.3 V of the digital pins. The comparator output goes high once
+/// the input rises above the upper threshold, and low once it falls below
+/// the lower one. The channel samples the output instead of its own pin.
+///
+/// Thresholds are in 64ths of the reference, from 1 to 64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComparatorConfig {
+    /// One of the SPIM channels, 0 to 3. Triggers, measurements and UART
+    /// sniffers on the channel still use its own pin.
+    pub channel: u8,
+    /// Analog input pin AIN0 to AIN7.
+    pub ain: u8,
+    pub reference: ComparatorReference,
+    pub threshold_up: u8,
+    /// At most `threshold_up`, the difference is the hysteresis.
+    pub threshold_down: u8,
+}
+
+impl ComparatorConfig {
+    pub fn is_valid(&self, channels: u8) -> bool {
+        self.channel < channels
+            && self.ain < 8
+            && (1..=64).contains(&self.threshold_up)
+            && (1..=self.threshold_up).contains(&self.threshold_down)
+    }
+
+    /// Upper and lower threshold in volts, `vdd` is only used by
+    /// `ComparatorReference::Vdd`.
+    pub fn thresholds(&self, vdd: f32) -> (f32, f32) {
+        let reference = self.reference.volts(vdd);
+        (
+            reference * f32::from(self.threshold_up) / 64.0,
+            reference * f32::from(self.threshold_down) / 64.0,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ComparatorReference {
+    Internal1V2,
+    Internal1V8,
+    Internal2V4,
+    Vdd,
+}
+
+impl ComparatorReference {
+    /// Reference voltage, `vdd` is only used by `Vdd`.
+    pub fn volts(&self, vdd: f32) -> f32 {
+        match self {
+            ComparatorReference::Internal1V2 => 1.2,
+            ComparatorReference::Internal1V8 => 1.8,
+            ComparatorReference::Internal2V4 => 2.4,
+            ComparatorReference::Vdd => vdd,
+        }
+    }
+}
+
+/// Counts the edges on the pin of a digital channel, and times its pulses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeasurementConfig {
+    /// One of the SPIM channels, 0 to 3.
+    pub channel: u8,
+    /// A `ReportKind::Measurement` is sent after each gate.
+    pub gate_ms: u16,
+}
+
+impl MeasurementConfig {
+    pub const MIN_GATE_MS: u16 = 10;
+    pub const MAX_GATE_MS: u16 = 10_000;
+
+    /// Most edges interrupted on per gate while timing pulses. A high and a
+    /// low pulse take three edges in a row, the rest leave room for edges
+    /// too close together to be timed.
+    pub const MAX_TIMED_EDGES: u32 = 16;
+
+    /// Pulses are only timed after a gate with at most this many edges per
+    /// second, faster signals would keep the edge interrupt busy.
+    pub const MAX_TIMED_EDGE_RATE: u32 = 20_000;
+
+    pub fn is_valid(&self, channels: u8) -> bool {
+        self.channel < channels
+            && (Self::MIN_GATE_MS..=Self::MAX_GATE_MS).contains(&self.gate_ms)
+    }
+
+    /// Whether pulses are timed during the gate after one that counted
+    /// `edges`.
+    pub fn times_pulses(&self, edges: u32) -> bool {
+        u64::from(edges) * 1000 <= u64::from(Self::MAX_TIMED_EDGE_RATE) * u64::from(self.gate_ms)
+    }
+}
+
+/// What was seen on a digital channel during one gate. Times are in ticks
+/// of a 16 MHz timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub gate_ticks: u32,
+    /// Rising and falling edges counted during the gate.
+    pub edges: u32,
+    /// Width of a high pulse during the gate. Pulses shorter than the
+    /// interrupt latency, a few microseconds, are not caught, and none are
+    /// timed on fast signals, see `MeasurementConfig::times_pulses`.
+    pub high_ticks: Option<u32>,
+    /// Width of a low pulse during the gate, see `high_ticks`.
+    pub low_ticks: Option<u32>,
+}
+
+impl Measurement {
+    pub const TICKS_PER_SECOND: u32 = 16_000_000;
+
+    /// Mean frequency over the gate, counting two edges per period.
+    pub fn frequency_hz(&self) -> f64 {
+        if self.gate_ticks == 0 {
+            return 0.0;
+        }
+        f64::from(self.edges) * f64::from(Self::TICKS_PER_SECOND)
+            / (2.0 * f64::from(self.gate_ticks))
+    }
+
+    /// Share of the period the line was high, from the pulses caught.
+    pub fn duty_cycle(&self) -> Option<f64> {
+        let high = f64::from(self.high_ticks?);
+        let low = f64::from(self.low_ticks?);
+        Some(high / (high + low))
+    }
+}
+
+/// Receive-only UART on the pin of a digital channel. The channel keeps
+/// sampling the pin as usual.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UartConfig {
+    /// Digital channel to listen in on, one of the SPIM channels 0 to 3.
+    pub channel: u8,
+    /// One of the standard rates from 1200 to 1000000 baud.
+    pub baudrate: u32,
+    /// Whether frames carry an even parity bit.
+    pub parity: bool,
+}
+
+impl UartConfig {
+    pub const BAUDRATES: [u32; 18] = [
+        1200, 2400, 4800, 9600, 14400, 19200, 28800, 31250, 38400, 56000, 57600, 76800, 115200,
+        230400, 250000, 460800, 921600, 1000000,
+    ];
+}
+
+/// Receive errors seen by a UART sniffer while the bytes of a report came in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UartErrors {
+    /// A byte was received before the previous one was stored, and lost.
+    pub overrun: bool,
+    pub parity: bool,
+    /// No valid stop bit where one was expected.
+    pub framing: bool,
+    /// The line was held low for longer than a frame.
+    pub line_break: bool,
+}
+
+impl UartErrors {
+    pub fn any(&self) -> bool {
+        self.overrun || self.parity || self.framing || self.line_break
+    }
+}
+
+/// Restart the capture on its own once it stopped because the device ran
+/// out of buffers, instead of waiting for the button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoRestart {
+    /// Restarts allowed until the capture is started again by hand.
+    pub max_attempts: u8,
+    /// Wait before the first restart, doubled for every further attempt.
+    pub backoff_ms: u32,
+}
+
+impl AutoRestart {
+    /// Wait before restart `attempt`, counting from 1.
+    pub fn delay_ms(&self, attempt: u8) -> u32 {
+        let doublings = u32::from(attempt.saturating_sub(1)).min(16);
+        self.backoff_ms.saturating_mul(1 << doublings)
+    }
+}
+
+/// What the device does when it runs out of sample buffers, e.g. because
+/// the host stopped reading for a moment.
+///
+/// When blocks are dropped, the capture goes on, and the sequence numbers
+/// of the dropped blocks are skipped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Stop the capture.
+    #[default]
+    Stop,
+    /// Drop the block that just completed, pausing the channel until a
+    /// buffer is free again.
+    DropNewest,
+    /// Drop the oldest blocks not yet sent, of any channel.
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
+/// Starts streaming once an analog channel crosses `level`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalogTrigger {
+    /// Channel to watch, in scan order.
+    pub index: u8,
+    /// Raw code to compare against, see `AnalogSettings::code`.
+    pub level: i16,
+    pub edge: Edge,
+}
+
+/// Starts the capture on the digital channels' pins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DigitalTrigger {
+    /// An edge on one channel. Starts the capture in hardware, so no
+    /// samples are lost.
+    Edge { channel: u8, edge: Edge },
+    /// The channels in the `mask` bitflag all reach their bit in `levels`.
+    /// Checked in software on every edge of those channels, so the first
+    /// few microseconds after the match are not captured.
+    Pattern { mask: u8, levels: u8 },
+}
+
+impl DigitalTrigger {
+    pub fn is_valid(&self, channels: u8) -> bool {
+        match *self {
+            DigitalTrigger::Edge { channel, .. } => channel < channels,
+            DigitalTrigger::Pattern { mask, .. } => mask != 0 && (mask >> channels) == 0,
+        }
+    }
+}
+
+/// Sample rates supported by the digital channels. Rates above 8 MHz are
+/// only available on channel 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DigitalRate {
+    K125,
+    K250,
+    K500,
+    M1,
+    M2,
+    M4,
+    M8,
+    M16,
+    M32,
+}
+
+impl DigitalRate {
+    pub const ALL: [DigitalRate; 9] = [
+        DigitalRate::K125,
+        DigitalRate::K250,
+        DigitalRate::K500,
+        DigitalRate::M1,
+        DigitalRate::M2,
+        DigitalRate::M4,
+        DigitalRate::M8,
+        DigitalRate::M16,
+        DigitalRate::M32,
+    ];
+
+    pub fn hz(&self) -> u32 {
+        match self {
+            DigitalRate::K125 => 125_000,
+            DigitalRate::K250 => 250_000,
+            DigitalRate::K500 => 500_000,
+            DigitalRate::M1 => 1_000_000,
+            DigitalRate::M2 => 2_000_000,
+            DigitalRate::M4 => 4_000_000,
+            DigitalRate::M8 => 8_000_000,
+            DigitalRate::M16 => 16_000_000,
+            DigitalRate::M32 => 32_000_000,
+        }
+    }
+
+    pub fn from_hz(hz: u32) -> Option<Self> {
+        Self::ALL.iter().copied().find(|rate| rate.hz() == hz)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalogResolution {
+    Bits8,
+    Bits10,
+    Bits12,
+    Bits14,
+}
+
+impl AnalogResolution {
+    pub fn bits(&self) -> u8 {
+        match self {
+            AnalogResolution::Bits8 => 8,
+            AnalogResolution::Bits10 => 10,
+            AnalogResolution::Bits12 => 12,
+            AnalogResolution::Bits14 => 14,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalogGain {
+    Gain1_6,
+    Gain1_5,
+    Gain1_4,
+    Gain1_3,
+    Gain1_2,
+    Gain1,
+    Gain2,
+    Gain4,
+}
+
+impl AnalogGain {
+    pub fn factor(&self) -> f32 {
+        match self {
+            AnalogGain::Gain1_6 => 1.0 / 6.0,
+            AnalogGain::Gain1_5 => 1.0 / 5.0,
+            AnalogGain::Gain1_4 => 1.0 / 4.0,
+            AnalogGain::Gain1_3 => 1.0 / 3.0,
+            AnalogGain::Gain1_2 => 1.0 / 2.0,
+            AnalogGain::Gain1 => 1.0,
+            AnalogGain::Gain2 => 2.0,
+            AnalogGain::Gain4 => 4.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalogReference {
+    /// Internal 0.6V reference.
+    Internal,
+    /// VDD / 4.
+    Vdd1_4,
+}
+
+impl AnalogReference {
+    /// Reference voltage, `vdd` is only used by `Vdd1_4`.
+    pub fn volts(&self, vdd: f32) -> f32 {
+        match self {
+            AnalogReference::Internal => 0.6,
+            AnalogReference::Vdd1_4 => vdd / 4.0,
+        }
+    }
+}
+
+/// Acquisition time of a single conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcquisitionTime {
+    Us3,
+    Us5,
+    Us10,
+    Us15,
+    Us20,
+    Us40,
+}
+
+impl AcquisitionTime {
+    pub fn micros(&self) -> u32 {
+        match self {
+            AcquisitionTime::Us3 => 3,
+            AcquisitionTime::Us5 => 5,
+            AcquisitionTime::Us10 => 10,
+            AcquisitionTime::Us15 => 15,
+            AcquisitionTime::Us20 => 20,
+            AcquisitionTime::Us40 => 40,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalogChannelConfig {
+    pub gain: AnalogGain,
+    pub reference: AnalogReference,
+    pub time: AcquisitionTime,
+}
+
+impl Default for AnalogChannelConfig {
+    fn default() -> Self {
+        Self {
+            gain: AnalogGain::Gain1_4,
+            reference: AnalogReference::Vdd1_4,
+            time: AcquisitionTime::Us3,
+        }
+    }
+}
+
+impl AnalogChannelConfig {
+    /// Input voltage at which the channel reads full scale.
+    pub fn full_scale(&self, vdd: f32) -> f32 {
+        self.reference.volts(vdd) / self.gain.factor()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalogConfig {
+    pub resolution: AnalogResolution,
+    /// Average 2^`oversample` conversions for every sample, 0 to 8.
+    pub oversample: u8,
+    /// Interval between two scans over all channels.
+    pub sample_period_us: u32,
+    /// Per-channel settings, in the order the channels are scanned.
+    pub channels: [AnalogChannelConfig; 8],
+    /// Input of each channel, in scan order. The scan ends at the first
+    /// `None`. AIN pins the board doesn't reserve for analog use are refused.
+    pub inputs: [Option<AnalogInput>; 8],
+}
+
+impl Default for AnalogConfig {
+    fn default() -> Self {
+        Self {
+            resolution: AnalogResolution::Bits14,
+            oversample: 0,
+            sample_period_us: 15,
+            channels: [AnalogChannelConfig::default(); 8],
+            inputs: [
+                Some(AnalogInput::single(AnalogSource::Ain(0))),
+                Some(AnalogInput::single(AnalogSource::Ain(1))),
+                Some(AnalogInput::single(AnalogSource::Ain(5))),
+                None,
+                None,
+                None,
+                None,
+                None,
+            ],
+        }
+    }
+}
+
+impl AnalogConfig {
+    /// Longest postcard encoding: the resolution, the oversampling, the
+    /// period, 3 bytes of settings per channel, and up to 6 bytes per input,
+    /// with a pin on either side.
+    pub const MAX_SERIALIZED_LEN: usize = 1 + 1 + 4 + (8 * 3) + (8 * 6);
+
+    /// Time needed to scan the first `num_channels` channels once. Conversions
+    /// take up to 2µs on top of the acquisition time.
+    pub fn scan_time_us(&self, num_channels: usize) -> u32 {
+        let per_scan: u32 = self.channels[..num_channels.min(8)]
+            .iter()
+            .map(|ch| ch.time.micros() + 2)
+            .sum();
+        per_scan << self.oversample
+    }
+
+    /// Number of channels scanned, see `inputs`.
+    pub fn scan_len(&self) -> usize {
+        self.inputs.iter().take_while(|input| input.is_some()).count()
+    }
+
+    /// Whether the inputs are valid, and a scan over them fits into the
+    /// sample period.
+    pub fn is_valid(&self) -> bool {
+        let len = self.scan_len();
+        (self.oversample <= 8)
+            && (len > 0)
+            && self.inputs[..len].iter().flatten().all(AnalogInput::is_valid)
+            && self.inputs[len..].iter().all(Option::is_none)
+            && (self.scan_time_us(len) <= self.sample_period_us)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        1_000_000 / self.sample_period_us.max(1)
+    }
+}
+
+/// Signal connected to one side of an analog channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalogSource {
+    /// Analog input pin AIN0 to AIN7.
+    Ain(u8),
+    Vdd,
+    /// VDDH divided by 5.
+    VddhDiv5,
+}
+
+impl AnalogSource {
+    /// Decode a PSELP/PSELN register value. Disconnected inputs are `None`.
+    pub fn from_psel(psel: u8) -> Option<Self> {
+        match psel {
+            1..=8 => Some(AnalogSource::Ain(psel - 1)),
+            9 => Some(AnalogSource::Vdd),
+            0x0D => Some(AnalogSource::VddhDiv5),
+            _ => None,
+        }
+    }
+
+    /// The PSELP/PSELN register value selecting this source.
+    pub fn psel(&self) -> u8 {
+        match *self {
+            AnalogSource::Ain(ain) => ain + 1,
+            AnalogSource::Vdd => 9,
+            AnalogSource::VddhDiv5 => 0x0D,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalogInput {
+    pub positive: AnalogSource,
+    /// Only set for differential channels, e.g. across a current shunt.
+    pub negative: Option<AnalogSource>,
+}
+
+impl AnalogInput {
+    pub fn single(positive: AnalogSource) -> Self {
+        Self {
+            positive,
+            negative: None,
+        }
+    }
+
+    pub fn differential(positive: AnalogSource, negative: AnalogSource) -> Self {
+        Self {
+            positive,
+            negative: Some(negative),
+        }
+    }
+
+    /// Whether all AIN pins exist.
+    pub fn is_valid(&self) -> bool {
+        let exists = |source: &AnalogSource| !matches!(source, AnalogSource::Ain(ain) if *ain >= 8);
+        exists(&self.positive) && self.negative.as_ref().is_none_or(exists)
+    }
+}
+
+/// Effective settings of the analog channels, as reported by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalogSettings {
+    pub config: AnalogConfig,
+}
+
+impl AnalogSettings {
+    /// Convert a raw code of the channel at `index` (in scan order) to volts.
+    /// `vdd` is only used by channels referenced to VDD.
+    pub fn volts(&self, index: usize, code: i16, vdd: f32) -> f32 {
+        let full_scale = self.config.channels[index].full_scale(vdd);
+        // Differential results are signed, so they lose one bit of range
+        let differential = self.config.inputs[index].is_some_and(|i| i.negative.is_some());
+        let bits = self.config.resolution.bits() - (differential as u8);
+        f32::from(code) * full_scale / ((1u32 << bits) as f32)
+    }
+
+    /// Convert a voltage to the raw code of the channel at `index`, the
+    /// inverse of `volts`. Saturates at the limits of the code range.
+    pub fn code(&self, index: usize, volts: f32, vdd: f32) -> i16 {
+        let code = volts / self.volts(index, 1, vdd);
+        if code >= f32::from(i16::MAX) {
+            i16::MAX
+        } else if code <= f32::from(i16::MIN) {
+            i16::MIN
+        } else {
+            code as i16
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::DataReport;
+    use crate::{
+        AnalogConfig, AnalogInput, AnalogResolution, AutoRestart, AnalogSettings, AnalogSource,
+        Command, ComparatorConfig, ComparatorReference, DataReport,
+        DigitalEncoding, DigitalRate, DigitalTrigger, Measurement, MeasurementConfig, ReportKind,
+    };
     use managed::Managed;
     use postcard::{to_stdvec, from_bytes};
     use core::ops::Deref;
@@ -79,6 +785,11 @@ mod test {
     fn basic_roundtrip() {
         let foo = DataReport {
             timestamp: 0x12345678,
+            seq: 42,
+            kind: ReportKind::DigitalPin {
+                channel: 1,
+                encoding: DigitalEncoding::Raw,
+            },
             payload: Managed::Owned(Box::new([0x42; 4096])),
         };
 
@@ -86,6 +797,222 @@ mod test {
 
         let baz: DataReport = from_bytes(&bar).unwrap();
         assert_eq!(foo.timestamp, baz.timestamp);
+        assert_eq!(foo.seq, baz.seq);
         assert_eq!(foo.payload.deref(), baz.payload.deref());
     }
+
+    #[test]
+    fn empty_payload_roundtrip() {
+        let foo = DataReport {
+            timestamp: 0x12345678,
+            seq: 0,
+            kind: ReportKind::CaptureStart,
+            payload: Managed::Borrowed(&mut []),
+        };
+
+        let bar = to_stdvec(&foo).unwrap();
+        assert!(bar.len() < 16);
+
+        let baz: DataReport = from_bytes(&bar).unwrap();
+        assert!(matches!(baz.kind, ReportKind::CaptureStart));
+        assert!(baz.payload.is_empty());
+    }
+
+    #[test]
+    fn command_roundtrip() {
+        let cmd = Command::SetDigitalRate {
+            channel: 3,
+            rate: DigitalRate::from_hz(32_000_000).unwrap(),
+        };
+
+        let mut buf = [0u8; 16];
+        let used = postcard::to_slice_cobs(&cmd, &mut buf).unwrap();
+        assert_eq!(cmd, postcard::from_bytes_cobs(used).unwrap());
+        assert_eq!(DigitalRate::from_hz(3_000_000), None);
+
+        let trigger = DigitalTrigger::Pattern {
+            mask: 0b1010,
+            levels: 0b0010,
+        };
+        assert!(trigger.is_valid(4));
+        assert!(!trigger.is_valid(3));
+        let cmd = Command::SetDigitalTrigger(Some(trigger));
+        let used = postcard::to_slice_cobs(&cmd, &mut buf).unwrap();
+        assert_eq!(cmd, postcard::from_bytes_cobs(used).unwrap());
+
+        let restart = AutoRestart {
+            max_attempts: 3,
+            backoff_ms: 1000,
+        };
+        assert_eq!(restart.delay_ms(1), 1000);
+        assert_eq!(restart.delay_ms(3), 4000);
+        assert_eq!(restart.delay_ms(255), 65_536_000);
+    }
+
+    #[test]
+    fn measurement_math() {
+        let measurement = Measurement {
+            gate_ticks: 1_600_000,
+            edges: 2_000,
+            high_ticks: Some(4_000),
+            low_ticks: Some(12_000),
+        };
+        assert_eq!(measurement.frequency_hz(), 10_000.0);
+        assert_eq!(measurement.duty_cycle(), Some(0.25));
+
+        let measurement = Measurement {
+            low_ticks: None,
+            ..measurement
+        };
+        assert_eq!(measurement.duty_cycle(), None);
+
+        let config = MeasurementConfig {
+            channel: 3,
+            gate_ms: 100,
+        };
+        assert!(config.is_valid(4));
+        assert!(!config.is_valid(3));
+        assert!(!MeasurementConfig { gate_ms: 5, ..config }.is_valid(4));
+    }
+
+    #[test]
+    fn fast_signals_are_not_timed() {
+        let config = MeasurementConfig {
+            channel: 0,
+            gate_ms: 100,
+        };
+        assert!(config.times_pulses(0));
+        assert!(config.times_pulses(2_000));
+        assert!(!config.times_pulses(2_001));
+
+        let config = MeasurementConfig {
+            gate_ms: MeasurementConfig::MAX_GATE_MS,
+            ..config
+        };
+        assert!(config.times_pulses(200_000));
+        assert!(!config.times_pulses(u32::MAX));
+    }
+
+    #[test]
+    fn comparator_thresholds() {
+        // 1.8 V logic, switching around 0.9 V with 75 mV of hysteresis
+        let config = ComparatorConfig {
+            channel: 0,
+            ain: 1,
+            reference: ComparatorReference::Internal1V2,
+            threshold_up: 50,
+            threshold_down: 46,
+        };
+        assert!(config.is_valid(4));
+        let (up, down) = config.thresholds(3.3);
+        assert!((up - 0.9375).abs() < 1e-6);
+        assert!((down - 0.8625).abs() < 1e-6);
+
+        assert!(!ComparatorConfig { threshold_down: 51, ..config }.is_valid(4));
+        assert!(!ComparatorConfig { threshold_up: 65, ..config }.is_valid(4));
+        assert!(!ComparatorConfig { ain: 8, ..config }.is_valid(4));
+
+        let cmd = Command::SetComparator(Some(config));
+        let mut buf = [0u8; 16];
+        let used = postcard::to_slice_cobs(&cmd, &mut buf).unwrap();
+        assert_eq!(cmd, postcard::from_bytes_cobs(used).unwrap());
+    }
+
+    #[test]
+    fn analog_config() {
+        let mut config = AnalogConfig::default();
+        assert_eq!(config.scan_len(), 3);
+        assert!(config.is_valid());
+
+        config.inputs[3] = Some(AnalogInput::single(AnalogSource::Vdd));
+        assert!(!config.is_valid());
+        config.inputs[3] = None;
+
+        config.oversample = 2;
+        assert_eq!(config.scan_time_us(3), 60);
+        assert!(!config.is_valid());
+        config.oversample = 0;
+
+        // Scans end at the first gap, and the pins must exist
+        let mut gap = config;
+        gap.inputs[1] = None;
+        assert!(!gap.is_valid());
+        let mut missing = config;
+        missing.inputs[0] = Some(AnalogInput::single(AnalogSource::Ain(8)));
+        assert!(!missing.is_valid());
+
+        let single = AnalogInput::single(AnalogSource::from_psel(1).unwrap());
+        let differential = AnalogInput::differential(AnalogSource::Ain(0), AnalogSource::Ain(1));
+        assert_eq!(AnalogSource::from_psel(AnalogSource::VddhDiv5.psel()), Some(AnalogSource::VddhDiv5));
+        config.inputs = [None; 8];
+        config.inputs[0] = Some(single);
+        config.inputs[1] = Some(differential);
+        assert!(config.is_valid());
+        let settings = AnalogSettings { config };
+
+        // Half of full scale, which is VDD with the default gain and reference
+        let volts = settings.volts(0, 1 << 13, 3.0);
+        assert!((volts - 1.5).abs() < 0.001);
+        let volts = settings.volts(1, -(1 << 12), 3.0);
+        assert!((volts + 1.5).abs() < 0.001);
+        assert_eq!(settings.code(0, 1.5, 3.0), 1 << 13);
+        assert_eq!(settings.code(0, 100.0, 3.0), i16::MAX);
+    }
+
+    #[test]
+    fn largest_command() {
+        let mut config = AnalogConfig {
+            resolution: AnalogResolution::Bits14,
+            oversample: 8,
+            sample_period_us: u32::MAX,
+            ..AnalogConfig::default()
+        };
+        for (i, input) in config.inputs.iter_mut().enumerate() {
+            let ain = AnalogSource::Ain(i as u8);
+            *input = Some(AnalogInput::differential(ain, AnalogSource::Ain(7)));
+        }
+        let cmd = Command::SetAnalogConfig(config);
+
+        let mut buf = [0u8; 128];
+        let used = postcard::to_slice_cobs(&cmd, &mut buf).unwrap();
+        assert_eq!(used.len(), Command::MAX_ENCODED_LEN);
+        assert_eq!(cmd, postcard::from_bytes_cobs(used).unwrap());
+    }
+
+    #[test]
+    fn rle_roundtrip() {
+        let mut block = [0xFFu8; 4096];
+        block[100] = 0x0F;
+        block[101..200].iter_mut().for_each(|b| *b = 0x00);
+        block[4095] = 0x5A;
+
+        let mut encoded = [0u8; 4095];
+        let used = crate::rle::encode(&block, &mut encoded).unwrap();
+        assert!(used < 32);
+
+        let mut decoded = [0u8; 4096];
+        assert_eq!(crate::rle::decode(&encoded[..used], &mut decoded), Some(4096));
+        assert_eq!(&block[..], &decoded[..]);
+
+        // Toggling every sample doesn't get any smaller
+        assert_eq!(crate::rle::encode(&[0x55; 4096], &mut encoded), None);
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        // Two inputs, one slowly rising, one at a fixed negative level
+        let mut block = [0u8; 4096];
+        for (i, sample) in block.chunks_exact_mut(2).enumerate() {
+            let value: i16 = if i % 2 == 0 { 1000 + (i / 8) as i16 } else { -2000 };
+            sample.copy_from_slice(&value.to_le_bytes());
+        }
+
+        let mut encoded = [0u8; 4095];
+        let used = crate::delta::encode(&block, 2, &mut encoded).unwrap();
+        assert!(used < 2100);
+
+        let mut decoded = [0u8; 4096];
+        assert_eq!(crate::delta::decode(&encoded[..used], 2, &mut decoded), Some(4096));
+        assert_eq!(&block[..], &decoded[..]);
+    }
 }
@@ -0,0 +1,74 @@
+//! Run-length encoding of digital payloads, see `DigitalEncoding::RunLength`.
+//!
+//! Payloads hold eight samples per byte, oldest sample in the most
+//! significant bit. The encoding starts with the level of the first sample
+//! (0 or 1), followed by the length of each run of equal samples as a LEB128
+//! varint, with the level alternating between runs.
+
+use crate::varint::{push, push_varint, take_varint};
+
+/// Encode `bytes` into `out`. Returns the encoded length, or `None` if it
+/// doesn't fit, in which case encoding stops early.
+pub fn encode(bytes: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut level = *bytes.first()? >> 7;
+    let mut used = 0;
+    push(out, &mut used, level)?;
+
+    let mut run: u32 = 0;
+    for byte in bytes {
+        let same = if level == 0 { 0x00 } else { 0xFF };
+        if *byte == same {
+            run += 8;
+            continue;
+        }
+
+        for bit in (0..8).rev() {
+            let sample = (byte >> bit) & 0x01;
+            if sample == level {
+                run += 1;
+            } else {
+                push_varint(out, &mut used, run)?;
+                level = sample;
+                run = 1;
+            }
+        }
+    }
+    push_varint(out, &mut used, run)?;
+
+    Some(used)
+}
+
+/// Decode `data` into `out`. Returns the decoded length, or `None` if `data`
+/// is malformed, doesn't end on a byte boundary, or doesn't fit.
+pub fn decode(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    let (first, mut rest) = data.split_first()?;
+    let mut level = match first {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+
+    out.iter_mut().for_each(|byte| *byte = 0);
+    let mut idx: usize = 0;
+    while !rest.is_empty() {
+        let (run, remaining) = take_varint(rest)?;
+        rest = remaining;
+
+        let end = idx.checked_add(run as usize)?;
+        if end > out.len() * 8 {
+            return None;
+        }
+        if level {
+            for bit in idx..end {
+                out[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        idx = end;
+        level = !level;
+    }
+
+    if !idx.is_multiple_of(8) {
+        return None;
+    }
+    Some(idx / 8)
+}
@@ -0,0 +1,29 @@
+//! LEB128 varints, as used by the payload encodings.
+
+/// Write `byte` to `out` at `used`, and advance `used`.
+pub(crate) fn push(out: &mut [u8], used: &mut usize, byte: u8) -> Option<()> {
+    *out.get_mut(*used)? = byte;
+    *used += 1;
+    Some(())
+}
+
+/// Write `value` to `out` at `used`, and advance `used`.
+pub(crate) fn push_varint(out: &mut [u8], used: &mut usize, mut value: u32) -> Option<()> {
+    while value >= 0x80 {
+        push(out, used, (value as u8) | 0x80)?;
+        value >>= 7;
+    }
+    push(out, used, value as u8)
+}
+
+/// Read a varint from the start of `data`, returning it and the rest of `data`.
+pub(crate) fn take_varint(data: &[u8]) -> Option<(u32, &[u8])> {
+    let mut value: u32 = 0;
+    for (i, byte) in data.iter().enumerate().take(5) {
+        value |= u32::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}